@@ -0,0 +1,70 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compare the time to look up the memory region of a guest address with the number of regions.
+//!
+//! GuestMemory keeps its regions sorted and looks them up by binary search, so the time per lookup
+//! should grow with the logarithm of the number of regions. A linear scan of the same regions is
+//! timed as a reference.
+//!
+//! Run with `cargo bench`.
+
+extern crate memory_model;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use memory_model::{GuestAddress, GuestMemory, GuestUsize};
+
+const REGION_SIZE: GuestUsize = 0x1000;
+// Leave a hole after each region.
+const REGION_STRIDE: GuestUsize = 0x2000;
+const LOOKUPS: usize = 1_000_000;
+
+// Pseudo-random guest addresses spread over the regions and the holes between them.
+fn addresses(num_regions: usize) -> Vec<GuestAddress> {
+    let end = num_regions as GuestUsize * REGION_STRIDE;
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..LOOKUPS)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            GuestAddress(seed % end)
+        })
+        .collect()
+}
+
+fn time_per_lookup<F: Fn(GuestAddress) -> bool>(addrs: &[GuestAddress], lookup: F) -> f64 {
+    let start = Instant::now();
+    let mut found = 0;
+    for addr in addrs {
+        if lookup(black_box(*addr)) {
+            found += 1;
+        }
+    }
+    black_box(found);
+    start.elapsed().as_secs_f64() * 1e9 / addrs.len() as f64
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>16} {:>16}",
+        "regions", "binary (ns)", "linear (ns)"
+    );
+    for &num_regions in &[1usize, 16, 256, 4096] {
+        let ranges: Vec<(GuestAddress, GuestUsize)> = (0..num_regions)
+            .map(|i| (GuestAddress(i as GuestUsize * REGION_STRIDE), REGION_SIZE))
+            .collect();
+        let mem = GuestMemory::new(&ranges).unwrap();
+        let addrs = addresses(num_regions);
+
+        let binary = time_per_lookup(&addrs, |addr| mem.address_in_range(addr));
+        let linear = time_per_lookup(&addrs, |addr| {
+            ranges
+                .iter()
+                .any(|(base, size)| addr >= *base && addr.0 - base.0 < *size)
+        });
+        println!("{:>8} {:>16.1} {:>16.1}", num_regions, binary, linear);
+    }
+}
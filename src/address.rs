@@ -0,0 +1,236 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Traits and types to represent addresses in the different address spaces of a virtual machine.
+//!
+//! Guest physical addresses, MMIO bus addresses, IO virtual addresses and file offsets are all
+//! plain 64-bit numbers, but they belong to different address spaces and must never be mixed up.
+//! Each of them is represented by a distinct newtype, and the `Address` trait provides the same
+//! overflow-aware arithmetic for all of them.
+
+use std::fmt::Debug;
+
+/// Represents an address in an address space.
+///
+/// Implementors only need to provide `new()` and `raw_value()`, all the arithmetic helpers are
+/// derived from them.
+///
+/// # Examples
+///
+/// ```
+/// # use memory_model::Address;
+/// #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+/// struct BusAddress(u64);
+///
+/// impl Address for BusAddress {
+///     fn new(addr: u64) -> Self {
+///         BusAddress(addr)
+///     }
+///
+///     fn raw_value(&self) -> u64 {
+///         self.0
+///     }
+/// }
+///
+/// let addr = BusAddress(0x1234);
+/// assert_eq!(addr.align_up(0x1000), Some(BusAddress(0x2000)));
+/// assert_eq!(addr.checked_offset_from(BusAddress(0x1000)), Some(0x234));
+/// ```
+pub trait Address: Copy + Debug + Eq + Ord {
+    /// Creates an address from its raw value.
+    fn new(addr: u64) -> Self;
+
+    /// Returns the raw value of the address.
+    fn raw_value(&self) -> u64;
+
+    /// Returns the offset from `base` to this address, or None if `base` is above this address.
+    fn checked_offset_from(&self, base: Self) -> Option<u64> {
+        self.raw_value().checked_sub(base.raw_value())
+    }
+
+    /// Returns the offset from `base` to this address.
+    /// Only use this when `base` is guaranteed not to be above this address.
+    fn offset_from(&self, base: Self) -> u64 {
+        self.raw_value() - base.raw_value()
+    }
+
+    /// Returns the bitwise and of the address with the given mask.
+    fn mask(&self, mask: u64) -> Self {
+        Self::new(self.raw_value() & mask)
+    }
+
+    /// Returns the result of the add or None if there is overflow.
+    fn checked_add(&self, other: u64) -> Option<Self> {
+        self.raw_value().checked_add(other).map(Self::new)
+    }
+
+    /// Returns the result of the add and a flag indicating whether an overflow happened.
+    fn overflowing_add(&self, other: u64) -> (Self, bool) {
+        let (value, overflow) = self.raw_value().overflowing_add(other);
+        (Self::new(value), overflow)
+    }
+
+    /// Returns the result of the add, wrapping around at the boundary of the address space.
+    fn wrapping_add(&self, other: u64) -> Self {
+        Self::new(self.raw_value().wrapping_add(other))
+    }
+
+    /// Returns the result of the add.
+    /// Only use this when `other` is guaranteed not to overflow.
+    fn unchecked_add(&self, other: u64) -> Self {
+        Self::new(self.raw_value() + other)
+    }
+
+    /// Returns the result of the subtraction or None if there is underflow.
+    fn checked_sub(&self, other: u64) -> Option<Self> {
+        self.raw_value().checked_sub(other).map(Self::new)
+    }
+
+    /// Returns the result of the subtraction and a flag indicating whether an underflow happened.
+    fn overflowing_sub(&self, other: u64) -> (Self, bool) {
+        let (value, overflow) = self.raw_value().overflowing_sub(other);
+        (Self::new(value), overflow)
+    }
+
+    /// Returns the result of the subtraction, wrapping around at the boundary of the address
+    /// space.
+    fn wrapping_sub(&self, other: u64) -> Self {
+        Self::new(self.raw_value().wrapping_sub(other))
+    }
+
+    /// Returns the result of the subtraction.
+    /// Only use this when `other` is guaranteed not to underflow.
+    fn unchecked_sub(&self, other: u64) -> Self {
+        Self::new(self.raw_value() - other)
+    }
+
+    /// Rounds the address up to the next multiple of `alignment`, or returns None if the result
+    /// overflows. `alignment` must be a power of two.
+    fn align_up(&self, alignment: u64) -> Option<Self> {
+        debug_assert!(alignment.is_power_of_two());
+        let mask = alignment - 1;
+        self.raw_value()
+            .checked_add(mask)
+            .map(|value| Self::new(value & !mask))
+    }
+
+    /// Rounds the address down to the previous multiple of `alignment`.
+    /// `alignment` must be a power of two.
+    fn align_down(&self, alignment: u64) -> Self {
+        debug_assert!(alignment.is_power_of_two());
+        Self::new(self.raw_value() & !(alignment - 1))
+    }
+
+    /// Checks whether the address is a multiple of `alignment`.
+    /// `alignment` must be a power of two.
+    fn is_aligned(&self, alignment: u64) -> bool {
+        debug_assert!(alignment.is_power_of_two());
+        self.raw_value() & (alignment - 1) == 0
+    }
+}
+
+/// Implements `Address` and the bitwise operators for a newtype wrapping an `u64`.
+macro_rules! impl_address {
+    ($T:ident) => {
+        impl ::address::Address for $T {
+            fn new(addr: u64) -> Self {
+                $T(addr)
+            }
+
+            fn raw_value(&self) -> u64 {
+                self.0
+            }
+        }
+
+        impl ::std::ops::BitAnd<u64> for $T {
+            type Output = $T;
+
+            fn bitand(self, other: u64) -> $T {
+                $T(self.0 & other)
+            }
+        }
+
+        impl ::std::ops::BitOr<u64> for $T {
+            type Output = $T;
+
+            fn bitor(self, other: u64) -> $T {
+                $T(self.0 | other)
+            }
+        }
+    };
+}
+
+/// Represents an address on the MMIO bus of the guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MmioAddress(pub u64);
+impl_address!(MmioAddress);
+
+/// Represents an IO virtual address, as seen by a device behind an IOMMU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IovaAddress(pub u64);
+impl_address!(IovaAddress);
+
+/// Represents an offset into a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileOffset(pub u64);
+impl_address!(FileOffset);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_from() {
+        let base = MmioAddress(0x1000);
+        let addr = MmioAddress(0x1800);
+        assert_eq!(addr.offset_from(base), 0x800);
+        assert_eq!(addr.checked_offset_from(base), Some(0x800));
+        assert_eq!(base.checked_offset_from(addr), None);
+    }
+
+    #[test]
+    fn add_sub() {
+        let a = IovaAddress(0xffff_ffff_ffff_fff0);
+        assert_eq!(a.checked_add(0xf), Some(IovaAddress(u64::MAX)));
+        assert_eq!(a.checked_add(0x10), None);
+        assert_eq!(a.overflowing_add(0x10), (IovaAddress(0), true));
+        assert_eq!(
+            a.overflowing_add(0x1),
+            (IovaAddress(0xffff_ffff_ffff_fff1), false)
+        );
+        assert_eq!(a.wrapping_add(0x20), IovaAddress(0x10));
+        assert_eq!(a.unchecked_add(0x1), IovaAddress(0xffff_ffff_ffff_fff1));
+
+        let b = IovaAddress(0x10);
+        assert_eq!(b.checked_sub(0x10), Some(IovaAddress(0)));
+        assert_eq!(b.checked_sub(0x11), None);
+        assert_eq!(b.overflowing_sub(0x11), (IovaAddress(u64::MAX), true));
+        assert_eq!(b.wrapping_sub(0x20), IovaAddress(0xffff_ffff_ffff_fff0));
+        assert_eq!(b.unchecked_sub(0x8), IovaAddress(0x8));
+    }
+
+    #[test]
+    fn align() {
+        let a = FileOffset(0x1001);
+        assert_eq!(a.align_up(0x1000), Some(FileOffset(0x2000)));
+        assert_eq!(a.align_down(0x1000), FileOffset(0x1000));
+        assert!(!a.is_aligned(0x1000));
+        assert!(a.is_aligned(1));
+
+        let b = FileOffset(0x2000);
+        assert_eq!(b.align_up(0x1000), Some(b));
+        assert_eq!(b.align_down(0x1000), b);
+        assert!(b.is_aligned(0x1000));
+        assert!(!b.is_aligned(0x4000));
+
+        assert_eq!(FileOffset(u64::MAX).align_up(0x1000), None);
+    }
+
+    #[test]
+    fn bit_ops() {
+        let a = MmioAddress(0x5050);
+        assert_eq!(a & 0xff00, MmioAddress(0x5000));
+        assert_eq!(a | 0x0005, MmioAddress(0x5055));
+        assert_eq!(a.mask(0xf0), MmioAddress(0x50));
+    }
+}
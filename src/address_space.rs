@@ -4,12 +4,20 @@
 //! Represent the physical address space of a virtual machine, which is composed
 //! by address ranges for memory and memory-mapped IO areas.
 
+use std::collections::{HashMap, VecDeque};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
 
-use guest_address::GuestAddress;
-use guest_memory::{GuestMemory, MemoryRegion};
-use mmap::{Error as MmapError, MemoryMapping};
+use libc;
+
+use address::Address;
+use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
+use guest_memory::{Error as GuestMemoryError, GuestMemory, MemoryRegion};
+use mmap::{self, Error as MmapError, MemoryMappingBuilder, MemoryReservation, Protection};
+
+// Alignment of the host address range reserved for contiguous mappings, large enough for 1 GiB
+// huge pages.
+const CONTIGUOUS_MAPPING_ALIGNMENT: usize = 1 << 30;
 
 /// Errors associated with address space operations.
 #[derive(Debug)]
@@ -19,11 +27,34 @@ pub enum Error {
     /// Invalid operation
     InvalidOperation,
     /// Address range is invalid
-    InvalidAddressRange(GuestAddress, usize),
+    InvalidAddressRange(GuestAddress, GuestUsize),
     /// Address range conflicts with other ranges
-    ConflictAddressRange(GuestAddress, usize),
+    ConflictAddressRange(GuestAddress, GuestUsize),
     /// Failure in creating memory mapping.
     MemoryMappingFailed(MmapError),
+    /// Failure in creating guest memory from the memory mappings.
+    GuestMemoryFailed(GuestMemoryError),
+    /// No address region with the given handle
+    InvalidRegionId(AddressRegionId),
+    /// No listener with the given handle
+    InvalidListenerId(ListenerId),
+    /// Alignment is not a power of two
+    InvalidAlignment(u64),
+    /// No free address range of the requested size
+    NoFreeAddressRange(GuestUsize),
+    /// Huge page size is not a power of two
+    InvalidPageSize(u64),
+    /// Address region is not aligned to the size of the huge pages backing it
+    UnalignedRegion {
+        /// Base address of the region.
+        base: GuestAddress,
+        /// Size of the region.
+        size: GuestUsize,
+        /// Offset into the file backing the region.
+        offset: u64,
+        /// Size of the huge pages backing the region.
+        page_size: u64,
+    },
 }
 
 /// Type of address regions.
@@ -33,7 +64,7 @@ pub enum Error {
 /// extended to support better cooperation between the hypervisor and the guest
 /// kernel. Here type means what the memory will be used for by the guest, and
 /// different permissions and policies may be applied to different region types.
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum AddressRegionType {
     /// Normal memory accessible by CPUs and IO devices
     DefaultMemory,
@@ -55,24 +86,65 @@ pub enum AddressRegionType {
     KernelData,
 }
 
+impl AddressRegionType {
+    /// Get the default access permissions to map regions of this type into the current process.
+    ///
+    /// Guest kernel code and read only data are mapped read-only, and device MMIO regions can't
+    /// be mapped at all. Boot info stays writable because the VMM fills it in.
+    pub fn default_protection(self) -> Option<Protection> {
+        match self {
+            AddressRegionType::KernelText | AddressRegionType::KernelRoData => {
+                Some(Protection::ReadOnly)
+            }
+            AddressRegionType::DeviceMemory => None,
+            _ => Some(Protection::ReadWrite),
+        }
+    }
+}
+
+/// Size of the pages backing an address region when it's mapped into the current process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSizePolicy {
+    /// Normal pages of the host
+    Normal,
+    /// Transparent huge pages of the given size, requested with `madvise(MADV_HUGEPAGE)`
+    Transparent(u64),
+    /// hugetlbfs pages of the given size. Anonymous regions are mapped with `MAP_HUGETLB`, and
+    /// regions backed by a file descriptor must come from hugetlbfs or `memfd_create()` with
+    /// `MFD_HUGETLB`.
+    HugeTlb(u64),
+}
+
+impl PageSizePolicy {
+    /// Get the size of the huge pages, if any.
+    pub fn huge_page_size(self) -> Option<u64> {
+        match self {
+            PageSizePolicy::Normal => None,
+            PageSizePolicy::Transparent(size) | PageSizePolicy::HugeTlb(size) => Some(size),
+        }
+    }
+}
+
 /// Represent a guest address region.
 pub struct AddressRegion {
     ty: AddressRegionType,
     base: GuestAddress,
-    size: usize,
-    fd: Option<Arc<AsRawFd>>,
-    offset: usize,
+    size: GuestUsize,
+    fd: Option<Arc<dyn AsRawFd + Send + Sync>>,
+    offset: u64,
+    page_size: PageSizePolicy,
 }
 
 impl AddressRegion {
     /// Create a memory region backed up by anonymous memory.
-    pub fn new(ty: AddressRegionType, base: GuestAddress, size: usize) -> Self {
+    pub fn new(ty: AddressRegionType, base: GuestAddress, size: GuestUsize) -> Self {
         AddressRegion {
             ty,
             base,
             size,
             fd: None,
             offset: 0,
+            page_size: PageSizePolicy::Normal,
         }
     }
 
@@ -80,9 +152,9 @@ impl AddressRegion {
     pub fn from_fd(
         ty: AddressRegionType,
         base: GuestAddress,
-        size: usize,
-        fd: Arc<AsRawFd>,
-        offset: usize,
+        size: GuestUsize,
+        fd: Arc<dyn AsRawFd + Send + Sync>,
+        offset: u64,
     ) -> Self {
         AddressRegion {
             ty,
@@ -90,9 +162,44 @@ impl AddressRegion {
             size,
             fd: Some(fd),
             offset,
+            page_size: PageSizePolicy::Normal,
         }
     }
 
+    /// Create a memory region backed up by a sealed memfd, so that it may be shared with other
+    /// processes such as vhost-user backends.
+    ///
+    /// The memfd is created with `MFD_HUGETLB` if the region is backed by hugetlbfs pages. Get it
+    /// with `get_fd()` to pass it over a Unix socket with `SCM_RIGHTS`.
+    pub fn new_memfd(
+        ty: AddressRegionType,
+        base: GuestAddress,
+        size: GuestUsize,
+        page_size: PageSizePolicy,
+    ) -> Result<Self, Error> {
+        let mut region = AddressRegion::new(ty, base, size).with_page_size(page_size);
+        region.check_page_size()?;
+        let huge_page_size = match page_size {
+            PageSizePolicy::HugeTlb(page_size) => {
+                Some(mmap::host_size(page_size).map_err(Error::MemoryMappingFailed)?)
+            }
+            _ => None,
+        };
+        let file =
+            mmap::create_sealed_memfd(size, huge_page_size).map_err(Error::MemoryMappingFailed)?;
+        region.fd = Some(Arc::new(file));
+        Ok(region)
+    }
+
+    /// Back the memory region with pages of the given size.
+    ///
+    /// The base, size and file offset of the region must be aligned to the huge page size for
+    /// the region to be inserted into an address space.
+    pub fn with_page_size(mut self, page_size: PageSizePolicy) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
     /// Get type of memory region.
     pub fn get_type(&self) -> AddressRegionType {
         self.ty
@@ -104,56 +211,73 @@ impl AddressRegion {
     }
 
     /// Get memory region size.
-    pub fn get_size(&self) -> usize {
+    pub fn get_size(&self) -> GuestUsize {
         self.size
     }
 
+    /// Get the range of guest addresses covered by the memory region.
+    pub fn get_range(&self) -> GuestAddressRange {
+        GuestAddressRange::new(self.base, self.size)
+    }
+
     /// Get optional file descriptor backing the memory region.
-    pub fn get_fd(&self) -> Option<Arc<AsRawFd>> {
-        match self.fd {
-            Some(ref fd) => Some(fd.clone()),
-            None => None,
-        }
+    pub fn get_fd(&self) -> Option<Arc<dyn AsRawFd + Send + Sync>> {
+        self.fd.clone()
     }
 
     /// Get file offset to mmap().
-    pub fn get_offset(&self) -> usize {
+    pub fn get_offset(&self) -> u64 {
         self.offset
     }
 
+    /// Get the size of the pages backing the memory region.
+    pub fn get_page_size(&self) -> PageSizePolicy {
+        self.page_size
+    }
+
     /// Check whether memory region has associated file descriptor
     pub fn has_fd(&self) -> bool {
         self.fd.is_some()
     }
 
     /// Check whether memory region is valid.
+    ///
+    /// Empty regions are invalid, they would fit inside other regions and hide them from lookups.
     pub fn is_valid(&self) -> bool {
-        !(self.base.checked_add(self.size).is_none() || (self.fd.is_none() && self.offset != 0))
+        self.size != 0
+            && self.get_range().end().is_some()
+            && (self.fd.is_some() || self.offset == 0)
     }
 
     /// Check whether intersects with another address region.
     pub fn intersect_with(&self, other: &AddressRegion) -> bool {
+        let (range1, range2) = (self.get_range(), other.get_range());
         // Treat invalid address region as intersecting always
-        let end1 = match self.base.checked_add(self.size) {
-            Some(addr) => addr,
-            None => return true,
-        };
-        let end2 = match other.base.checked_add(other.size) {
-            Some(addr) => addr,
-            None => return true,
-        };
-
-        if self.base >= other.base && self.base < end2 {
-            return true;
-        } else if end1 > other.base && end1 <= end2 {
-            return true;
-        } else if other.base >= self.base && other.base < end1 {
-            return true;
-        } else if end2 > self.base && end2 <= end1 {
+        if range1.end().is_none() || range2.end().is_none() {
             return true;
         }
+        range1.intersects(&range2)
+    }
 
-        false
+    // Check that the region may be backed by huge pages of the requested size.
+    fn check_page_size(&self) -> Result<(), Error> {
+        let page_size = match self.page_size.huge_page_size() {
+            Some(page_size) => page_size,
+            None => return Ok(()),
+        };
+        if !page_size.is_power_of_two() {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        let mask = page_size - 1;
+        if self.base.raw_value() & mask != 0 || self.size & mask != 0 || self.offset & mask != 0 {
+            return Err(Error::UnalignedRegion {
+                base: self.base,
+                size: self.size,
+                offset: self.offset,
+                page_size,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -166,17 +290,358 @@ impl AsRawFd for AddressRegion {
     }
 }
 
+/// Policy to choose among the free address ranges when allocating an address region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// Allocate from the lowest free address range large enough
+    FirstFit,
+    /// Allocate from the highest free address range large enough
+    LastFit,
+}
+
+/// Handle to an address region inserted into an `AddressSpace`.
+///
+/// Handles are never reused, so they stay valid across insertion and removal of other regions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AddressRegionId(u64);
+
+/// Change of the layout of an address space.
+#[derive(Clone)]
+pub enum AddressSpaceEvent {
+    /// An address region has been inserted.
+    RegionAdded(AddressRegionId, Arc<AddressRegion>),
+    /// An address region has been removed.
+    RegionRemoved(AddressRegionId, Arc<AddressRegion>),
+    /// An address region has been replaced by a new one, keeping the same handle.
+    RegionUpdated {
+        /// Handle of the address region.
+        id: AddressRegionId,
+        /// The replaced address region.
+        old: Arc<AddressRegion>,
+        /// The new address region.
+        new: Arc<AddressRegion>,
+    },
+}
+
+/// Subscriber to the layout changes of an address space.
+///
+/// Events are delivered in the order the changes happen, so listeners never observe a torn
+/// layout, but without the address space locked: the change reported may not be the latest one,
+/// and the caller of a change may return before the event is delivered by another thread.
+///
+/// Listeners may call back into the address space they are subscribed to, the events of the
+/// changes they make are delivered once they return. A listener which panics doesn't poison the
+/// address space, the panic propagates to the caller of the change and the listeners following
+/// it miss the event.
+pub trait AddressSpaceListener: Send + Sync {
+    /// Handle a change of the layout of the address space.
+    fn on_event(&self, event: &AddressSpaceEvent);
+}
+
+impl<F> AddressSpaceListener for F
+where
+    F: Fn(&AddressSpaceEvent) + Send + Sync,
+{
+    fn on_event(&self, event: &AddressSpaceEvent) {
+        self(event)
+    }
+}
+
+/// Handle to a listener subscribed to an `AddressSpace`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ListenerId(u64);
+
+type RegionEntry = (AddressRegionId, Arc<AddressRegion>);
+
+// Index of the region containing `addr`, in entries sorted by base address.
+fn find_entry(entries: &[RegionEntry], addr: GuestAddress) -> Option<usize> {
+    let index = entries.partition_point(|(_, reg)| reg.get_base() <= addr);
+    // Regions don't overlap, so only the last region starting at or below `addr` may contain it.
+    index
+        .checked_sub(1)
+        .filter(|index| entries[*index].1.get_range().contains(addr))
+}
+
+// Indexes of the regions intersecting with `range`, in entries sorted by base address.
+fn find_entries_in_range(entries: &[RegionEntry], range: &GuestAddressRange) -> Vec<usize> {
+    let first = find_entry(entries, range.start())
+        .unwrap_or_else(|| entries.partition_point(|(_, reg)| reg.get_base() < range.start()));
+    let mut indexes = Vec::new();
+    for (index, (_, reg)) in entries.iter().enumerate().skip(first) {
+        // The remaining regions start past the end of the range.
+        if reg.get_base() >= range.start() && !range.contains(reg.get_base()) {
+            break;
+        }
+        if reg.get_range().intersects(range) {
+            indexes.push(index);
+        }
+    }
+    indexes
+}
+
+/// Immutable view of the layout of an address space.
+///
+/// Snapshots are cheap to share and may be used without locking the address space. Each change
+/// of the layout creates a new snapshot with a higher generation number, so holders of a
+/// snapshot can detect that it's stale by comparing it with `AddressSpace::generation()`.
+pub struct AddressSpaceSnapshot {
+    generation: u64,
+    entries: Vec<RegionEntry>,
+}
+
+impl AddressSpaceSnapshot {
+    /// Get the generation number of the layout.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Get number of memory regions.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there's no memory region.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get specific space region by handle.
+    pub fn get_region(&self, id: AddressRegionId) -> Option<&Arc<AddressRegion>> {
+        self.entries
+            .iter()
+            .find(|entry| entry.0 == id)
+            .map(|entry| &entry.1)
+    }
+
+    /// Get the region containing the guest address `addr`.
+    pub fn find_region(&self, addr: GuestAddress) -> Option<&Arc<AddressRegion>> {
+        find_entry(&self.entries, addr).map(|index| &self.entries[index].1)
+    }
+
+    /// Get the regions intersecting with `range`, sorted by base address.
+    pub fn find_regions_in_range(&self, range: &GuestAddressRange) -> Vec<&Arc<AddressRegion>> {
+        find_entries_in_range(&self.entries, range)
+            .into_iter()
+            .map(|index| &self.entries[index].1)
+            .collect()
+    }
+
+    /// Iterate over the regions and their handles, sorted by base address.
+    pub fn iter(&self) -> impl Iterator<Item = (AddressRegionId, &Arc<AddressRegion>)> + '_ {
+        self.entries.iter().map(|(id, region)| (*id, region))
+    }
+}
+
+// Regions of an address space and the handles assigned to them.
+//
+// Entries are kept sorted by base address (and by size for regions sharing the same base), so
+// that address lookups and conflict checks take logarithmic time.
+//
+// Reserved ranges are holes, sorted by start address, which the allocator must never hand out.
+// Protections override the default access permissions of region types.
+// Dirty pages are tracked in the guest memory mapped for the regions if a page size is set.
+struct AddressRegions {
+    entries: Vec<RegionEntry>,
+    reserved: Vec<GuestAddressRange>,
+    protections: HashMap<AddressRegionType, Option<Protection>>,
+    listeners: Vec<(ListenerId, Arc<dyn AddressSpaceListener>)>,
+    // Events not delivered yet, with the listeners subscribed when they happened.
+    events: VecDeque<(Vec<Arc<dyn AddressSpaceListener>>, AddressSpaceEvent)>,
+    dirty_page_size: Option<usize>,
+    memfd_backing: Option<PageSizePolicy>,
+    reservation: Option<Arc<MemoryReservation>>,
+    // Address regions backing the memory regions mapped by this address space.
+    mapped: Vec<(Weak<MemoryRegion>, Arc<AddressRegion>)>,
+    next_id: u64,
+    next_listener_id: u64,
+}
+
+impl AddressRegions {
+    fn alloc_id(&mut self) -> AddressRegionId {
+        let id = AddressRegionId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    fn notify(&mut self, event: AddressSpaceEvent) {
+        if !self.listeners.is_empty() {
+            let listeners = self.listeners.iter().map(|(_, l)| l.clone()).collect();
+            self.events.push_back((listeners, event));
+        }
+    }
+
+    fn protection(&self, ty: AddressRegionType) -> Option<Protection> {
+        match self.protections.get(&ty) {
+            Some(prot) => *prot,
+            None => ty.default_protection(),
+        }
+    }
+
+    fn position(&self, id: AddressRegionId) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.0 == id)
+    }
+
+    // Index where `region` should be inserted to keep the entries sorted.
+    fn insert_position(&self, region: &AddressRegion) -> usize {
+        let key = (region.get_base(), region.get_size());
+        self.entries
+            .partition_point(|(_, reg)| (reg.get_base(), reg.get_size()) < key)
+    }
+
+    fn find(&self, addr: GuestAddress) -> Option<usize> {
+        find_entry(&self.entries, addr)
+    }
+
+    fn find_in_range(&self, range: &GuestAddressRange) -> Vec<usize> {
+        find_entries_in_range(&self.entries, range)
+    }
+
+    // Check that `region` may be inserted, ignoring the region identified by `skip`.
+    fn check_region(
+        &self,
+        region: &AddressRegion,
+        skip: Option<AddressRegionId>,
+    ) -> Result<(), Error> {
+        if !region.is_valid() {
+            return Err(Error::InvalidAddressRange(
+                region.get_base(),
+                region.get_size(),
+            ));
+        }
+        region.check_page_size()?;
+        let conflict = Err(Error::ConflictAddressRange(
+            region.get_base(),
+            region.get_size(),
+        ));
+        let index = self.insert_position(region);
+
+        // Regions don't overlap, so the closest region below `region` is the one reaching the
+        // highest address.
+        let below = self.entries[..index]
+            .iter()
+            .rev()
+            .find(|(id, _)| Some(*id) != skip);
+        if let Some((_, reg)) = below {
+            if region.intersect_with(reg) {
+                return conflict;
+            }
+        }
+        let range = region.get_range();
+        for (id, reg) in self.entries[index..].iter() {
+            if !range.contains(reg.get_base()) {
+                break;
+            }
+            if Some(*id) != skip && region.intersect_with(reg) {
+                return conflict;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, id: AddressRegionId, region: Arc<AddressRegion>) {
+        let index = self.insert_position(&region);
+        self.entries.insert(index, (id, region));
+    }
+
+    // Free ranges within `window`, as `(start, end)` pairs sorted by start address.
+    fn free_ranges(&self, window: &GuestAddressRange) -> Vec<(GuestAddress, GuestAddress)> {
+        // Regions ending at the top of the address space are invalid, so the last byte is never
+        // handed out.
+        let window_end = window.end().unwrap_or(GuestAddress(u64::MAX));
+        let mut used: Vec<(GuestAddress, GuestAddress)> = self
+            .find_in_range(window)
+            .into_iter()
+            .map(|index| self.entries[index].1.get_range())
+            .chain(
+                self.reserved
+                    .iter()
+                    .filter(|range| range.intersects(window))
+                    .cloned(),
+            )
+            .map(|range| {
+                let end = range.end().unwrap_or(GuestAddress(u64::MAX));
+                (range.start(), end)
+            })
+            .collect();
+        used.sort();
+
+        let mut free = Vec::new();
+        let mut cursor = window.start();
+        for (start, end) in used {
+            if start > cursor {
+                free.push((cursor, start.min(window_end)));
+            }
+            cursor = cursor.max(end);
+        }
+        if window_end > cursor {
+            free.push((cursor, window_end));
+        }
+        free
+    }
+
+    // Find a free and suitably aligned base address for a region of `size` bytes.
+    fn find_free(
+        &self,
+        size: GuestUsize,
+        alignment: u64,
+        window: &GuestAddressRange,
+        policy: AllocPolicy,
+    ) -> Option<GuestAddress> {
+        let free = self.free_ranges(window);
+        match policy {
+            AllocPolicy::FirstFit => free.into_iter().find_map(|(start, end)| {
+                let base = start.align_up(alignment)?;
+                match base.checked_add(size) {
+                    Some(top) if top <= end => Some(base),
+                    _ => None,
+                }
+            }),
+            AllocPolicy::LastFit => free.into_iter().rev().find_map(|(start, end)| {
+                let base = end.checked_sub(size)?.align_down(alignment);
+                if base >= start {
+                    Some(base)
+                } else {
+                    None
+                }
+            }),
+        }
+    }
+}
+
 /// Maintain address space information for a virtual machine.
 pub struct AddressSpace {
-    regions: Mutex<Vec<Arc<AddressRegion>>>,
+    regions: Mutex<AddressRegions>,
+    // Current snapshot of the layout, only locked to clone or replace it, so readers never wait
+    // for a change of the layout. Only replaced with `regions` locked, so that snapshots are
+    // published in order.
+    snapshot: Mutex<Arc<AddressSpaceSnapshot>>,
+    // Held by the thread delivering the queued events to the listeners.
+    dispatching: Mutex<()>,
 }
 
 impl AddressSpace {
     /// Create an address space.
+    ///
+    /// The regions are not checked for conflicts, the caller must make sure they don't overlap.
     pub fn new(vec: Vec<Arc<AddressRegion>>) -> Self {
-        AddressSpace {
-            regions: Mutex::new(vec),
+        let mut regions = AddressRegions {
+            entries: Vec::with_capacity(vec.len()),
+            reserved: Vec::new(),
+            protections: HashMap::new(),
+            listeners: Vec::new(),
+            events: VecDeque::new(),
+            dirty_page_size: None,
+            memfd_backing: None,
+            reservation: None,
+            mapped: Vec::new(),
+            next_id: 0,
+            next_listener_id: 0,
+        };
+        for region in vec {
+            let id = regions.alloc_id();
+            regions.insert(id, region);
         }
+        AddressSpace::from_regions(regions)
     }
 
     /// Create an empty address space.
@@ -187,11 +652,95 @@ impl AddressSpace {
             _ => size,
         };
 
+        AddressSpace::from_regions(AddressRegions {
+            entries: Vec::with_capacity(cap),
+            reserved: Vec::new(),
+            protections: HashMap::new(),
+            listeners: Vec::new(),
+            events: VecDeque::new(),
+            dirty_page_size: None,
+            memfd_backing: None,
+            reservation: None,
+            mapped: Vec::new(),
+            next_id: 0,
+            next_listener_id: 0,
+        })
+    }
+
+    fn from_regions(regions: AddressRegions) -> Self {
+        let snapshot = AddressSpaceSnapshot {
+            generation: 0,
+            entries: regions.entries.clone(),
+        };
         AddressSpace {
-            regions: Mutex::new(Vec::with_capacity(cap)),
+            regions: Mutex::new(regions),
+            snapshot: Mutex::new(Arc::new(snapshot)),
+            dispatching: Mutex::new(()),
+        }
+    }
+
+    // Publish a snapshot of the new layout, then unlock the regions and notify the listeners of
+    // the change.
+    fn commit(&self, mut regions: MutexGuard<'_, AddressRegions>, event: AddressSpaceEvent) {
+        let snapshot = Arc::new(AddressSpaceSnapshot {
+            generation: self.generation() + 1,
+            entries: regions.entries.clone(),
+        });
+        *self.snapshot.lock().unwrap() = snapshot;
+        regions.notify(event);
+        drop(regions);
+        self.dispatch();
+    }
+
+    // Deliver the queued events to the listeners, in order, with the regions unlocked.
+    //
+    // A single thread delivers events at a time. Events queued meanwhile, including by listeners
+    // changing the layout, are delivered by that thread, which checks for them again after
+    // giving up the delivery.
+    fn dispatch(&self) {
+        loop {
+            let guard = match self.dispatching.try_lock() {
+                Ok(guard) => guard,
+                Err(TryLockError::WouldBlock) => return,
+                // A listener panicked while delivering a previous event, which leaves the queue
+                // consistent.
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            };
+            // Assuming the lock is healthy otherwise we are already in trouble
+            loop {
+                let next = self.regions.lock().unwrap().events.pop_front();
+                match next {
+                    Some((listeners, event)) => {
+                        for listener in listeners {
+                            listener.on_event(&event);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            drop(guard);
+            if self.regions.lock().unwrap().events.is_empty() {
+                return;
+            }
         }
     }
 
+    /// Get a consistent snapshot of the current layout of the address space.
+    ///
+    /// The snapshot is only locked while its reference count is incremented, so readers are
+    /// never blocked for the duration of a change of the layout.
+    pub fn snapshot(&self) -> Arc<AddressSpaceSnapshot> {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    /// Get the generation number of the current layout, which increases on every change.
+    ///
+    /// This is cheap, so it may be used to check whether a snapshot is stale. It's read from the
+    /// current snapshot, so it's never ahead of the snapshot returned by `snapshot()`.
+    pub fn generation(&self) -> u64 {
+        self.snapshot.lock().unwrap().generation
+    }
+
     /// Create an address region mapping content from a file descriptor.
     ///
     /// # Arguments
@@ -201,13 +750,13 @@ impl AddressSpace {
     /// * `fd` - File descriptor to map content from
     /// * `offset` - The offset into file to start mapping
     pub fn add_region(
-        &mut self,
+        &self,
         ty: AddressRegionType,
         base: GuestAddress,
-        size: usize,
-        fd: Option<Arc<AsRawFd>>,
-        offset: usize,
-    ) -> Result<usize, Error> {
+        size: GuestUsize,
+        fd: Option<Arc<dyn AsRawFd + Send + Sync>>,
+        offset: u64,
+    ) -> Result<AddressRegionId, Error> {
         let region = match fd {
             Some(fd1) => Arc::new(AddressRegion::from_fd(ty, base, size, fd1, offset)),
             None => Arc::new(AddressRegion::new(ty, base, size)),
@@ -217,10 +766,24 @@ impl AddressSpace {
 
     /// Create an address region mapping anonymous memory.
     ///
+    /// The memory is backed by a sealed memfd if enabled by `set_memfd_backing()`, with the page
+    /// size policy given there.
+    ///
     /// # Arguments
     /// * `base` - Base address in VM to map content
     /// * `size` - Length of content to map
-    pub fn add_default_memory(&mut self, base: GuestAddress, size: usize) -> Result<usize, Error> {
+    pub fn add_default_memory(
+        &self,
+        base: GuestAddress,
+        size: GuestUsize,
+    ) -> Result<AddressRegionId, Error> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let memfd_backing = self.regions.lock().unwrap().memfd_backing;
+        if let Some(page_size) = memfd_backing {
+            let region =
+                AddressRegion::new_memfd(AddressRegionType::DefaultMemory, base, size, page_size)?;
+            return self.insert_region(Arc::new(region));
+        }
         self.add_region(AddressRegionType::DefaultMemory, base, size, None, 0)
     }
 
@@ -229,7 +792,11 @@ impl AddressSpace {
     /// # Arguments
     /// * `base` - Base address in VM to map content
     /// * `size` - Length of content to map
-    pub fn add_device_memory(&mut self, base: GuestAddress, size: usize) -> Result<usize, Error> {
+    pub fn add_device_memory(
+        &self,
+        base: GuestAddress,
+        size: GuestUsize,
+    ) -> Result<AddressRegionId, Error> {
         let region = Arc::new(AddressRegion::new(
             AddressRegionType::DeviceMemory,
             base,
@@ -238,28 +805,270 @@ impl AddressSpace {
         self.insert_region(region)
     }
 
+    /// Insert an address region into the address space.
+    ///
+    /// Returns a handle to the region, which may be used to remove or replace it later.
+    pub fn insert_region(&self, region: Arc<AddressRegion>) -> Result<AddressRegionId, Error> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        regions.check_region(&region, None)?;
+        let id = regions.alloc_id();
+        regions.insert(id, region.clone());
+        self.commit(regions, AddressSpaceEvent::RegionAdded(id, region));
+        Ok(id)
+    }
+
+    /// Remove an address region from the address space.
+    ///
+    /// Returns the removed region. Memory already mapped for the region by `map_guest_memory()`
+    /// stays valid until the corresponding `GuestMemory` object is dropped.
+    pub fn remove_region(&self, id: AddressRegionId) -> Result<Arc<AddressRegion>, Error> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let index = regions.position(id).ok_or(Error::InvalidRegionId(id))?;
+        let region = regions.entries.remove(index).1;
+        self.commit(
+            regions,
+            AddressSpaceEvent::RegionRemoved(id, region.clone()),
+        );
+        Ok(region)
+    }
+
+    /// Replace an address region with a new one, for example to relocate a PCI BAR.
+    ///
+    /// The new region keeps the handle of the old one, and is checked for conflicts against all
+    /// the other regions. Returns the replaced region.
+    pub fn replace_region(
+        &self,
+        id: AddressRegionId,
+        region: Arc<AddressRegion>,
+    ) -> Result<Arc<AddressRegion>, Error> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let index = regions.position(id).ok_or(Error::InvalidRegionId(id))?;
+        regions.check_region(&region, Some(id))?;
+        let old = regions.entries.remove(index).1;
+        regions.insert(id, region.clone());
+        self.commit(
+            regions,
+            AddressSpaceEvent::RegionUpdated {
+                id,
+                old: old.clone(),
+                new: region,
+            },
+        );
+        Ok(old)
+    }
+
+    /// Allocate a free address range and insert an anonymous address region for it.
+    ///
+    /// The region is placed within `window`, avoiding existing regions and reserved ranges.
+    /// Returns the handle and the base address of the new region.
+    ///
+    /// # Arguments
+    /// * `size` - Size of the address region
+    /// * `alignment` - Alignment of the base address, must be a power of two
+    /// * `window` - Range of guest addresses the region must fit in
+    /// * `ty` - Type of the address region
+    /// * `policy` - Whether to allocate from the lowest or the highest free range
+    pub fn allocate(
+        &self,
+        size: GuestUsize,
+        alignment: u64,
+        window: GuestAddressRange,
+        ty: AddressRegionType,
+        policy: AllocPolicy,
+    ) -> Result<(AddressRegionId, GuestAddress), Error> {
+        if !alignment.is_power_of_two() {
+            return Err(Error::InvalidAlignment(alignment));
+        }
+        if size == 0 {
+            return Err(Error::InvalidAddressRange(window.start(), size));
+        }
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let base = regions
+            .find_free(size, alignment, &window, policy)
+            .ok_or(Error::NoFreeAddressRange(size))?;
+        let region = Arc::new(AddressRegion::new(ty, base, size));
+        regions.check_region(&region, None)?;
+        let id = regions.alloc_id();
+        regions.insert(id, region.clone());
+        self.commit(regions, AddressSpaceEvent::RegionAdded(id, region));
+        Ok((id, base))
+    }
+
+    /// Reserve an address range, such as the 32-bit PCI hole or the LAPIC/IOAPIC windows.
+    ///
+    /// Reserved ranges are never handed out by `allocate()`, but regions may still be inserted
+    /// into them explicitly.
+    pub fn reserve_range(&self, range: GuestAddressRange) -> Result<(), Error> {
+        if range.is_empty() || range.end().is_none() {
+            return Err(Error::InvalidAddressRange(range.start(), range.len()));
+        }
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let index = regions
+            .reserved
+            .partition_point(|reserved| reserved.start() <= range.start());
+        regions.reserved.insert(index, range);
+        Ok(())
+    }
+
+    /// Get the reserved address ranges, sorted by start address.
+    pub fn get_reserved_ranges(&self) -> Vec<GuestAddressRange> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().reserved.clone()
+    }
+
+    /// Subscribe a listener to the layout changes of the address space.
+    ///
+    /// The listener first receives a `RegionAdded` event for each existing region, then an event
+    /// for every subsequent change. Returns a handle to unsubscribe the listener.
+    pub fn add_listener(&self, listener: Arc<dyn AddressSpaceListener>) -> ListenerId {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let added: Vec<_> = regions
+            .entries
+            .iter()
+            .map(|(id, region)| AddressSpaceEvent::RegionAdded(*id, region.clone()))
+            .collect();
+        for event in added {
+            regions.events.push_back((vec![listener.clone()], event));
+        }
+        let id = ListenerId(regions.next_listener_id);
+        regions.next_listener_id += 1;
+        regions.listeners.push((id, listener));
+        drop(regions);
+        self.dispatch();
+        id
+    }
+
+    /// Unsubscribe a listener from the layout changes of the address space.
+    ///
+    /// The listener may still receive the events of changes made before it was unsubscribed.
+    pub fn remove_listener(&self, id: ListenerId) -> Result<Arc<dyn AddressSpaceListener>, Error> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut regions = self.regions.lock().unwrap();
+        let index = regions
+            .listeners
+            .iter()
+            .position(|entry| entry.0 == id)
+            .ok_or(Error::InvalidListenerId(id))?;
+        Ok(regions.listeners.remove(index).1)
+    }
+
+    /// Override the access permissions to map regions of type `ty` into the current process.
+    ///
+    /// Regions of a type with no access permissions can't be mapped by `map_guest_memory()`.
+    pub fn set_protection(&self, ty: AddressRegionType, prot: Option<Protection>) {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().protections.insert(ty, prot);
+    }
+
+    /// Get the access permissions to map regions of type `ty` into the current process.
+    pub fn get_protection(&self, ty: AddressRegionType) -> Option<Protection> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().protection(ty)
+    }
+
+    /// Track the pages written by the VMM in the guest memory mapped from now on.
+    ///
+    /// Pass the size of the pages to track, or None to stop tracking dirty pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page size is not a power of two.
+    pub fn set_dirty_tracking(&self, page_size: Option<usize>) {
+        assert!(page_size.map_or(true, |size| size.is_power_of_two()));
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().dirty_page_size = page_size;
+    }
+
+    /// Back the default memory added from now on by `add_default_memory()` with sealed memfds
+    /// instead of anonymous mappings, so that it may be shared with other processes. The memfds
+    /// are backed by pages of the given size, see `AddressRegion::new_memfd()`. Pass None to
+    /// back the default memory with anonymous mappings again.
+    pub fn set_memfd_backing(&self, page_size: Option<PageSizePolicy>) {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().memfd_backing = page_size;
+    }
+
+    /// Map the memory regions mapped from now on at their guest address in one contiguous range
+    /// of the address space of the current process, reserved to cover guest addresses from 0 to
+    /// `end_addr`. Pass None to map regions anywhere.
+    ///
+    /// Guest addresses then translate to host addresses with a single add, see
+    /// `GuestMemory::host_base()`, and the holes between the regions fault if touched. The
+    /// reservation is aligned to 1 GiB so that regions backed by huge pages may be mapped in it.
+    ///
+    /// Regions beyond `end_addr` can't be mapped, and a region can't be mapped again while it's
+    /// still mapped by another `GuestMemory` object, unless the mapping is shared by
+    /// `update_guest_memory()`. Regions are still unmapped individually, and the range they leave
+    /// becomes inaccessible again.
+    pub fn set_contiguous_mapping(&self, end_addr: Option<GuestAddress>) -> Result<(), Error> {
+        let reservation = match end_addr {
+            Some(end_addr) => {
+                let size =
+                    mmap::host_size(end_addr.raw_value()).map_err(Error::MemoryMappingFailed)?;
+                let reservation = MemoryReservation::new(size, CONTIGUOUS_MAPPING_ALIGNMENT)
+                    .map_err(Error::MemoryMappingFailed)?;
+                Some(Arc::new(reservation))
+            }
+            None => None,
+        };
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.regions.lock().unwrap().reservation = reservation;
+        Ok(())
+    }
+
     /// Get number of memory regions.
     pub fn len(&self) -> usize {
         // Assuming the lock is healthy otherwise we are already in trouble
-        self.regions.lock().unwrap().len()
+        self.regions.lock().unwrap().entries.len()
     }
 
-    /// Get specific space region by index
-    pub fn get_region(&self, index: usize) -> Option<Arc<AddressRegion>> {
+    /// Check whether there's no memory region.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get specific space region by handle
+    pub fn get_region(&self, id: AddressRegionId) -> Option<Arc<AddressRegion>> {
         // Assuming the lock is healthy otherwise we are already in trouble
         let regions = self.regions.lock().unwrap();
-        if index < regions.len() {
-            Some(regions[index].clone())
-        } else {
-            None
-        }
+        regions
+            .position(id)
+            .map(|index| regions.entries[index].1.clone())
+    }
+
+    /// Get the region containing the guest address `addr`.
+    ///
+    /// The lookup takes logarithmic time, so it's suitable for the MMIO exit path.
+    pub fn find_region(&self, addr: GuestAddress) -> Option<Arc<AddressRegion>> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let regions = self.regions.lock().unwrap();
+        regions
+            .find(addr)
+            .map(|index| regions.entries[index].1.clone())
+    }
+
+    /// Get the regions intersecting with `range`, sorted by base address.
+    pub fn find_regions_in_range(&self, range: &GuestAddressRange) -> Vec<Arc<AddressRegion>> {
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let regions = self.regions.lock().unwrap();
+        regions
+            .find_in_range(range)
+            .into_iter()
+            .map(|index| regions.entries[index].1.clone())
+            .collect()
     }
 
     /// Get regions of specific type
     pub fn get_regions_by_type(&self, ty: AddressRegionType) -> Vec<Arc<AddressRegion>> {
         let mut vec = Vec::new();
         let regions = self.regions.lock().unwrap();
-        for region in regions.iter() {
+        for (_, region) in regions.entries.iter() {
             if region.get_type() == ty {
                 vec.push(region.clone());
             }
@@ -274,67 +1083,119 @@ impl AddressSpace {
     {
         // Assuming the lock is healthy otherwise we are already in trouble
         let regions = self.regions.lock().unwrap();
-        for region in regions.iter() {
+        for (_, region) in regions.entries.iter() {
             cb(region)?;
         }
         Ok(())
     }
 
     /// Map memory regions of specific type into current process.
+    ///
+    /// Regions are mapped with the access permissions of their type, see `set_protection()`.
     pub fn map_guest_memory(&self, types: &[AddressRegionType]) -> Result<GuestMemory, Error> {
-        // Can't map regions of device MMIO into current process
-        if types.contains(&AddressRegionType::DeviceMemory) {
-            return Err(Error::InvalidOperation);
-        }
-        let regions = self.map_regions_by_types(types)?;
-        Ok(GuestMemory::from_regions(regions))
+        let regions = self.map_regions_by_types(types, None)?;
+        GuestMemory::from_shared_regions(regions).map_err(Error::GuestMemoryFailed)
     }
 
-    fn map_regions_by_types(
+    /// Derive a new guest memory object from `mem` for the memory regions of specific type.
+    ///
+    /// Regions of `mem` mapped by this address space from the same address region, with the same
+    /// access permissions, dirty page tracking and host address reservation, are shared instead
+    /// of being mapped again, so hot-adding memory doesn't duplicate the existing mappings.
+    /// Regions which are no longer part of the address space are dropped, and regions replaced
+    /// by another backing are mapped again.
+    pub fn update_guest_memory(
         &self,
+        mem: &GuestMemory,
         types: &[AddressRegionType],
-    ) -> Result<Vec<MemoryRegion>, Error> {
-        let mut regions = Vec::<MemoryRegion>::new();
+    ) -> Result<GuestMemory, Error> {
+        let regions = self.map_regions_by_types(types, Some(mem))?;
+        GuestMemory::from_shared_regions(regions).map_err(Error::GuestMemoryFailed)
+    }
+
+    fn map_regions_by_types(
+        &self,
+        types: &[AddressRegionType],
+        mapped: Option<&GuestMemory>,
+    ) -> Result<Vec<Arc<MemoryRegion>>, Error> {
+        let mut regions = Vec::<Arc<MemoryRegion>>::new();
         // Assuming the lock is healthy otherwise we are already in trouble
-        let regs = self.regions.lock().unwrap();
-        for region in regs.iter() {
-            if types.contains(&region.ty) {
-                let mapping = match region.fd {
-                    Some(ref fd) => {
-                        MemoryMapping::from_fd_offset(&**fd, region.size, region.offset)
-                            .map_err(|e| Error::MemoryMappingFailed(e))?
+        let mut regs = self.regions.lock().unwrap();
+        regs.mapped.retain(|(mr, _)| mr.upgrade().is_some());
+        // Can't map regions without access permissions, such as device MMIO, into current process
+        if types.iter().any(|ty| regs.protection(*ty).is_none()) {
+            return Err(Error::InvalidOperation);
+        }
+        let mut new_mappings = Vec::new();
+        for (_, region) in regs.entries.iter() {
+            if let Some(prot) = types
+                .iter()
+                .find(|ty| **ty == region.ty)
+                .and_then(|ty| regs.protection(*ty))
+            {
+                // Address regions are immutable, so a memory region mapped from the same address
+                // region has the same range, file descriptor, offset and page size policy.
+                let existing = mapped
+                    .and_then(|mem| mem.find_region(region.base))
+                    .filter(|mr| {
+                        let same_reservation =
+                            match (mr.mapping().reservation(), regs.reservation.as_ref()) {
+                                (Some(r1), Some(r2)) => Arc::ptr_eq(r1, r2),
+                                (None, None) => true,
+                                _ => false,
+                            };
+                        regs.mapped.iter().any(|(weak, reg)| {
+                            Weak::as_ptr(weak) == Arc::as_ptr(mr) && Arc::ptr_eq(reg, region)
+                        }) && mr.protection() == prot
+                            && mr.dirty_bitmap().map(|dirty| dirty.page_size())
+                                == regs.dirty_page_size
+                            && same_reservation
+                    });
+                if let Some(mr) = existing {
+                    regions.push(mr.clone());
+                    continue;
+                }
+                // The region may be too big to be mapped into a 32-bit process.
+                let size = mmap::host_size(region.size).map_err(Error::MemoryMappingFailed)?;
+                let mut builder = MemoryMappingBuilder::new(size).protection(prot);
+                builder = match (&region.fd, region.page_size) {
+                    (Some(fd), _) => builder.from_fd(&**fd, region.offset),
+                    // Reserve the huge pages up front, faulting in a page which can't be
+                    // allocated would kill the process.
+                    (None, PageSizePolicy::HugeTlb(page_size)) => builder.hugetlb(Some(
+                        mmap::host_size(page_size).map_err(Error::MemoryMappingFailed)?,
+                    )),
+                    (None, _) => builder.noreserve(true),
+                };
+                if let Some(ref reservation) = regs.reservation {
+                    let offset = mmap::host_size(region.base.raw_value())
+                        .map_err(Error::MemoryMappingFailed)?;
+                    builder = builder.in_reservation(reservation.clone(), offset);
+                }
+                let mapping = builder.build().map_err(Error::MemoryMappingFailed)?;
+                if let PageSizePolicy::Transparent(_) = region.page_size {
+                    // Transparent huge pages are only a hint, which kernels built without them
+                    // reject with EINVAL.
+                    match mapping.advise_hugepages() {
+                        Err(MmapError::SystemCallFailed(ref e))
+                            if e.raw_os_error() == Some(libc::EINVAL) => {}
+                        res => res.map_err(Error::MemoryMappingFailed)?,
+                    }
+                }
+                let mr = match regs.dirty_page_size {
+                    Some(page_size) => {
+                        MemoryRegion::with_dirty_tracking(mapping, region.base, page_size)
                     }
-                    None => MemoryMapping::new(region.size)
-                        .map_err(|e| Error::MemoryMappingFailed(e))?,
+                    None => MemoryRegion::new(mapping, region.base),
                 };
-                regions.push(MemoryRegion::new(mapping, region.base));
+                let mr = Arc::new(mr);
+                new_mappings.push((Arc::downgrade(&mr), region.clone()));
+                regions.push(mr);
             }
         }
+        regs.mapped.extend(new_mappings);
         Ok(regions)
     }
-
-    fn insert_region(&mut self, region: Arc<AddressRegion>) -> Result<usize, Error> {
-        if !region.is_valid() {
-            return Err(Error::InvalidAddressRange(
-                region.get_base(),
-                region.get_size(),
-            ));
-        }
-
-        // Assuming the lock is healthy otherwise we are already in trouble
-        let mut regions = self.regions.lock().unwrap();
-        for reg in regions.iter() {
-            if region.intersect_with(reg) {
-                return Err(Error::ConflictAddressRange(
-                    region.get_base(),
-                    region.get_size(),
-                ));
-            }
-        }
-
-        regions.push(region);
-        Ok(regions.len() - 1)
-    }
 }
 
 #[cfg(test)]
@@ -344,7 +1205,11 @@ mod tests {
     use self::tempfile::tempfile;
     use super::*;
     use guest_address::GuestAddress;
+    use libc;
+    use mmap::MemoryMapping;
     use std::io::Write;
+    use std::panic;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn test_memory_region_valid() {
@@ -366,6 +1231,8 @@ mod tests {
             0x1000,
         );
         assert!(reg1.is_valid());
+        let reg1 = AddressRegion::new(AddressRegionType::DefaultMemory, GuestAddress(0x1000), 0);
+        assert!(!reg1.is_valid());
 
         let mut f = Arc::new(tempfile().unwrap());
         let sample_buf = &[1, 2, 3, 4, 5];
@@ -423,14 +1290,25 @@ mod tests {
         assert!(reg5.intersect_with(&reg1));
     }
 
+    #[test]
+    fn reject_empty_region() {
+        let space = AddressSpace::with_capacity(0);
+        space.add_default_memory(GuestAddress(0), 0x10000).unwrap();
+        assert!(space.add_default_memory(GuestAddress(0x8000), 0).is_err());
+        assert_eq!(space.len(), 1);
+        assert!(space.find_region(GuestAddress(0x8000)).is_some());
+        let range = GuestAddressRange::new(GuestAddress(0x8000), 0x1000);
+        assert_eq!(space.find_regions_in_range(&range).len(), 1);
+    }
+
     #[test]
     fn create_address_space() {
         let mut f = Arc::new(tempfile().unwrap());
         let sample_buf = &[1, 2, 3, 4, 5];
         assert!(Arc::get_mut(&mut f).unwrap().write_all(sample_buf).is_ok());
 
-        let mut space = AddressSpace::with_capacity(0);
-        space
+        let space = AddressSpace::with_capacity(0);
+        let text_id = space
             .add_region(
                 AddressRegionType::KernelText,
                 GuestAddress(0x100000),
@@ -439,7 +1317,7 @@ mod tests {
                 0x0,
             )
             .unwrap();
-        let region = space.get_region(0).unwrap();
+        let region = space.get_region(text_id).unwrap();
         assert_eq!(region.get_fd().unwrap().as_raw_fd(), f.as_raw_fd());
         assert_eq!(region.get_offset(), 0);
         assert!(region.has_fd());
@@ -449,9 +1327,9 @@ mod tests {
         let regions = space.get_regions_by_type(AddressRegionType::KernelText);
         assert_eq!(regions.len(), 1);
 
-        space.add_default_memory(GuestAddress(0), 0x100000).unwrap();
+        let mem_id = space.add_default_memory(GuestAddress(0), 0x100000).unwrap();
         assert_eq!(space.len(), 2);
-        assert!(space.get_region(2).is_none());
+        assert!(space.get_region(AddressRegionId(2)).is_none());
         space
             .with_regions(|region| {
                 if region.get_size() == 0x100000 {
@@ -461,7 +1339,7 @@ mod tests {
             })
             .unwrap_err();
 
-        let region = space.get_region(1).unwrap();
+        let region = space.get_region(mem_id).unwrap();
         assert_eq!(region.get_base().offset(), 0x0);
         assert_eq!(region.get_size(), 0x100000);
         assert_eq!(region.get_offset(), 0);
@@ -470,6 +1348,18 @@ mod tests {
 
         let m = space.map_guest_memory(&[AddressRegionType::DeviceMemory]);
         assert!(m.is_err());
+        space.set_protection(AddressRegionType::DefaultMemory, None);
+        assert_eq!(space.get_protection(AddressRegionType::DefaultMemory), None);
+        let m = space.map_guest_memory(&[AddressRegionType::DefaultMemory]);
+        assert!(m.is_err());
+        space.set_protection(
+            AddressRegionType::DefaultMemory,
+            Some(Protection::ReadWrite),
+        );
+        let m = space
+            .map_guest_memory(&[AddressRegionType::DefaultMemory])
+            .unwrap();
+        m.write_obj_at_addr(0x5au8, GuestAddress(0x1000)).unwrap();
 
         let m = space
             .map_guest_memory(&[
@@ -486,6 +1376,20 @@ mod tests {
         let mut val: u8 = m.read_obj_from_addr(GuestAddress(0x100001)).unwrap();
         assert_eq!(val, 2);
 
+        // Kernel text is mapped read-only by default.
+        val = 0xa5;
+        match m.write_obj_at_addr(val, GuestAddress(0x100001)) {
+            Err(GuestMemoryError::ReadOnlyMemory(addr)) => assert_eq!(addr, GuestAddress(0x100001)),
+            _ => panic!("kernel text should be read-only"),
+        }
+        assert!(m.write_at_addr(&[val], GuestAddress(0x100001)).is_err());
+        val = m.read_obj_from_addr(GuestAddress(0x100001)).unwrap();
+        assert_eq!(val, 2);
+
+        space.set_protection(AddressRegionType::KernelText, Some(Protection::ReadWrite));
+        let m = space
+            .map_guest_memory(&[AddressRegionType::KernelText])
+            .unwrap();
         val = 0xa5;
         m.write_obj_at_addr(val, GuestAddress(0x100001)).unwrap();
         val = m.read_obj_from_addr(GuestAddress(0x100001)).unwrap();
@@ -503,6 +1407,791 @@ mod tests {
         assert!(m.read_obj_from_addr::<u8>(GuestAddress(0x101000)).is_err());
     }
 
+    #[test]
+    fn remove_and_replace_region() {
+        let space = AddressSpace::with_capacity(0);
+        let id1 = space
+            .add_default_memory(GuestAddress(0x0), 0x10000)
+            .unwrap();
+        let id2 = space
+            .add_device_memory(GuestAddress(0x10000), 0x1000)
+            .unwrap();
+        let id3 = space
+            .add_default_memory(GuestAddress(0x20000), 0x10000)
+            .unwrap();
+        assert_ne!(id1, id2);
+        assert_ne!(id2, id3);
+
+        // Handles stay valid after removing other regions.
+        let region = space.remove_region(id1).unwrap();
+        assert_eq!(region.get_base(), GuestAddress(0x0));
+        assert_eq!(space.len(), 2);
+        assert!(space.get_region(id1).is_none());
+        assert_eq!(
+            space.get_region(id3).unwrap().get_base(),
+            GuestAddress(0x20000)
+        );
+        match space.remove_region(id1) {
+            Err(Error::InvalidRegionId(id)) => assert_eq!(id, id1),
+            _ => panic!("removed the same region twice"),
+        }
+
+        // Handles are never reused.
+        let id4 = space.add_default_memory(GuestAddress(0x0), 0x8000).unwrap();
+        assert_ne!(id4, id1);
+
+        // Relocate the MMIO region, conflicting with the remaining regions only.
+        let moved = Arc::new(AddressRegion::new(
+            AddressRegionType::DeviceMemory,
+            GuestAddress(0x8000),
+            0x1000,
+        ));
+        let old = space.replace_region(id2, moved).unwrap();
+        assert_eq!(old.get_base(), GuestAddress(0x10000));
+        assert_eq!(
+            space.get_region(id2).unwrap().get_base(),
+            GuestAddress(0x8000)
+        );
+        let moved = Arc::new(AddressRegion::new(
+            AddressRegionType::DeviceMemory,
+            GuestAddress(0x8800),
+            0x1000,
+        ));
+        assert!(space.replace_region(id2, moved).is_ok());
+        let conflict = Arc::new(AddressRegion::new(
+            AddressRegionType::DeviceMemory,
+            GuestAddress(0x7000),
+            0x1000,
+        ));
+        match space.replace_region(id2, conflict) {
+            Err(Error::ConflictAddressRange(base, _)) => assert_eq!(base, GuestAddress(0x7000)),
+            _ => panic!("replaced region with a conflicting one"),
+        }
+        assert_eq!(
+            space.get_region(id2).unwrap().get_base(),
+            GuestAddress(0x8800)
+        );
+        let region = Arc::new(AddressRegion::new(
+            AddressRegionType::DeviceMemory,
+            GuestAddress(0x40000),
+            0x1000,
+        ));
+        assert!(space.replace_region(id1, region).is_err());
+    }
+
+    #[test]
+    fn find_regions() {
+        let space = AddressSpace::with_capacity(0);
+        // Insert out of order, lookups must not depend on the insertion order.
+        space
+            .add_device_memory(GuestAddress(0xd000_0000), 0x1000)
+            .unwrap();
+        space
+            .add_default_memory(GuestAddress(0x1_0000_0000), 0x4000_0000)
+            .unwrap();
+        space
+            .add_default_memory(GuestAddress(0x0), 0xa0000)
+            .unwrap();
+        space
+            .add_region(
+                AddressRegionType::BiosMemory,
+                GuestAddress(0xa0000),
+                0x60000,
+                None,
+                0,
+            )
+            .unwrap();
+
+        assert!(space.find_region(GuestAddress(0x100000)).is_none());
+        assert!(space.find_region(GuestAddress(0xd000_1000)).is_none());
+        assert!(space.find_region(GuestAddress(0x1_4000_0000)).is_none());
+        let region = space.find_region(GuestAddress(0x0)).unwrap();
+        assert!(region.get_type() == AddressRegionType::DefaultMemory);
+        let region = space.find_region(GuestAddress(0x9ffff)).unwrap();
+        assert_eq!(region.get_base(), GuestAddress(0x0));
+        let region = space.find_region(GuestAddress(0xa0000)).unwrap();
+        assert!(region.get_type() == AddressRegionType::BiosMemory);
+        let region = space.find_region(GuestAddress(0xd000_0fff)).unwrap();
+        assert!(region.get_type() == AddressRegionType::DeviceMemory);
+        let region = space.find_region(GuestAddress(0x1_3fff_ffff)).unwrap();
+        assert_eq!(region.get_base(), GuestAddress(0x1_0000_0000));
+
+        let regions =
+            space.find_regions_in_range(&GuestAddressRange::new(GuestAddress(0x9f000), 0x2000));
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].get_base(), GuestAddress(0x0));
+        assert_eq!(regions[1].get_base(), GuestAddress(0xa0000));
+        let regions = space
+            .find_regions_in_range(&GuestAddressRange::new(GuestAddress(0x100000), 0xd000_0000));
+        assert_eq!(regions.len(), 1);
+        assert_eq!(regions[0].get_base(), GuestAddress(0xd000_0000));
+        let regions =
+            space.find_regions_in_range(&GuestAddressRange::new(GuestAddress(0x0), u64::MAX));
+        assert_eq!(regions.len(), 4);
+        let regions =
+            space.find_regions_in_range(&GuestAddressRange::new(GuestAddress(0x100000), 0x1000));
+        assert!(regions.is_empty());
+        let regions = space.find_regions_in_range(&GuestAddressRange::new(GuestAddress(0x1000), 0));
+        assert!(regions.is_empty());
+
+        // Conflicts with the regions below and above.
+        assert!(space
+            .add_default_memory(GuestAddress(0x9f000), 0x1000)
+            .is_err());
+        assert!(space
+            .add_default_memory(GuestAddress(0xfffff), 0x1000)
+            .is_err());
+        assert!(space
+            .add_default_memory(GuestAddress(0xcfff_f000), 0x2000)
+            .is_err());
+        assert!(space
+            .add_default_memory(GuestAddress(0x100000), 0x1_0000_0000)
+            .is_err());
+        assert!(space
+            .add_default_memory(GuestAddress(0x100000), 0x1000)
+            .is_ok());
+        assert!(space
+            .add_default_memory(GuestAddress(0x101000), 0xcfef_f000)
+            .is_ok());
+        assert_eq!(space.len(), 6);
+    }
+
+    #[test]
+    fn allocate_regions() {
+        let space = AddressSpace::with_capacity(0);
+        let low = GuestAddressRange::new(GuestAddress(0), 0x1_0000_0000);
+        space
+            .add_default_memory(GuestAddress(0), 0x8000_0000)
+            .unwrap();
+        // 32-bit PCI hole and the IOAPIC/LAPIC window.
+        space
+            .reserve_range(GuestAddressRange::new(
+                GuestAddress(0xc000_0000),
+                0x2000_0000,
+            ))
+            .unwrap();
+        space
+            .reserve_range(GuestAddressRange::new(
+                GuestAddress(0xfec0_0000),
+                0x140_0000,
+            ))
+            .unwrap();
+        assert!(space
+            .reserve_range(GuestAddressRange::new(GuestAddress(0x1000), 0))
+            .is_err());
+        assert!(space
+            .reserve_range(GuestAddressRange::new(GuestAddress(u64::MAX), 2))
+            .is_err());
+        assert_eq!(space.get_reserved_ranges().len(), 2);
+
+        let (id, base) = space
+            .allocate(
+                0x1000,
+                0x1000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0x8000_0000));
+        assert_eq!(space.get_region(id).unwrap().get_base(), base);
+        assert!(space.get_region(id).unwrap().get_type() == AddressRegionType::DeviceMemory);
+
+        // Skips the used page and honours the alignment.
+        let (_, base) = space
+            .allocate(
+                0x1000,
+                0x10_0000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0x8010_0000));
+
+        // Allocates from the top of the 4GB range, below the APIC window.
+        let (_, base) = space
+            .allocate(
+                0x10_0000,
+                0x1000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::LastFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0xfeb0_0000));
+        let (_, base) = space
+            .allocate(
+                0x20_0000,
+                0x1000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::LastFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0xfe90_0000));
+
+        // Only the hole between the reserved ranges and the regions is left below 4GB.
+        assert!(space
+            .allocate(
+                0x4000_0000,
+                0x1000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .is_err());
+        let (_, base) = space
+            .allocate(
+                0x3fe0_0000,
+                0x1000,
+                low,
+                AddressRegionType::DefaultMemory,
+                AllocPolicy::LastFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0x8020_0000));
+
+        // Allocations reaching the top of the address space.
+        let top = GuestAddressRange::new(GuestAddress(0xffff_ffff_ffff_0000), 0x1_0000);
+        let (_, base) = space
+            .allocate(
+                0x1000,
+                0x1000,
+                top,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::LastFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0xffff_ffff_ffff_e000));
+        let (_, base) = space
+            .allocate(
+                0xe000,
+                0x1000,
+                top,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .unwrap();
+        assert_eq!(base, GuestAddress(0xffff_ffff_ffff_0000));
+        assert!(space
+            .allocate(
+                0x1000,
+                0x1000,
+                top,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .is_err());
+
+        assert!(space
+            .allocate(
+                0x1000,
+                0x1001,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .is_err());
+        assert!(space
+            .allocate(
+                0,
+                0x1000,
+                low,
+                AddressRegionType::DeviceMemory,
+                AllocPolicy::FirstFit,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn layout_listeners() {
+        let space = AddressSpace::with_capacity(0);
+        let low_id = space.add_default_memory(GuestAddress(0), 0x1000).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        let listener_id = space.add_listener(Arc::new(move |event: &AddressSpaceEvent| {
+            let entry = match event {
+                AddressSpaceEvent::RegionAdded(id, region) => ("added", *id, region.get_base()),
+                AddressSpaceEvent::RegionRemoved(id, region) => ("removed", *id, region.get_base()),
+                AddressSpaceEvent::RegionUpdated { id, old, new } => {
+                    assert_ne!(old.get_base(), new.get_base());
+                    ("updated", *id, new.get_base())
+                }
+            };
+            log.lock().unwrap().push(entry);
+        }));
+
+        let mmio_id = space
+            .add_device_memory(GuestAddress(0xd000_0000), 0x1000)
+            .unwrap();
+        // Failed changes are not reported.
+        assert!(space
+            .add_device_memory(GuestAddress(0xd000_0000), 0x1000)
+            .is_err());
+        space
+            .replace_region(
+                mmio_id,
+                Arc::new(AddressRegion::new(
+                    AddressRegionType::DeviceMemory,
+                    GuestAddress(0xe000_0000),
+                    0x1000,
+                )),
+            )
+            .unwrap();
+        let (alloc_id, base) = space
+            .allocate(
+                0x1000,
+                0x1000,
+                GuestAddressRange::new(GuestAddress(0), 0x10_0000),
+                AddressRegionType::DefaultMemory,
+                AllocPolicy::FirstFit,
+            )
+            .unwrap();
+        space.remove_region(low_id).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("added", low_id, GuestAddress(0)),
+                ("added", mmio_id, GuestAddress(0xd000_0000)),
+                ("updated", mmio_id, GuestAddress(0xe000_0000)),
+                ("added", alloc_id, base),
+                ("removed", low_id, GuestAddress(0)),
+            ]
+        );
+
+        space.remove_listener(listener_id).unwrap();
+        assert!(space.remove_listener(listener_id).is_err());
+        space.remove_region(mmio_id).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn reentrant_and_panicking_listeners() {
+        let space = Arc::new(AddressSpace::with_capacity(0));
+        let weak = Arc::downgrade(&space);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = events.clone();
+        // Adds a device region right above each memory region.
+        space.add_listener(Arc::new(move |event: &AddressSpaceEvent| {
+            if let AddressSpaceEvent::RegionAdded(_, region) = event {
+                log.lock().unwrap().push(region.get_base());
+                if region.get_type() == AddressRegionType::DefaultMemory {
+                    let top = region.get_range().end().unwrap();
+                    let space = weak.upgrade().unwrap();
+                    space.add_device_memory(top, 0x1000).unwrap();
+                }
+            }
+        }));
+        let id = space
+            .add_default_memory(GuestAddress(0x1000), 0x1000)
+            .unwrap();
+        // Listeners don't take region handles.
+        assert_eq!(id, AddressRegionId(0));
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![GuestAddress(0x1000), GuestAddress(0x2000)]
+        );
+        assert!(space.find_region(GuestAddress(0x2000)).is_some());
+
+        let panicking = space.add_listener(Arc::new(|event: &AddressSpaceEvent| {
+            if let AddressSpaceEvent::RegionRemoved(..) = event {
+                panic!("listener failure");
+            }
+        }));
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| space.remove_region(id)));
+        assert!(res.is_err());
+        assert!(space.get_region(id).is_none());
+
+        // The address space is still usable.
+        space.remove_listener(panicking).unwrap();
+        space
+            .add_device_memory(GuestAddress(0x10_0000), 0x1000)
+            .unwrap();
+        assert_eq!(events.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn layout_snapshots() {
+        let space = AddressSpace::new(vec![Arc::new(AddressRegion::new(
+            AddressRegionType::DefaultMemory,
+            GuestAddress(0),
+            0x10_0000,
+        ))]);
+        let snapshot = space.snapshot();
+        assert_eq!(snapshot.generation(), 0);
+        assert_eq!(space.generation(), 0);
+        assert_eq!(snapshot.len(), 1);
+
+        let mmio_id = space
+            .add_device_memory(GuestAddress(0xd000_0000), 0x1000)
+            .unwrap();
+        assert!(space
+            .add_device_memory(GuestAddress(0xd000_0000), 0x1000)
+            .is_err());
+        assert_eq!(space.generation(), 1);
+        // The old snapshot is stale but still consistent.
+        assert!(snapshot.generation() < space.generation());
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot.get_region(mmio_id).is_none());
+        assert!(snapshot.find_region(GuestAddress(0xd000_0000)).is_none());
+
+        let snapshot = space.snapshot();
+        assert_eq!(snapshot.generation(), 1);
+        assert_eq!(snapshot.len(), 2);
+        let region = snapshot.find_region(GuestAddress(0xd000_0800)).unwrap();
+        assert!(region.get_type() == AddressRegionType::DeviceMemory);
+        assert!(Arc::ptr_eq(region, snapshot.get_region(mmio_id).unwrap()));
+        let regions =
+            snapshot.find_regions_in_range(&GuestAddressRange::new(GuestAddress(0), 0xd000_1000));
+        assert_eq!(regions.len(), 2);
+        let ids: Vec<AddressRegionId> = snapshot.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![AddressRegionId(0), mmio_id]);
+
+        space.remove_region(mmio_id).unwrap();
+        assert_eq!(space.generation(), 2);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(space.snapshot().len(), 1);
+        assert_eq!(space.snapshot().generation(), 2);
+    }
+
+    #[test]
+    fn concurrent_snapshots() {
+        let space = Arc::new(AddressSpace::with_capacity(0));
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let space = space.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 200 {
+                        let snapshot = space.snapshot();
+                        assert!(snapshot.generation() >= last);
+                        assert!(snapshot.generation() <= space.generation());
+                        // Regions are added and removed in turn.
+                        assert_eq!(snapshot.len() as u64, snapshot.generation() % 2);
+                        last = snapshot.generation();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..100 {
+            let id = space
+                .add_default_memory(GuestAddress(0x1000), 0x1000)
+                .unwrap();
+            space.remove_region(id).unwrap();
+        }
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn snapshot_reclamation() {
+        let space = Arc::new(AddressSpace::with_capacity(0));
+        let done = Arc::new(AtomicBool::new(false));
+        // Readers which never stop reading until the writer is done.
+        let readers: Vec<_> = (0..20)
+            .map(|_| {
+                let space = space.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        let snapshot = space.snapshot();
+                        assert_eq!(snapshot.len() as u64, snapshot.generation());
+                        assert!(snapshot.generation() <= space.generation());
+                        // Let the writer run on machines with few CPUs.
+                        std::thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+        let mut replaced = Vec::new();
+        for i in 0..1000 {
+            replaced.push(Arc::downgrade(&space.snapshot()));
+            space
+                .add_default_memory(GuestAddress(i * 0x1000), 0x1000)
+                .unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        // The replaced snapshots were all released.
+        assert!(replaced.iter().all(|snapshot| snapshot.upgrade().is_none()));
+        assert_eq!(Arc::strong_count(&space.snapshot()), 2);
+    }
+
+    #[test]
+    fn update_guest_memory() {
+        let space = AddressSpace::with_capacity(0);
+        let low_id = space
+            .add_default_memory(GuestAddress(0), 0x10_0000)
+            .unwrap();
+        let types = [AddressRegionType::DefaultMemory];
+        let mem = space.map_guest_memory(&types).unwrap();
+        mem.write_obj_at_addr(0x5au8, GuestAddress(0x1000)).unwrap();
+        let host_addr = mem.get_host_address(GuestAddress(0)).unwrap();
+
+        // Hot-add a DIMM, the existing region is shared instead of being mapped again.
+        space
+            .add_default_memory(GuestAddress(0x1_0000_0000), 0x10_0000)
+            .unwrap();
+        let mem2 = space.update_guest_memory(&mem, &types).unwrap();
+        assert_eq!(mem.num_regions(), 1);
+        assert_eq!(mem2.num_regions(), 2);
+        assert_eq!(mem2.get_host_address(GuestAddress(0)).unwrap(), host_addr);
+        assert_eq!(
+            mem2.read_obj_from_addr::<u8>(GuestAddress(0x1000)).unwrap(),
+            0x5a
+        );
+        mem2.write_obj_at_addr(0xa5u8, GuestAddress(0x1_0000_1000))
+            .unwrap();
+        assert!(mem
+            .read_obj_from_addr::<u8>(GuestAddress(0x1_0000_1000))
+            .is_err());
+
+        // Removed regions are dropped.
+        space.remove_region(low_id).unwrap();
+        let mem3 = space.update_guest_memory(&mem2, &types).unwrap();
+        assert_eq!(mem3.num_regions(), 1);
+        assert!(!mem3.address_in_range(GuestAddress(0x1000)));
+        assert_eq!(
+            mem3.read_obj_from_addr::<u8>(GuestAddress(0x1_0000_1000))
+                .unwrap(),
+            0xa5
+        );
+
+        // Mappings with different dirty page tracking aren't shared.
+        space.set_dirty_tracking(Some(0x1000));
+        let mem4 = space.update_guest_memory(&mem3, &types).unwrap();
+        mem4.write_obj_at_addr(0u8, GuestAddress(0x1_0000_1000))
+            .unwrap();
+        assert!(mem4.is_dirty(GuestAddress(0x1_0000_1000)));
+        assert!(!mem3.is_dirty(GuestAddress(0x1_0000_1000)));
+        assert_eq!(
+            mem3.read_obj_from_addr::<u8>(GuestAddress(0x1_0000_1000))
+                .unwrap(),
+            0xa5
+        );
+        space.set_dirty_tracking(None);
+
+        // Mappings with different permissions aren't shared.
+        space.set_protection(AddressRegionType::DefaultMemory, Some(Protection::ReadOnly));
+        let mem4 = space.update_guest_memory(&mem3, &types).unwrap();
+        assert!(mem4
+            .write_obj_at_addr(0u8, GuestAddress(0x1_0000_1000))
+            .is_err());
+    }
+
+    #[test]
+    fn huge_page_regions() {
+        const HUGE_PAGE: u64 = 0x20_0000;
+        let space = AddressSpace::with_capacity(0);
+        let region = |base, size, page_size| {
+            Arc::new(
+                AddressRegion::new(AddressRegionType::DefaultMemory, GuestAddress(base), size)
+                    .with_page_size(page_size),
+            )
+        };
+
+        match space.insert_region(region(0, HUGE_PAGE, PageSizePolicy::HugeTlb(0x30_0000))) {
+            Err(Error::InvalidPageSize(0x30_0000)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        for &(base, size) in &[(0x1000, HUGE_PAGE), (0, HUGE_PAGE + 0x1000)] {
+            match space.insert_region(region(base, size, PageSizePolicy::Transparent(HUGE_PAGE))) {
+                Err(Error::UnalignedRegion {
+                    base: b,
+                    size: s,
+                    offset: 0,
+                    page_size: HUGE_PAGE,
+                }) => assert_eq!((b, s), (GuestAddress(base), size)),
+                res => panic!("unexpected result: {:?}", res),
+            }
+        }
+        let f: Arc<dyn AsRawFd + Send + Sync> = Arc::new(tempfile().unwrap());
+        let unaligned_offset = AddressRegion::from_fd(
+            AddressRegionType::DefaultMemory,
+            GuestAddress(0),
+            HUGE_PAGE,
+            f,
+            0x1000,
+        )
+        .with_page_size(PageSizePolicy::HugeTlb(HUGE_PAGE));
+        match space.insert_region(Arc::new(unaligned_offset)) {
+            Err(Error::UnalignedRegion { offset: 0x1000, .. }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(space.is_empty());
+
+        let id = space
+            .insert_region(region(
+                HUGE_PAGE,
+                2 * HUGE_PAGE,
+                PageSizePolicy::Transparent(HUGE_PAGE),
+            ))
+            .unwrap();
+        assert_eq!(
+            space.get_region(id).unwrap().get_page_size(),
+            PageSizePolicy::Transparent(HUGE_PAGE)
+        );
+        let mem = space
+            .map_guest_memory(&[AddressRegionType::DefaultMemory])
+            .unwrap();
+        mem.write_obj_at_addr(0x5au8, GuestAddress(2 * HUGE_PAGE))
+            .unwrap();
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(2 * HUGE_PAGE))
+                .unwrap(),
+            0x5a
+        );
+    }
+
+    #[test]
+    fn memfd_default_memory() {
+        let space = AddressSpace::with_capacity(0);
+        let anon = space.add_default_memory(GuestAddress(0), 0x1000).unwrap();
+        space.set_memfd_backing(Some(PageSizePolicy::Normal));
+        let id = space
+            .add_default_memory(GuestAddress(0x1_0000), 0x2000)
+            .unwrap();
+        assert!(!space.get_region(anon).unwrap().has_fd());
+        let region = space.get_region(id).unwrap();
+        let fd = region.get_fd().unwrap();
+        assert_eq!(region.get_offset(), 0);
+
+        // The memfd can't be resized.
+        assert!(unsafe { libc::ftruncate(fd.as_raw_fd(), 0x1000) } < 0);
+        assert!(unsafe { libc::ftruncate(fd.as_raw_fd(), 0x3000) } < 0);
+
+        // Other processes mapping the memfd share the guest memory.
+        let mem = space
+            .map_guest_memory(&[AddressRegionType::DefaultMemory])
+            .unwrap();
+        mem.write_obj_at_addr(0xa5u8, GuestAddress(0x1_1000))
+            .unwrap();
+        let mapping = MemoryMapping::from_fd(&*fd, 0x2000).unwrap();
+        assert_eq!(mapping.read_obj::<u8>(0x1000).unwrap(), 0xa5);
+
+        // A region removed then added again with another memfd is mapped again.
+        let types = [AddressRegionType::DefaultMemory];
+        space.remove_region(id).unwrap();
+        let id = space
+            .add_default_memory(GuestAddress(0x1_0000), 0x2000)
+            .unwrap();
+        let mem2 = space.update_guest_memory(&mem, &types).unwrap();
+        assert_eq!(
+            mem2.get_host_address(GuestAddress(0)).unwrap(),
+            mem.get_host_address(GuestAddress(0)).unwrap()
+        );
+        assert_eq!(
+            mem2.read_obj_from_addr::<u8>(GuestAddress(0x1_1000))
+                .unwrap(),
+            0
+        );
+        mem2.write_obj_at_addr(0x5au8, GuestAddress(0x1_1000))
+            .unwrap();
+        let fd2 = space.get_region(id).unwrap().get_fd().unwrap();
+        let mapping2 = MemoryMapping::from_fd(&*fd2, 0x2000).unwrap();
+        assert_eq!(mapping2.read_obj::<u8>(0x1000).unwrap(), 0x5a);
+        assert_eq!(mapping.read_obj::<u8>(0x1000).unwrap(), 0xa5);
+
+        // Unchanged regions are shared.
+        let mem3 = space.update_guest_memory(&mem2, &types).unwrap();
+        assert_eq!(
+            mem3.get_host_address(GuestAddress(0x1_1000)).unwrap(),
+            mem2.get_host_address(GuestAddress(0x1_1000)).unwrap()
+        );
+
+        match AddressRegion::new_memfd(
+            AddressRegionType::DefaultMemory,
+            GuestAddress(0x20_0000),
+            0x1000,
+            PageSizePolicy::HugeTlb(0x20_0000),
+        ) {
+            Err(Error::UnalignedRegion { .. }) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // The page size policy given is used for the default memory added afterwards.
+        let space = AddressSpace::with_capacity(0);
+        space.set_memfd_backing(Some(PageSizePolicy::HugeTlb(0x20_0000)));
+        match space.add_default_memory(GuestAddress(0x20_0000), 0x1000) {
+            Err(Error::UnalignedRegion { .. }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        space.set_memfd_backing(None);
+        let id = space
+            .add_default_memory(GuestAddress(0x20_0000), 0x1000)
+            .unwrap();
+        assert!(space.get_region(id).unwrap().get_fd().is_none());
+    }
+
+    #[test]
+    fn contiguous_mapping() {
+        let space = AddressSpace::with_capacity(0);
+        space
+            .add_default_memory(GuestAddress(0x1000), 0x2000)
+            .unwrap();
+        let high = space
+            .add_default_memory(GuestAddress(0x1_0000), 0x1000)
+            .unwrap();
+        space
+            .add_device_memory(GuestAddress(0x8000), 0x1000)
+            .unwrap();
+        let types = [AddressRegionType::DefaultMemory];
+
+        let mem = space.map_guest_memory(&types).unwrap();
+        assert!(mem.host_base().is_none());
+
+        space
+            .set_contiguous_mapping(Some(GuestAddress(0x1_1000)))
+            .unwrap();
+        // The regions mapped before aren't in the reservation, so they're mapped again.
+        let mem = space.map_guest_memory(&types).unwrap();
+        let base = mem.host_base().unwrap();
+        for &addr in &[0x1000, 0x2fff, 0x1_0000] {
+            assert_eq!(mem.get_host_address(GuestAddress(addr)).unwrap(), unsafe {
+                base.add(addr as usize)
+            }
+                as *const u8);
+        }
+        // Holes and regions of other types aren't translated.
+        assert!(mem.get_host_address(GuestAddress(0x3000)).is_err());
+        assert!(mem.get_host_address(GuestAddress(0x8000)).is_err());
+        mem.write_obj_at_addr(0xa5u8, GuestAddress(0x2fff)).unwrap();
+        assert_eq!(unsafe { *base.add(0x2fff) }, 0xa5);
+
+        // A region can't be mapped twice in the reservation.
+        match space.map_guest_memory(&types) {
+            Err(Error::MemoryMappingFailed(MmapError::RangeInUse(0x1000, 0x2000))) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        // Regions may still be removed individually.
+        space.remove_region(high).unwrap();
+        let mem2 = space.update_guest_memory(&mem, &types).unwrap();
+        assert_eq!(mem2.host_base(), Some(base));
+        drop(mem);
+        assert!(!mem2.address_in_range(GuestAddress(0x1_0000)));
+        space
+            .add_default_memory(GuestAddress(0x1_0000), 0x1000)
+            .unwrap();
+        let mem3 = space.update_guest_memory(&mem2, &types).unwrap();
+        assert_eq!(mem3.host_base(), Some(base));
+
+        // Regions beyond the reservation can't be mapped.
+        space
+            .add_default_memory(GuestAddress(0x2_0000), 0x1000)
+            .unwrap();
+        match space.update_guest_memory(&mem3, &types) {
+            Err(Error::MemoryMappingFailed(MmapError::InvalidRange(..))) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
     #[test]
     #[should_panic]
     fn region_as_rawfd() {
@@ -0,0 +1,350 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Track the pages of a memory region written by the VMM, for example for live migration.
+//!
+//! Each page is represented by one bit of an array of atomic 64-bit words, so pages may be marked
+//! dirty concurrently without any lock.
+//!
+//! Several consumers, such as diff snapshots and migration, may collect the dirty pages, each
+//! with a `DirtyLog` of its own. The pages marked dirty are handed over to all the consumers
+//! whenever one of them collects them, so that collecting them doesn't hide them from the
+//! others. The consumers without a log share the default view of the bitmap.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+const BITS_PER_WORD: usize = 64;
+
+/// Consumer of dirty pages with its own view of them, created by `GuestMemory::dirty_log()`.
+///
+/// A log reports the pages written since it was created, and since they were last fetched from
+/// it, regardless of the pages fetched by other consumers.
+#[derive(Debug)]
+pub struct DirtyLog {
+    token: Arc<()>,
+}
+
+impl DirtyLog {
+    // Creates a log which isn't subscribed to any bitmap yet.
+    pub(crate) fn new() -> Self {
+        DirtyLog {
+            token: Arc::new(()),
+        }
+    }
+}
+
+// Dirty pages handed over to the consumers, in words of the same layout as the bitmap.
+#[derive(Debug)]
+struct Consumers {
+    default: Vec<u64>,
+    // Dropped logs are pruned when pages are handed over.
+    logs: Vec<(Weak<()>, Vec<u64>)>,
+}
+
+/// Bitmap of the dirty pages of a memory region.
+#[derive(Debug)]
+pub struct DirtyBitmap {
+    // Pages marked dirty and not handed over to the consumers yet.
+    words: Vec<AtomicU64>,
+    page_shift: u32,
+    num_pages: usize,
+    consumers: Mutex<Consumers>,
+}
+
+impl DirtyBitmap {
+    /// Creates a bitmap, with all pages clean, for a memory region of `size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `page_size` is not a power of two.
+    pub fn new(size: usize, page_size: usize) -> Self {
+        assert!(page_size.is_power_of_two());
+        let num_pages = size.div_ceil(page_size);
+        let num_words = num_pages.div_ceil(BITS_PER_WORD);
+        DirtyBitmap {
+            words: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+            page_shift: page_size.trailing_zeros(),
+            num_pages,
+            consumers: Mutex::new(Consumers {
+                default: vec![0; num_words],
+                logs: Vec::new(),
+            }),
+        }
+    }
+
+    /// Returns the size of the pages tracked by the bitmap.
+    pub fn page_size(&self) -> usize {
+        1 << self.page_shift
+    }
+
+    /// Returns the number of pages tracked by the bitmap.
+    pub fn num_pages(&self) -> usize {
+        self.num_pages
+    }
+
+    /// Marks dirty the pages covering `len` bytes starting at `offset` bytes.
+    ///
+    /// The part of the range past the end of the memory region is ignored.
+    pub fn mark_dirty(&self, offset: usize, len: usize) {
+        for (index, mask) in self.word_masks(offset, len) {
+            self.words[index].fetch_or(mask, Ordering::Release);
+        }
+    }
+
+    /// Checks whether the page containing the byte at `offset` is dirty in the default view.
+    pub fn is_dirty(&self, offset: usize) -> bool {
+        !self.dirty_pages(offset, 1).is_empty()
+    }
+
+    /// Returns the indexes of the dirty pages covering `len` bytes starting at `offset` bytes, in
+    /// the default view.
+    ///
+    /// Only the words of the bitmap covering the range are read.
+    pub fn dirty_pages(&self, offset: usize, len: usize) -> Vec<usize> {
+        let masks = self.word_masks(offset, len);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut consumers = self.consumers.lock().unwrap();
+        self.hand_over(&mut consumers, &masks);
+        let mut pages = Vec::new();
+        for (index, mask) in masks {
+            pages.extend(word_pages(index, consumers.default[index] & mask));
+        }
+        pages
+    }
+
+    /// Returns the indexes of the dirty pages covering `len` bytes starting at `offset` bytes, and
+    /// marks them clean, in the default view.
+    ///
+    /// A page marked dirty concurrently is never lost: it's either returned or left dirty.
+    pub fn fetch_and_clear(&self, offset: usize, len: usize) -> Vec<usize> {
+        let masks = self.word_masks(offset, len);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut consumers = self.consumers.lock().unwrap();
+        self.hand_over(&mut consumers, &masks);
+        take_pages(&mut consumers.default, &masks)
+    }
+
+    /// Marks all the pages clean in the default view.
+    pub fn clear(&self) {
+        let masks = self.word_masks(0, usize::MAX);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut consumers = self.consumers.lock().unwrap();
+        self.hand_over(&mut consumers, &masks);
+        for word in consumers.default.iter_mut() {
+            *word = 0;
+        }
+    }
+
+    /// Subscribes `log` to the bitmap, with all pages clean.
+    ///
+    /// Does nothing if the log is already subscribed.
+    pub fn subscribe(&self, log: &DirtyLog) {
+        let masks = self.word_masks(0, usize::MAX);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut consumers = self.consumers.lock().unwrap();
+        // The pages marked dirty so far don't concern the new log.
+        self.hand_over(&mut consumers, &masks);
+        if find_log(&consumers.logs, log).is_none() {
+            let words = vec![0; self.words.len()];
+            consumers.logs.push((Arc::downgrade(&log.token), words));
+        }
+    }
+
+    /// Returns the indexes of the pages covering `len` bytes starting at `offset` bytes which are
+    /// dirty in `log`, and marks them clean in `log` only.
+    ///
+    /// If `log` isn't subscribed to the bitmap yet, it gets subscribed with all pages dirty,
+    /// since the pages written before are unknown.
+    pub fn fetch_and_clear_log(&self, log: &DirtyLog, offset: usize, len: usize) -> Vec<usize> {
+        let masks = self.word_masks(offset, len);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut consumers = self.consumers.lock().unwrap();
+        self.hand_over(&mut consumers, &masks);
+        let index = match find_log(&consumers.logs, log) {
+            Some(index) => index,
+            None => {
+                let all = self
+                    .word_masks(0, usize::MAX)
+                    .into_iter()
+                    .map(|(_, mask)| mask);
+                consumers
+                    .logs
+                    .push((Arc::downgrade(&log.token), all.collect()));
+                consumers.logs.len() - 1
+            }
+        };
+        take_pages(&mut consumers.logs[index].1, &masks)
+    }
+
+    // Hands the pages marked dirty in the words of `masks` over to all the consumers.
+    //
+    // Pages marked dirty concurrently are either handed over or left for the next time.
+    fn hand_over(&self, consumers: &mut Consumers, masks: &[(usize, u64)]) {
+        consumers.logs.retain(|(token, _)| token.strong_count() > 0);
+        for &(index, _) in masks {
+            let bits = self.words[index].swap(0, Ordering::AcqRel);
+            if bits == 0 {
+                continue;
+            }
+            consumers.default[index] |= bits;
+            for (_, words) in consumers.logs.iter_mut() {
+                words[index] |= bits;
+            }
+        }
+    }
+
+    // Returns the index of the words and the masks of the bits for the pages covering `len` bytes
+    // starting at `offset` bytes.
+    fn word_masks(&self, offset: usize, len: usize) -> Vec<(usize, u64)> {
+        let first = offset >> self.page_shift;
+        if len == 0 || first >= self.num_pages {
+            return Vec::new();
+        }
+        let last = (offset.saturating_add(len - 1) >> self.page_shift).min(self.num_pages - 1);
+        let mut masks = Vec::new();
+        let mut page = first;
+        while page <= last {
+            let index = page / BITS_PER_WORD;
+            let low = page % BITS_PER_WORD;
+            let high = (last - index * BITS_PER_WORD).min(BITS_PER_WORD - 1);
+            // Bits `low` to `high` included.
+            let mask = (u64::MAX >> (BITS_PER_WORD - 1 - high)) & (u64::MAX << low);
+            masks.push((index, mask));
+            page = (index + 1) * BITS_PER_WORD;
+        }
+        masks
+    }
+}
+
+// Returns the index of the entry of `log` among `logs`.
+fn find_log(logs: &[(Weak<()>, Vec<u64>)], log: &DirtyLog) -> Option<usize> {
+    logs.iter()
+        .position(|(token, _)| token.as_ptr() == Arc::as_ptr(&log.token))
+}
+
+// Returns the indexes of the pages of `masks` set in `words`, and clears them.
+fn take_pages(words: &mut [u64], masks: &[(usize, u64)]) -> Vec<usize> {
+    let mut pages = Vec::new();
+    for &(index, mask) in masks {
+        pages.extend(word_pages(index, words[index] & mask));
+        words[index] &= !mask;
+    }
+    pages
+}
+
+// Returns the indexes of the pages whose bits are set in the word at `index`.
+fn word_pages(index: usize, bits: u64) -> impl Iterator<Item = usize> {
+    (0..BITS_PER_WORD)
+        .filter(move |bit| bits & (1 << bit) != 0)
+        .map(move |bit| index * BITS_PER_WORD + bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mark_and_query() {
+        let bitmap = DirtyBitmap::new(0x10_0000 + 1, 0x1000);
+        assert_eq!(bitmap.page_size(), 0x1000);
+        assert_eq!(bitmap.num_pages(), 0x101);
+        assert!(bitmap.dirty_pages(0, usize::MAX).is_empty());
+
+        bitmap.mark_dirty(0xfff, 2);
+        bitmap.mark_dirty(0x3f000, 0x2000);
+        bitmap.mark_dirty(0x10_0000, 0x1000);
+        // Ignored.
+        bitmap.mark_dirty(0x5000, 0);
+        bitmap.mark_dirty(0x20_0000, 0x1000);
+        bitmap.mark_dirty(usize::MAX, 0x1000);
+
+        assert!(bitmap.is_dirty(0x0));
+        assert!(bitmap.is_dirty(0x1fff));
+        assert!(!bitmap.is_dirty(0x2000));
+        assert!(!bitmap.is_dirty(0x5000));
+        assert!(!bitmap.is_dirty(0x20_0000));
+        assert_eq!(
+            bitmap.dirty_pages(0, usize::MAX),
+            vec![0, 1, 0x3f, 0x40, 0x100]
+        );
+        assert_eq!(bitmap.dirty_pages(0x1000, 0x3f000), vec![1, 0x3f]);
+        assert_eq!(bitmap.dirty_pages(0x40fff, 1), vec![0x40]);
+        assert!(bitmap.dirty_pages(0x41000, 0xbf000).is_empty());
+    }
+
+    #[test]
+    fn fetch_and_clear() {
+        let bitmap = DirtyBitmap::new(0x100 * 0x1000, 0x1000);
+        bitmap.mark_dirty(0, 0x100 * 0x1000);
+        assert_eq!(bitmap.dirty_pages(0, usize::MAX).len(), 0x100);
+
+        // Clears a range spanning several words only.
+        let pages = bitmap.fetch_and_clear(0x3e * 0x1000 + 1, 0x44 * 0x1000);
+        assert_eq!(pages, (0x3e..0x83).collect::<Vec<usize>>());
+        assert!(bitmap.is_dirty(0x3d * 0x1000));
+        assert!(!bitmap.is_dirty(0x3e * 0x1000));
+        assert!(!bitmap.is_dirty(0x82 * 0x1000));
+        assert!(bitmap.is_dirty(0x83 * 0x1000));
+        assert!(bitmap.fetch_and_clear(0x40 * 0x1000, 0x1000).is_empty());
+
+        let pages = bitmap.fetch_and_clear(0, usize::MAX);
+        assert_eq!(pages.len(), 0x100 - (0x83 - 0x3e));
+        assert!(bitmap.dirty_pages(0, usize::MAX).is_empty());
+
+        bitmap.mark_dirty(0x1000, 1);
+        bitmap.clear();
+        assert!(!bitmap.is_dirty(0x1000));
+    }
+
+    #[test]
+    fn logs() {
+        let bitmap = DirtyBitmap::new(0x100 * 0x1000, 0x1000);
+        bitmap.mark_dirty(0, 0x1000);
+        let first = DirtyLog::new();
+        let second = DirtyLog::new();
+        bitmap.subscribe(&first);
+        bitmap.subscribe(&second);
+        // Pages marked dirty before subscribing are left to the default view.
+        assert!(bitmap.fetch_and_clear_log(&first, 0, usize::MAX).is_empty());
+        assert_eq!(bitmap.dirty_pages(0, usize::MAX), vec![0]);
+
+        bitmap.mark_dirty(0x1000, 0x1000);
+        bitmap.mark_dirty(0x80 * 0x1000, 0x1000);
+        // Each consumer fetches the dirty pages regardless of the others.
+        bitmap.clear();
+        assert_eq!(
+            bitmap.fetch_and_clear_log(&first, 0, 0x80 * 0x1000),
+            vec![1]
+        );
+        assert_eq!(
+            bitmap.fetch_and_clear_log(&first, 0, usize::MAX),
+            vec![0x80]
+        );
+        assert!(bitmap.fetch_and_clear_log(&first, 0, usize::MAX).is_empty());
+        assert_eq!(
+            bitmap.fetch_and_clear_log(&second, 0, usize::MAX),
+            vec![1, 0x80]
+        );
+        assert!(bitmap.dirty_pages(0, usize::MAX).is_empty());
+
+        // Subscribing twice keeps the pages dirty in the log.
+        bitmap.mark_dirty(0x2000, 1);
+        bitmap.subscribe(&first);
+        assert_eq!(bitmap.fetch_and_clear_log(&first, 0, usize::MAX), vec![2]);
+
+        // A log which isn't subscribed gets all the pages dirty.
+        let third = DirtyLog::new();
+        assert_eq!(
+            bitmap.fetch_and_clear_log(&third, 0, usize::MAX).len(),
+            0x100
+        );
+        assert!(bitmap.fetch_and_clear_log(&third, 0, usize::MAX).is_empty());
+
+        // Dropped logs are pruned.
+        drop(second);
+        drop(third);
+        assert!(bitmap.dirty_pages(0, 1).is_empty());
+        assert_eq!(bitmap.consumers.lock().unwrap().logs.len(), 1);
+    }
+}
@@ -7,88 +7,219 @@
 
 //! Represents an address in the guest's memory space.
 
-use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
-use std::ops::{BitAnd, BitOr};
+use address::Address;
+
+/// Represents a size or an offset within the guest's physical address space.
+///
+/// The guest physical address space is independent of the word size of the VMM process. For
+/// example a 32-bit userspace VMM may run on a 64-bit host kernel and manage a guest with more
+/// than 4GiB of memory, so a fixed 64-bit type is used instead of `usize`.
+pub type GuestUsize = u64;
 
 /// Represents an Address in the guest's memory.
-#[derive(Clone, Copy, Debug)]
-pub struct GuestAddress(pub usize);
+///
+/// The address arithmetic is provided by the `Address` trait.
+///
+/// # Examples
+///
+/// ```
+/// # use memory_model::{Address, GuestAddress};
+///   let base = GuestAddress(0x100);
+///   let addr = GuestAddress(0x150);
+///   assert_eq!(addr.offset_from(base), 0x50u64);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GuestAddress(pub GuestUsize);
+impl_address!(GuestAddress);
 
 impl GuestAddress {
-    /// Returns the offset from this address to the given base address.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// # use memory_model::GuestAddress;
-    ///   let base = GuestAddress(0x100);
-    ///   let addr = GuestAddress(0x150);
-    ///   assert_eq!(addr.offset_from(base), 0x50usize);
-    /// ```
-    pub fn offset_from(&self, base: GuestAddress) -> usize {
-        self.0 - base.0
-    }
-
-    /// Returns the address as a usize offset from 0x0.
+    /// Returns the address as a `GuestUsize` offset from 0x0.
     /// Use this when a raw number is needed to pass to the kernel.
-    pub fn offset(&self) -> usize {
+    pub fn offset(&self) -> GuestUsize {
         self.0
     }
+}
+
+/// Represents a range of addresses in the guest's memory.
+///
+/// The range starts at `start` and covers `len` bytes. A range may extend past the end of the
+/// guest address space, in which case `end()` returns None and the range can't be used to
+/// describe valid guest memory, but all the set operations still give exact results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct GuestAddressRange {
+    start: GuestAddress,
+    len: GuestUsize,
+}
 
-    /// Returns the result of the add or None if there is overflow.
-    pub fn checked_add(&self, other: usize) -> Option<GuestAddress> {
-        self.0.checked_add(other).map(GuestAddress)
+impl GuestAddressRange {
+    /// Creates a range of `len` bytes starting at `start`.
+    pub fn new(start: GuestAddress, len: GuestUsize) -> Self {
+        GuestAddressRange { start, len }
     }
 
-    /// Returns the result of the base address + the size.
-    /// Only use this when `offset` is guaranteed not to overflow.
-    pub fn unchecked_add(&self, offset: usize) -> GuestAddress {
-        GuestAddress(self.0 + offset)
+    /// Creates a range covering `[start, end)`, or returns None if `end` is below `start`.
+    pub fn from_bounds(start: GuestAddress, end: GuestAddress) -> Option<Self> {
+        end.checked_offset_from(start)
+            .map(|len| GuestAddressRange::new(start, len))
     }
 
-    /// Returns the result of the subtraction of None if there is underflow.
-    pub fn checked_sub(&self, other: usize) -> Option<GuestAddress> {
-        self.0.checked_sub(other).map(GuestAddress)
+    /// Returns the first address of the range.
+    pub fn start(&self) -> GuestAddress {
+        self.start
     }
 
-    /// Returns the bitwise and of the address with the given mask.
-    pub fn mask(&self, mask: u64) -> GuestAddress {
-        GuestAddress(self.0 & mask as usize)
+    /// Returns the number of bytes in the range.
+    pub fn len(&self) -> GuestUsize {
+        self.len
     }
-}
 
-impl BitAnd<u64> for GuestAddress {
-    type Output = GuestAddress;
+    /// Checks whether the range is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the address right after the end of the range, or None if the range reaches the
+    /// end of the guest address space.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestAddressRange};
+    ///   let range = GuestAddressRange::new(GuestAddress(0x1000), 0x1000);
+    ///   assert_eq!(range.end(), Some(GuestAddress(0x2000)));
+    ///   let range = GuestAddressRange::new(GuestAddress(0xffff_ffff_ffff_f000), 0x1000);
+    ///   assert_eq!(range.end(), None);
+    /// ```
+    pub fn end(&self) -> Option<GuestAddress> {
+        self.start.checked_add(self.len)
+    }
 
-    fn bitand(self, other: u64) -> GuestAddress {
-        GuestAddress(self.0 & other as usize)
+    // The exclusive end of the range, which can't overflow.
+    fn end_value(&self) -> u128 {
+        u128::from(self.start.0) + u128::from(self.len)
     }
-}
 
-impl BitOr<u64> for GuestAddress {
-    type Output = GuestAddress;
+    /// Checks whether `addr` is within the range.
+    pub fn contains(&self, addr: GuestAddress) -> bool {
+        addr >= self.start && u128::from(addr.0) < self.end_value()
+    }
 
-    fn bitor(self, other: u64) -> GuestAddress {
-        GuestAddress(self.0 | other as usize)
+    /// Checks whether `other` is fully covered by the range.
+    ///
+    /// An empty range is covered by any range containing its start address.
+    pub fn contains_range(&self, other: &GuestAddressRange) -> bool {
+        other.start >= self.start
+            && u128::from(other.start.0) <= self.end_value()
+            && other.end_value() <= self.end_value()
+            && (!other.is_empty() || self.contains(other.start))
     }
-}
 
-impl PartialEq for GuestAddress {
-    fn eq(&self, other: &GuestAddress) -> bool {
-        self.0 == other.0
+    /// Checks whether the range has at least one address in common with `other`.
+    pub fn intersects(&self, other: &GuestAddressRange) -> bool {
+        self.intersection(other).is_some()
     }
-}
-impl Eq for GuestAddress {}
 
-impl Ord for GuestAddress {
-    fn cmp(&self, other: &GuestAddress) -> Ordering {
-        self.0.cmp(&other.0)
+    /// Returns the non-empty range of addresses shared with `other`, if any.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestAddressRange};
+    ///   let a = GuestAddressRange::new(GuestAddress(0x1000), 0x2000);
+    ///   let b = GuestAddressRange::new(GuestAddress(0x2000), 0x2000);
+    ///   assert_eq!(
+    ///       a.intersection(&b),
+    ///       Some(GuestAddressRange::new(GuestAddress(0x2000), 0x1000))
+    ///   );
+    /// ```
+    pub fn intersection(&self, other: &GuestAddressRange) -> Option<GuestAddressRange> {
+        let start = self.start.max(other.start);
+        let end = self.end_value().min(other.end_value());
+        if u128::from(start.0) >= end {
+            return None;
+        }
+        Some(GuestAddressRange::new(
+            start,
+            (end - u128::from(start.0)) as GuestUsize,
+        ))
     }
-}
 
-impl PartialOrd for GuestAddress {
-    fn partial_cmp(&self, other: &GuestAddress) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// Returns the range covering both `self` and `other` if they overlap or are adjacent, so
+    /// that the union is a single range.
+    pub fn union_if_adjacent(&self, other: &GuestAddressRange) -> Option<GuestAddressRange> {
+        let (low, high) = if self.start <= other.start {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        if u128::from(high.start.0) > low.end_value() {
+            return None;
+        }
+        let end = low.end_value().max(high.end_value());
+        let len = end - u128::from(low.start.0);
+        if len > u128::from(GuestUsize::MAX) {
+            return None;
+        }
+        Some(GuestAddressRange::new(low.start, len as GuestUsize))
+    }
+
+    /// Splits the range into `[start, addr)` and `[addr, end)`.
+    ///
+    /// Returns None unless `addr` is strictly inside the range, so that both parts are
+    /// non-empty.
+    pub fn split_at(&self, addr: GuestAddress) -> Option<(GuestAddressRange, GuestAddressRange)> {
+        if addr <= self.start || !self.contains(addr) {
+            return None;
+        }
+        let low_len = addr.offset_from(self.start);
+        Some((
+            GuestAddressRange::new(self.start, low_len),
+            GuestAddressRange::new(addr, self.len - low_len),
+        ))
+    }
+
+    /// Removes the addresses of `other` from the range.
+    ///
+    /// Returns the part of the range below the start of `other`, and the part at or above its
+    /// end, each None if empty. A range disjoint from `other` is thus returned as the part below
+    /// or above it. Both parts are clipped to the end of the guest address space, whether or not
+    /// the ranges intersect. An empty `other` within the range splits it at its start.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestAddressRange};
+    ///   let range = GuestAddressRange::new(GuestAddress(0x0), 0x3000);
+    ///   let hole = GuestAddressRange::new(GuestAddress(0x1000), 0x1000);
+    ///   assert_eq!(
+    ///       range.subtract(&hole),
+    ///       (
+    ///           Some(GuestAddressRange::new(GuestAddress(0x0), 0x1000)),
+    ///           Some(GuestAddressRange::new(GuestAddress(0x2000), 0x1000))
+    ///       )
+    ///   );
+    /// ```
+    pub fn subtract(
+        &self,
+        other: &GuestAddressRange,
+    ) -> (Option<GuestAddressRange>, Option<GuestAddressRange>) {
+        let start = u128::from(self.start.0);
+        // Addresses past the end of the guest address space don't exist, so they are dropped.
+        let end = self.end_value().min(u128::from(GuestUsize::MAX) + 1);
+        let part = |start: u128, end: u128| {
+            if start < end {
+                Some(GuestAddressRange::new(
+                    GuestAddress(start as GuestUsize),
+                    (end - start) as GuestUsize,
+                ))
+            } else {
+                None
+            }
+        };
+        (
+            part(start, end.min(u128::from(other.start.0))),
+            part(start.max(other.end_value()), end),
+        )
     }
 }
 
@@ -114,7 +245,7 @@ mod tests {
         let b = GuestAddress(0x301);
         assert!(a < b);
         assert!(b > a);
-        assert!(!(a < a));
+        assert!(a >= a);
     }
 
     #[test]
@@ -146,4 +277,167 @@ mod tests {
         assert_eq!(Some(GuestAddress(0x0f)), a.checked_sub(0xf0));
         assert!(a.checked_sub(0xffff).is_none());
     }
+
+    fn range(start: GuestUsize, len: GuestUsize) -> GuestAddressRange {
+        GuestAddressRange::new(GuestAddress(start), len)
+    }
+
+    #[test]
+    fn range_end() {
+        assert_eq!(range(0x1000, 0x1000).end(), Some(GuestAddress(0x2000)));
+        assert_eq!(range(0x1000, 0).end(), Some(GuestAddress(0x1000)));
+        assert_eq!(
+            range(0xffff_ffff_ffff_f000, 0xfff).end(),
+            Some(GuestAddress(u64::MAX))
+        );
+        assert_eq!(range(0xffff_ffff_ffff_f000, 0x1000).end(), None);
+        assert_eq!(range(0xffff_ffff_ffff_f000, 0x2000).end(), None);
+        assert!(range(0, 0).is_empty());
+        assert_eq!(
+            GuestAddressRange::from_bounds(GuestAddress(0x1000), GuestAddress(0x3000)),
+            Some(range(0x1000, 0x2000))
+        );
+        assert_eq!(
+            GuestAddressRange::from_bounds(GuestAddress(0x3000), GuestAddress(0x1000)),
+            None
+        );
+    }
+
+    #[test]
+    fn range_contains() {
+        let r = range(0x1000, 0x1000);
+        assert!(!r.contains(GuestAddress(0xfff)));
+        assert!(r.contains(GuestAddress(0x1000)));
+        assert!(r.contains(GuestAddress(0x1fff)));
+        assert!(!r.contains(GuestAddress(0x2000)));
+        assert!(!range(0x1000, 0).contains(GuestAddress(0x1000)));
+        assert!(range(0xffff_ffff_ffff_f000, 0x2000).contains(GuestAddress(u64::MAX)));
+
+        assert!(r.contains_range(&r));
+        assert!(r.contains_range(&range(0x1800, 0x800)));
+        assert!(r.contains_range(&range(0x1800, 0)));
+        assert!(!r.contains_range(&range(0x2000, 0)));
+        assert!(!r.contains_range(&range(0x1800, 0x801)));
+        assert!(!r.contains_range(&range(0xfff, 0x10)));
+    }
+
+    #[test]
+    fn range_intersection() {
+        let r = range(0x1000, 0x1000);
+        assert_eq!(r.intersection(&r), Some(r));
+        assert_eq!(r.intersection(&range(0x2000, 0x1000)), None);
+        assert_eq!(r.intersection(&range(0x0, 0x1000)), None);
+        assert_eq!(
+            r.intersection(&range(0x1800, 0x1000)),
+            Some(range(0x1800, 0x800))
+        );
+        assert_eq!(
+            r.intersection(&range(0x800, 0x1000)),
+            Some(range(0x1000, 0x800))
+        );
+        assert_eq!(
+            r.intersection(&range(0x1100, 0x100)),
+            Some(range(0x1100, 0x100))
+        );
+        assert_eq!(r.intersection(&range(0x1100, 0)), None);
+        assert!(r.intersects(&range(0x0, 0x1001)));
+        assert!(!r.intersects(&range(0xffff_ffff_ffff_f000, 0x2000)));
+    }
+
+    #[test]
+    fn range_union() {
+        let r = range(0x1000, 0x1000);
+        assert_eq!(
+            r.union_if_adjacent(&range(0x2000, 0x1000)),
+            Some(range(0x1000, 0x2000))
+        );
+        assert_eq!(
+            range(0x2000, 0x1000).union_if_adjacent(&r),
+            Some(range(0x1000, 0x2000))
+        );
+        assert_eq!(
+            r.union_if_adjacent(&range(0x1800, 0x1000)),
+            Some(range(0x1000, 0x1800))
+        );
+        assert_eq!(r.union_if_adjacent(&range(0x1100, 0x100)), Some(r));
+        assert_eq!(r.union_if_adjacent(&range(0x2001, 0x1000)), None);
+        assert_eq!(r.union_if_adjacent(&range(0x0, 0xfff)), None);
+        assert_eq!(
+            range(0x0, 0x8000_0000_0000_0000)
+                .union_if_adjacent(&range(0x8000_0000_0000_0000, 0x8000_0000_0000_0000)),
+            None
+        );
+    }
+
+    #[test]
+    fn range_split() {
+        let r = range(0x1000, 0x1000);
+        assert_eq!(
+            r.split_at(GuestAddress(0x1800)),
+            Some((range(0x1000, 0x800), range(0x1800, 0x800)))
+        );
+        assert_eq!(r.split_at(GuestAddress(0x1000)), None);
+        assert_eq!(r.split_at(GuestAddress(0x2000)), None);
+        assert_eq!(r.split_at(GuestAddress(0x800)), None);
+    }
+
+    #[test]
+    fn range_subtract() {
+        let r = range(0x1000, 0x3000);
+        assert_eq!(
+            r.subtract(&range(0x2000, 0x1000)),
+            (Some(range(0x1000, 0x1000)), Some(range(0x3000, 0x1000)))
+        );
+        assert_eq!(
+            r.subtract(&range(0x0, 0x2000)),
+            (None, Some(range(0x2000, 0x2000)))
+        );
+        assert_eq!(
+            r.subtract(&range(0x3000, 0x2000)),
+            (Some(range(0x1000, 0x2000)), None)
+        );
+        // Containing ranges.
+        assert_eq!(r.subtract(&range(0x0, 0x5000)), (None, None));
+        assert_eq!(r.subtract(&r), (None, None));
+        assert_eq!(
+            r.subtract(&range(0x1000, 0x1000)),
+            (None, Some(range(0x2000, 0x2000)))
+        );
+        assert_eq!(
+            r.subtract(&range(0x3000, 0x1000)),
+            (Some(range(0x1000, 0x2000)), None)
+        );
+        // Disjoint ranges.
+        assert_eq!(r.subtract(&range(0x5000, 0x1000)), (Some(r), None));
+        assert_eq!(r.subtract(&range(0x0, 0x800)), (None, Some(r)));
+        // Adjacent ranges.
+        assert_eq!(r.subtract(&range(0x4000, 0x1000)), (Some(r), None));
+        assert_eq!(r.subtract(&range(0x0, 0x1000)), (None, Some(r)));
+        // Empty ranges.
+        assert_eq!(
+            range(0x1000, 0).subtract(&range(0x5000, 0x1000)),
+            (None, None)
+        );
+        assert_eq!(
+            r.subtract(&range(0x2000, 0)),
+            (Some(range(0x1000, 0x1000)), Some(range(0x2000, 0x2000)))
+        );
+        // Ranges reaching past the end of the guest address space are clipped.
+        assert_eq!(
+            range(0xffff_ffff_ffff_f000, 0x1000).subtract(&range(0xffff_ffff_ffff_f000, 0x800)),
+            (None, Some(range(0xffff_ffff_ffff_f800, 0x800)))
+        );
+        assert_eq!(
+            range(0xffff_ffff_ffff_f000, 0x2000).subtract(&range(0xffff_ffff_ffff_f000, 0x1000)),
+            (None, None)
+        );
+        assert_eq!(
+            range(0xffff_ffff_ffff_f000, 0x2000).subtract(&range(0x0, 0x1000)),
+            (None, Some(range(0xffff_ffff_ffff_f000, 0x1000)))
+        );
+        assert_eq!(
+            range(0xffff_ffff_ffff_f000, 0x2000).subtract(&range(0xffff_ffff_ffff_f800, 0x800)),
+            (Some(range(0xffff_ffff_ffff_f000, 0x800)), None)
+        );
+    }
 }
@@ -11,11 +11,17 @@
 
 use std::fmt::{self, Display};
 use std::io::{Read, Write};
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
 use std::{mem, result};
 
-use guest_address::GuestAddress;
-use mmap::{self, MemoryMapping};
+use libc;
+
+use address::Address;
+use dirty_bitmap::{DirtyBitmap, DirtyLog};
+use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
+use mmap::{self, MemoryMapping, MemoryReservation, Protection};
 use volatile_memory::*;
 use DataInit;
 
@@ -25,7 +31,7 @@ pub enum Error {
     /// Failure in finding a guest address in any memory regions mapped by this guest.
     InvalidGuestAddress(GuestAddress),
     /// Failure in finding a guest address range in any memory regions mapped by this guest.
-    InvalidGuestAddressRange(GuestAddress, usize),
+    InvalidGuestAddressRange(GuestAddress, GuestUsize),
     /// Failure in accessing the memory located at some address.
     MemoryAccess(GuestAddress, mmap::Error),
     /// Failure in creating an anonymous shared mapping.
@@ -33,9 +39,13 @@ pub enum Error {
     /// Failure in initializing guest memory.
     MemoryNotInitialized,
     /// Two of the memory regions are overlapping.
-    MemoryRegionOverlap,
+    MemoryRegionOverlap(GuestAddressRange, GuestAddressRange),
+    /// A memory region is empty.
+    EmptyMemoryRegion(GuestAddress),
     /// No memory regions were provided for initializing the guest memory.
     NoMemoryRegions,
+    /// Writing to memory which is mapped read-only.
+    ReadOnlyMemory(GuestAddress),
     /// Incomplete write
     ShortWrite { expected: usize, completed: usize },
     /// Incomplete read
@@ -43,6 +53,37 @@ pub enum Error {
 }
 type Result<T> = result::Result<T, Error>;
 
+// Check that the ranges of memory regions, sorted by start address, are valid and don't overlap.
+fn check_ranges<I>(ranges: I) -> Result<()>
+where
+    I: Iterator<Item = GuestAddressRange>,
+{
+    let mut last: Option<GuestAddressRange> = None;
+    for range in ranges {
+        if range.is_empty() {
+            return Err(Error::EmptyMemoryRegion(range.start()));
+        }
+        if range.end().is_none() {
+            return Err(Error::InvalidGuestAddressRange(range.start(), range.len()));
+        }
+        if let Some(last) = last {
+            if last.intersects(&range) {
+                return Err(Error::MemoryRegionOverlap(last, range));
+            }
+        }
+        last = Some(range);
+    }
+    Ok(())
+}
+
+// Converts the failure of a write access to the memory located at `addr`.
+fn write_error(addr: GuestAddress, e: mmap::Error) -> Error {
+    match e {
+        mmap::Error::WriteProtected => Error::ReadOnlyMemory(addr),
+        e => Error::MemoryAccess(addr, e),
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl Display for Error {
@@ -52,9 +93,22 @@ impl Display for Error {
             Error::InvalidGuestAddress(_) => write!(f, "Invalid Guest Address"),
             Error::MemoryAccess(_, _) => write!(f, "Invalid Guest Memory Access"),
             Error::MemoryMappingFailed(_) => write!(f, "Failed to map guest memory"),
-            Error::MemoryRegionOverlap => write!(f, "Memory regions overlap"),
+            Error::MemoryRegionOverlap(first, second) => write!(
+                f,
+                "Memory regions overlap, base {:#x}/size {:#x} and base {:#x}/size {:#x}",
+                first.start().offset(),
+                first.len(),
+                second.start().offset(),
+                second.len(),
+            ),
+            Error::EmptyMemoryRegion(base) => {
+                write!(f, "Empty memory region at {:#x}", base.offset())
+            }
             Error::MemoryNotInitialized => write!(f, "Memory not initialized yet"),
             Error::NoMemoryRegions => write!(f, "No region for address found"),
+            Error::ReadOnlyMemory(addr) => {
+                write!(f, "write to read-only memory at {:#x}", addr.offset())
+            }
             Error::InvalidGuestAddressRange(base, size) => write!(
                 f,
                 "invalid address range, base {}/size {}",
@@ -86,6 +140,7 @@ impl Display for Error {
 pub struct MemoryRegion {
     mapping: MemoryMapping,
     guest_base: GuestAddress,
+    dirty: Option<DirtyBitmap>,
 }
 
 impl MemoryRegion {
@@ -93,62 +148,214 @@ impl MemoryRegion {
         MemoryRegion {
             mapping,
             guest_base,
+            dirty: None,
         }
     }
 
-    pub fn size(&self) -> usize {
-        self.mapping.size()
+    /// Creates a memory region tracking the pages written through `GuestMemory`.
+    pub fn with_dirty_tracking(
+        mapping: MemoryMapping,
+        guest_base: GuestAddress,
+        page_size: usize,
+    ) -> Self {
+        let dirty = DirtyBitmap::new(mapping.size(), page_size);
+        MemoryRegion {
+            mapping,
+            guest_base,
+            dirty: Some(dirty),
+        }
+    }
+
+    /// Returns the dirty page bitmap of the region, if dirty pages are tracked.
+    pub fn dirty_bitmap(&self) -> Option<&DirtyBitmap> {
+        self.dirty.as_ref()
+    }
+
+    fn mark_dirty(&self, offset: usize, len: usize) {
+        if let Some(ref dirty) = self.dirty {
+            dirty.mark_dirty(offset, len);
+        }
+    }
+
+    pub fn guest_base(&self) -> GuestAddress {
+        self.guest_base
+    }
+
+    pub fn size(&self) -> GuestUsize {
+        self.mapping.size() as GuestUsize
     }
-}
 
-fn region_end(region: &MemoryRegion) -> GuestAddress {
-    // unchecked_add is safe as the region bounds were checked when it was created.
-    region.guest_base.unchecked_add(region.mapping.size())
+    pub fn range(&self) -> GuestAddressRange {
+        GuestAddressRange::new(self.guest_base, self.size())
+    }
+
+    pub fn protection(&self) -> Protection {
+        self.mapping.protection()
+    }
+
+    /// Returns the memory mapping of the region in the current process.
+    ///
+    /// Writes through the mapping aren't tracked in the dirty bitmap.
+    pub fn mapping(&self) -> &MemoryMapping {
+        &self.mapping
+    }
 }
 
 /// Tracks all memory regions allocated/mapped for the guest in the current process.
+///
+/// Memory regions are kept sorted by guest address, so looking up the region of an address takes
+/// logarithmic time.
+///
+/// Writes to regions mapped read-only fail with `Error::ReadOnlyMemory`, and volatile slices of
+/// such regions can't be taken.
+///
+/// Memory regions may track the pages written by the VMM in a dirty bitmap. Volatile slices are
+/// marked dirty when they are obtained, so they must not be kept across a collection of the dirty
+/// pages.
 #[derive(Clone)]
 pub struct GuestMemory {
-    regions: Arc<Vec<MemoryRegion>>,
+    regions: Arc<Vec<Arc<MemoryRegion>>>,
+    // Reservation all the regions are mapped in at their guest address, if any.
+    reservation: Option<Arc<MemoryReservation>>,
 }
 
 impl GuestMemory {
     /// Creates a container for guest memory regions.
-    /// Valid memory regions are specified as a Vec of (Address, Size) tuples sorted by Address.
-    pub fn new(ranges: &[(GuestAddress, usize)]) -> Result<GuestMemory> {
+    /// Valid memory regions are specified as a Vec of (Address, Size) tuples, which must not be
+    /// empty nor overlap.
+    pub fn new(ranges: &[(GuestAddress, GuestUsize)]) -> Result<GuestMemory> {
+        GuestMemory::create(ranges, None)
+    }
+
+    /// Creates a container for guest memory regions tracking the pages written by the VMM.
+    ///
+    /// All the write accessors mark the pages they write dirty, and volatile slices are marked
+    /// dirty when they are obtained.
+    pub fn new_with_dirty_tracking(
+        ranges: &[(GuestAddress, GuestUsize)],
+        page_size: usize,
+    ) -> Result<GuestMemory> {
+        GuestMemory::create(ranges, Some(page_size))
+    }
+
+    fn create(
+        ranges: &[(GuestAddress, GuestUsize)],
+        page_size: Option<usize>,
+    ) -> Result<GuestMemory> {
         if ranges.is_empty() {
             return Err(Error::NoMemoryRegions);
         }
 
-        let mut regions = Vec::<MemoryRegion>::new();
-        for range in ranges.iter() {
-            if let Some(last) = regions.last() {
-                if last
-                    .guest_base
-                    .checked_add(last.mapping.size())
-                    .map_or(true, |a| a > range.0)
-                {
-                    return Err(Error::MemoryRegionOverlap);
-                }
-            }
-
-            let mapping = MemoryMapping::new(range.1).map_err(Error::MemoryMappingFailed)?;
-            regions.push(MemoryRegion {
-                mapping,
-                guest_base: range.0,
-            });
+        let mut ranges = ranges.to_vec();
+        ranges.sort();
+        check_ranges(
+            ranges
+                .iter()
+                .map(|&(base, size)| GuestAddressRange::new(base, size)),
+        )?;
+
+        let mut regions = Vec::<Arc<MemoryRegion>>::new();
+        for (base, size) in ranges {
+            let size = mmap::host_size(size).map_err(Error::MemoryMappingFailed)?;
+            let mapping = MemoryMapping::new(size).map_err(Error::MemoryMappingFailed)?;
+            let region = match page_size {
+                Some(page_size) => MemoryRegion::with_dirty_tracking(mapping, base, page_size),
+                None => MemoryRegion::new(mapping, base),
+            };
+            regions.push(Arc::new(region));
         }
 
         Ok(GuestMemory {
             regions: Arc::new(regions),
+            reservation: None,
         })
     }
 
     /// Creates a container for guest memory regions.
-    pub fn from_regions(regions: Vec<MemoryRegion>) -> Self {
-        GuestMemory {
+    ///
+    /// The memory regions may be given in any order, but must not be empty nor overlap.
+    pub fn from_regions(regions: Vec<MemoryRegion>) -> Result<Self> {
+        GuestMemory::from_shared_regions(regions.into_iter().map(Arc::new).collect())
+    }
+
+    /// Creates a container for guest memory regions, which may be shared with other containers.
+    ///
+    /// The memory regions may be given in any order, but must not be empty nor overlap.
+    pub fn from_shared_regions(mut regions: Vec<Arc<MemoryRegion>>) -> Result<Self> {
+        regions.sort_by_key(|region| region.guest_base);
+        check_ranges(regions.iter().map(|region| region.range()))?;
+        let reservation = regions
+            .first()
+            .and_then(|region| region.mapping.reservation())
+            .filter(|reservation| {
+                regions.iter().all(|region| {
+                    region
+                        .mapping
+                        .reservation()
+                        .is_some_and(|other| Arc::ptr_eq(other, reservation))
+                        && region.mapping.as_ptr() as u64
+                            == (reservation.as_ptr() as u64)
+                                .wrapping_add(region.guest_base.raw_value())
+                })
+            })
+            .cloned();
+        Ok(GuestMemory {
             regions: Arc::new(regions),
-        }
+            reservation,
+        })
+    }
+
+    /// Returns the host address guest address 0 translates to, if all the memory regions are
+    /// mapped at their guest address in one reservation, see
+    /// `AddressSpace::set_contiguous_mapping()`.
+    ///
+    /// Guest addresses then translate to host addresses with a single add. The holes between
+    /// the memory regions are inaccessible, so touching them faults.
+    pub fn host_base(&self) -> Option<*mut u8> {
+        self.reservation
+            .as_ref()
+            .map(|reservation| reservation.as_ptr())
+    }
+
+    /// Returns a new container with all the memory regions of this one plus `region`.
+    ///
+    /// The existing memory regions are shared with the new container instead of being mapped
+    /// again, so hot-adding memory doesn't duplicate the existing mappings.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping, MemoryRegion};
+    /// # use std::sync::Arc;
+    ///     let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000)]).unwrap();
+    ///     let mapping = MemoryMapping::new(0x1000).unwrap();
+    ///     let region = Arc::new(MemoryRegion::new(mapping, GuestAddress(0x1000)));
+    ///     let gm2 = gm.insert_region(region).unwrap();
+    ///     assert_eq!(gm.num_regions(), 1);
+    ///     assert_eq!(gm2.num_regions(), 2);
+    /// ```
+    pub fn insert_region(&self, region: Arc<MemoryRegion>) -> Result<GuestMemory> {
+        let index = self
+            .regions
+            .partition_point(|other| other.guest_base < region.guest_base);
+        let mut regions = Vec::with_capacity(self.regions.len() + 1);
+        regions.extend_from_slice(&self.regions[..index]);
+        regions.push(region);
+        regions.extend_from_slice(&self.regions[index..]);
+        GuestMemory::from_shared_regions(regions)
+    }
+
+    /// Returns the memory region containing `addr`.
+    pub fn find_region(&self, addr: GuestAddress) -> Option<&Arc<MemoryRegion>> {
+        let index = self
+            .regions
+            .partition_point(|region| region.guest_base <= addr);
+        // Regions don't overlap, so only the last region starting at or below `addr` may
+        // contain it.
+        index
+            .checked_sub(1)
+            .map(|index| &self.regions[index])
+            .filter(|region| region.range().contains(addr))
     }
 
     /// Returns the end address of memory.
@@ -156,7 +363,7 @@ impl GuestMemory {
     /// # Examples
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{Address, GuestAddress, GuestMemory, MemoryMapping};
     /// # fn test_end_addr() -> Result<(), ()> {
     ///     let start_addr = GuestAddress(0x1000);
     ///     let mut gm = GuestMemory::new(&vec![(start_addr, 0x400)]).map_err(|_| ())?;
@@ -166,39 +373,25 @@ impl GuestMemory {
     /// ```
     pub fn end_addr(&self) -> GuestAddress {
         self.regions
-            .iter()
-            .max_by_key(|region| region.guest_base)
-            .map_or(GuestAddress(0), |region| region_end(region))
+            .last()
+            .and_then(|region| region.range().end())
+            .unwrap_or(GuestAddress(0))
     }
 
     /// Returns the total size of memory in bytes.
-    pub fn memory_size(&self) -> usize {
-        self.regions
-            .iter()
-            .map(|region| region.mapping.size())
-            .sum()
+    pub fn memory_size(&self) -> GuestUsize {
+        self.regions.iter().map(|region| region.size()).sum()
     }
 
     /// Returns true if the given address is within the memory range available to the guest.
     pub fn address_in_range(&self, addr: GuestAddress) -> bool {
-        for region in self.regions.iter() {
-            if addr >= region.guest_base && addr < region_end(region) {
-                return true;
-            }
-        }
-        false
+        self.find_region(addr).is_some()
     }
 
     /// Returns the address plus the offset if it is in range.
-    pub fn checked_offset(&self, base: GuestAddress, offset: usize) -> Option<GuestAddress> {
-        if let Some(addr) = base.checked_add(offset) {
-            for region in self.regions.iter() {
-                if addr >= region.guest_base && addr < region_end(region) {
-                    return Some(addr);
-                }
-            }
-        }
-        None
+    pub fn checked_offset(&self, base: GuestAddress, offset: GuestUsize) -> Option<GuestAddress> {
+        base.checked_add(offset)
+            .filter(|addr| self.address_in_range(*addr))
     }
 
     /// Returns the size of the memory region in bytes.
@@ -208,23 +401,26 @@ impl GuestMemory {
 
     /// Madvise away the address range in the host that is associated with the given guest range.
     pub fn remove_range(&self, addr: GuestAddress, count: usize) -> Result<()> {
-        self.do_in_region(addr, count, move |mapping, offset| {
-            mapping
+        self.do_in_region(addr, count, move |region, offset| {
+            region
+                .mapping
                 .remove_range(offset, count)
-                .map_err(|e| Error::MemoryAccess(addr, e))
+                .map_err(|e| write_error(addr, e))?;
+            region.mark_dirty(offset, count);
+            Ok(())
         })
     }
 
     /// Perform the specified action on each region's addresses.
     pub fn with_regions<F, E>(&self, cb: F) -> result::Result<(), E>
     where
-        F: Fn(usize, GuestAddress, usize, usize) -> result::Result<(), E>,
+        F: Fn(usize, GuestAddress, GuestUsize, usize) -> result::Result<(), E>,
     {
         for (index, region) in self.regions.iter().enumerate() {
             cb(
                 index,
                 region.guest_base,
-                region.mapping.size(),
+                region.size(),
                 region.mapping.as_ptr() as usize,
             )?;
         }
@@ -234,13 +430,13 @@ impl GuestMemory {
     /// Perform the specified action on each region's addresses mutably.
     pub fn with_regions_mut<F, E>(&self, mut cb: F) -> result::Result<(), E>
     where
-        F: FnMut(usize, GuestAddress, usize, usize) -> result::Result<(), E>,
+        F: FnMut(usize, GuestAddress, GuestUsize, usize) -> result::Result<(), E>,
     {
         for (index, region) in self.regions.iter().enumerate() {
             cb(
                 index,
                 region.guest_base,
-                region.mapping.size(),
+                region.size(),
                 region.mapping.as_ptr() as usize,
             )?;
         }
@@ -248,9 +444,9 @@ impl GuestMemory {
     }
 
     /// Writes a slice to guest memory at the specified guest address.
-    /// Returns the number of bytes written. The number of bytes written can
-    /// be less than the length of the slice if there isn't enough room in the
-    /// memory region.
+    /// Returns the number of bytes written. The write continues across
+    /// contiguous memory regions, and the number of bytes written can be less
+    /// than the length of the slice if it runs into a hole.
     ///
     /// # Examples
     /// * Write a slice at guestaddress 0x200.
@@ -266,10 +462,13 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_at_addr(&self, buf: &[u8], guest_addr: GuestAddress) -> Result<usize> {
-        self.do_in_region_partial(guest_addr, move |mapping, offset| {
-            mapping
-                .write_slice(buf, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+        self.do_in_regions(guest_addr, buf.len(), |region, offset, done, len| {
+            let count = region
+                .mapping
+                .write_slice(&buf[done..done + len], offset)
+                .map_err(|e| write_error(guest_addr.unchecked_add(done as GuestUsize), e))?;
+            region.mark_dirty(offset, count);
+            Ok(count)
         })
     }
 
@@ -305,9 +504,9 @@ impl GuestMemory {
     }
 
     /// Reads to a slice from guest memory at the specified guest address.
-    /// Returns the number of bytes read.  The number of bytes read can
-    /// be less than the length of the slice if there isn't enough room in the
-    /// memory region.
+    /// Returns the number of bytes read. The read continues across contiguous
+    /// memory regions, and the number of bytes read can be less than the
+    /// length of the slice if it runs into a hole.
     ///
     /// # Examples
     /// * Read a slice of length 16 at guestaddress 0x200.
@@ -323,15 +522,12 @@ impl GuestMemory {
     ///     Ok(())
     /// # }
     /// ```
-    pub fn read_slice_at_addr(
-        &self,
-        mut buf: &mut [u8],
-        guest_addr: GuestAddress,
-    ) -> Result<usize> {
-        self.do_in_region_partial(guest_addr, move |mapping, offset| {
-            mapping
-                .read_slice(buf, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+    pub fn read_slice_at_addr(&self, buf: &mut [u8], guest_addr: GuestAddress) -> Result<usize> {
+        self.do_in_regions(guest_addr, buf.len(), |region, offset, done, len| {
+            region
+                .mapping
+                .read_slice(&mut buf[done..done + len], offset)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as GuestUsize), e))
         })
     }
 
@@ -390,8 +586,9 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn read_obj_from_addr<T: DataInit>(&self, guest_addr: GuestAddress) -> Result<T> {
-        self.do_in_region(guest_addr, mem::size_of::<T>(), |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, mem::size_of::<T>(), |region, offset| {
+            region
+                .mapping
                 .read_obj(offset)
                 .map_err(|e| Error::MemoryAccess(guest_addr, e))
         })
@@ -416,10 +613,13 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn write_obj_at_addr<T: DataInit>(&self, val: T, guest_addr: GuestAddress) -> Result<()> {
-        self.do_in_region(guest_addr, mem::size_of::<T>(), move |mapping, offset| {
-            mapping
+        self.do_in_region(guest_addr, mem::size_of::<T>(), move |region, offset| {
+            region
+                .mapping
                 .write_obj(val, offset)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
+                .map_err(|e| write_error(guest_addr, e))?;
+            region.mark_dirty(offset, mem::size_of::<T>());
+            Ok(())
         })
     }
 
@@ -435,7 +635,7 @@ impl GuestMemory {
     /// * Read bytes from /dev/urandom
     ///
     /// ```
-    /// # use memory_model::{GuestAddress, GuestMemory, MemoryMapping};
+    /// # use memory_model::{Address, GuestAddress, GuestMemory, MemoryMapping};
     /// # use std::fs::File;
     /// # use std::path::Path;
     /// # fn test_read_random() -> Result<u32, ()> {
@@ -458,11 +658,17 @@ impl GuestMemory {
     where
         F: Read,
     {
-        self.do_in_region(guest_addr, count, move |mapping, offset| {
-            mapping
-                .read_to_memory(offset, src, count)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        self.check_range(guest_addr, count)?;
+        self.do_in_regions(guest_addr, count, |region, offset, done, len| {
+            let res = region.mapping.read_to_memory(offset, src, len);
+            // Part of the chunk may have been written even if reading failed.
+            if region.mapping.protection().is_writable() {
+                region.mark_dirty(offset, len);
+            }
+            res.map(|_| len)
+                .map_err(|e| write_error(guest_addr.unchecked_add(done as GuestUsize), e))
+        })?;
+        Ok(())
     }
 
     /// Writes data from memory to a writable object.
@@ -498,11 +704,15 @@ impl GuestMemory {
     where
         F: Write,
     {
-        self.do_in_region(guest_addr, count, move |mapping, offset| {
-            mapping
-                .write_from_memory(offset, dst, count)
-                .map_err(|e| Error::MemoryAccess(guest_addr, e))
-        })
+        self.check_range(guest_addr, count)?;
+        self.do_in_regions(guest_addr, count, |region, offset, done, len| {
+            region
+                .mapping
+                .write_from_memory(offset, dst, len)
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(guest_addr.unchecked_add(done as GuestUsize), e))
+        })?;
+        Ok(())
     }
 
     /// Converts a GuestAddress into a pointer in the address space of this
@@ -510,6 +720,10 @@ impl GuestMemory {
     /// kernel, as with vhost ioctls. Normal reads/writes to guest memory should
     /// be done through `write_from_memory`, `read_obj_from_addr`, etc.
     ///
+    /// Fails for addresses outside of the memory regions, even if they are mapped contiguously,
+    /// see `host_base()`, in which case the pointer returned for the others is the base plus the
+    /// guest address.
+    ///
     /// # Arguments
     /// * `guest_addr` - Guest address to convert.
     ///
@@ -526,23 +740,286 @@ impl GuestMemory {
     /// # }
     /// ```
     pub fn get_host_address(&self, guest_addr: GuestAddress) -> Result<*const u8> {
-        self.do_in_region(guest_addr, 1, |mapping, offset| {
+        self.do_in_region(guest_addr, 1, |region, offset| {
             // This is safe; `do_in_region` already checks that offset is in
             // bounds.
-            Ok(unsafe { mapping.as_ptr().offset(offset as isize) } as *const u8)
+            Ok(unsafe { region.mapping.as_ptr().add(offset) } as *const u8)
+        })
+    }
+
+    /// Returns volatile slices covering `count` bytes of guest memory starting at `guest_addr`,
+    /// one for each memory region the range spans.
+    ///
+    /// Volatile slices are writable, so this fails with `Error::ReadOnlyMemory` if the range
+    /// spans read-only memory. Writes through the slices can't be tracked, so their pages are
+    /// marked dirty, see `DirtyGuard`.
+    pub fn get_slices(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+    ) -> Result<DirtyGuard<'_, VolatileSlice<'_>>> {
+        let slices = self.slices(guest_addr, count, true)?;
+        Ok(DirtyGuard::new(self, guest_addr, count, slices))
+    }
+
+    /// Returns iovecs covering `count` bytes of guest memory starting at `guest_addr`, one for
+    /// each memory region the range spans, to hand guest memory to vectored I/O system calls.
+    ///
+    /// The iovecs point to guest memory, so they must not be used once the guard is dropped. The
+    /// kernel may write through them, so this fails with `Error::ReadOnlyMemory` if the range
+    /// spans read-only memory, and their pages are marked dirty, see `DirtyGuard`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// let gm = GuestMemory::new(&[
+    ///     (GuestAddress(0x1000), 0x1000),
+    ///     (GuestAddress(0x2000), 0x1000),
+    /// ])
+    /// .unwrap();
+    /// let iovecs = gm.get_iovecs(GuestAddress(0x1800), 0x1000).unwrap();
+    /// assert_eq!(iovecs.len(), 2);
+    /// assert_eq!(iovecs[0].iov_len, 0x800);
+    /// assert_eq!(iovecs[1].iov_len, 0x800);
+    /// ```
+    pub fn get_iovecs(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+    ) -> Result<DirtyGuard<'_, libc::iovec>> {
+        let iovecs = self
+            .slices(guest_addr, count, true)?
+            .iter()
+            .map(VolatileSlice::as_iovec)
+            .collect();
+        Ok(DirtyGuard::new(self, guest_addr, count, iovecs))
+    }
+
+    /// Reads up to `count` bytes from `fd` to guest memory starting at `guest_addr`, with a
+    /// single `readv` system call, and returns how many bytes were read.
+    pub fn read_vectored_from_fd(
+        &self,
+        guest_addr: GuestAddress,
+        fd: &dyn AsRawFd,
+        count: usize,
+    ) -> Result<usize> {
+        let slices = self.slices(guest_addr, count, true)?;
+        let res = read_vectored_from_fd(fd, &slices);
+        // Part of the range may have been written even if reading failed.
+        self.mark_range_dirty(guest_addr, count);
+        res.map_err(|e| Error::MemoryAccess(guest_addr, mmap::Error::SystemCallFailed(e)))
+    }
+
+    /// Writes up to `count` bytes of guest memory starting at `guest_addr` to `fd`, with a single
+    /// `writev` system call, and returns how many bytes were written.
+    pub fn write_vectored_to_fd(
+        &self,
+        guest_addr: GuestAddress,
+        fd: &dyn AsRawFd,
+        count: usize,
+    ) -> Result<usize> {
+        let slices = self.slices(guest_addr, count, false)?;
+        write_vectored_to_fd(fd, &slices)
+            .map_err(|e| Error::MemoryAccess(guest_addr, mmap::Error::SystemCallFailed(e)))
+    }
+
+    /// Reads up to `count` bytes at `file_offset` of `fd` to guest memory starting at
+    /// `guest_addr`, with `pread`, and returns how many bytes were read.
+    ///
+    /// Fewer bytes are only read if the end of the file is reached. The file position of `fd` is
+    /// left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// # use std::fs::File;
+    /// let gm = GuestMemory::new(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+    /// let file = File::open("/dev/zero").unwrap();
+    /// let count = gm
+    ///     .read_from_fd_at(GuestAddress(0x1200), &file, 0x200, 0x400)
+    ///     .unwrap();
+    /// assert_eq!(count, 0x400);
+    /// ```
+    pub fn read_from_fd_at(
+        &self,
+        guest_addr: GuestAddress,
+        fd: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<usize> {
+        self.check_range(guest_addr, count)?;
+        self.do_in_regions(guest_addr, count, |region, offset, done, len| {
+            let addr = guest_addr.unchecked_add(done as GuestUsize);
+            let chunk_offset = file_offset
+                .checked_add(done as u64)
+                .ok_or(Error::MemoryAccess(addr, mmap::Error::InvalidOffset))?;
+            let res = region
+                .mapping
+                .read_from_fd_at(fd, chunk_offset, offset, len);
+            // Part of the chunk may have been written even if reading failed.
+            if region.mapping.protection().is_writable() {
+                region.mark_dirty(offset, len);
+            }
+            res.map_err(|e| write_error(addr, e))
         })
     }
 
+    /// Writes `count` bytes of guest memory starting at `guest_addr` at `file_offset` of `fd`,
+    /// with `pwrite`.
+    ///
+    /// The file position of `fd` is left untouched.
+    pub fn write_to_fd_at(
+        &self,
+        guest_addr: GuestAddress,
+        fd: &dyn AsRawFd,
+        file_offset: u64,
+        count: usize,
+    ) -> Result<()> {
+        self.check_range(guest_addr, count)?;
+        self.do_in_regions(guest_addr, count, |region, offset, done, len| {
+            let addr = guest_addr.unchecked_add(done as GuestUsize);
+            let chunk_offset = file_offset
+                .checked_add(done as u64)
+                .ok_or(Error::MemoryAccess(addr, mmap::Error::InvalidOffset))?;
+            region
+                .mapping
+                .write_to_fd_at(fd, chunk_offset, offset, len)
+                .map(|_| len)
+                .map_err(|e| Error::MemoryAccess(addr, e))
+        })?;
+        Ok(())
+    }
+
+    /// Returns true if dirty pages are tracked and the page containing `addr` is dirty, in the
+    /// default view of the dirty pages shared by the consumers without a `DirtyLog`.
+    pub fn is_dirty(&self, addr: GuestAddress) -> bool {
+        self.find_region(addr)
+            .and_then(|region| {
+                let offset = addr.offset_from(region.guest_base) as usize;
+                region.dirty_bitmap().map(|dirty| dirty.is_dirty(offset))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns the guest addresses of the dirty pages intersecting with `range`, in the default
+    /// view.
+    pub fn dirty_pages(&self, range: &GuestAddressRange) -> Vec<GuestAddress> {
+        self.collect_dirty_pages(range, |dirty, offset, len| dirty.dirty_pages(offset, len))
+    }
+
+    /// Returns the guest addresses of the dirty pages intersecting with `range`, and marks them
+    /// clean, in the default view.
+    ///
+    /// Pages are fetched and cleared atomically, so a page written concurrently is either
+    /// returned or left dirty. The pages are still reported to the `DirtyLog`s.
+    pub fn fetch_and_clear_dirty_pages(&self, range: &GuestAddressRange) -> Vec<GuestAddress> {
+        self.collect_dirty_pages(range, |dirty, offset, len| {
+            dirty.fetch_and_clear(offset, len)
+        })
+    }
+
+    /// Marks clean all the pages tracked as dirty, in the default view.
+    ///
+    /// The pages are still reported to the `DirtyLog`s.
+    pub fn clear_dirty_pages(&self) {
+        for region in self.regions.iter() {
+            if let Some(dirty) = region.dirty_bitmap() {
+                dirty.clear();
+            }
+        }
+    }
+
+    /// Creates a log of the pages written from now on, which isn't affected by the other
+    /// consumers of the dirty pages.
+    ///
+    /// The log is subscribed to the memory regions tracking dirty pages. Pages of memory regions
+    /// added later are all reported dirty the first time they are fetched from the log.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestAddressRange, GuestMemory};
+    /// let gm = GuestMemory::new_with_dirty_tracking(&[(GuestAddress(0), 0x4000)], 0x1000).unwrap();
+    /// let all = GuestAddressRange::new(GuestAddress(0), 0x4000);
+    /// let log = gm.dirty_log();
+    /// gm.write_obj_at_addr(1u8, GuestAddress(0x2000)).unwrap();
+    ///
+    /// gm.clear_dirty_pages();
+    /// assert_eq!(gm.fetch_and_clear_dirty_log(&log, &all), vec![GuestAddress(0x2000)]);
+    /// assert!(gm.fetch_and_clear_dirty_log(&log, &all).is_empty());
+    /// ```
+    pub fn dirty_log(&self) -> DirtyLog {
+        let log = DirtyLog::new();
+        for region in self.regions.iter() {
+            if let Some(dirty) = region.dirty_bitmap() {
+                dirty.subscribe(&log);
+            }
+        }
+        log
+    }
+
+    /// Returns the guest addresses of the pages intersecting with `range` written since they were
+    /// last fetched from `log`, and marks them clean in `log` only.
+    ///
+    /// Pages are fetched and cleared atomically, so a page written concurrently is either
+    /// returned or reported by the next call.
+    pub fn fetch_and_clear_dirty_log(
+        &self,
+        log: &DirtyLog,
+        range: &GuestAddressRange,
+    ) -> Vec<GuestAddress> {
+        self.collect_dirty_pages(range, |dirty, offset, len| {
+            dirty.fetch_and_clear_log(log, offset, len)
+        })
+    }
+
+    // Collect the dirty pages returned by `cb` for the part of each region intersecting with
+    // `range`, as guest addresses.
+    fn collect_dirty_pages<F>(&self, range: &GuestAddressRange, cb: F) -> Vec<GuestAddress>
+    where
+        F: Fn(&DirtyBitmap, usize, usize) -> Vec<usize>,
+    {
+        let first = self
+            .regions
+            .partition_point(|region| region.guest_base <= range.start())
+            .saturating_sub(1);
+        let mut pages = Vec::new();
+        for region in self.regions[first..].iter() {
+            if region.guest_base > range.start() && !range.contains(region.guest_base) {
+                break;
+            }
+            let (dirty, part) = match (region.dirty_bitmap(), region.range().intersection(range)) {
+                (Some(dirty), Some(part)) => (dirty, part),
+                _ => continue,
+            };
+            // Offsets and lengths fit in usize because they're smaller than the mapping size.
+            let offset = part.start().offset_from(region.guest_base) as usize;
+            let page_size = dirty.page_size() as GuestUsize;
+            pages.extend(
+                cb(dirty, offset, part.len() as usize)
+                    .into_iter()
+                    .map(|page| {
+                        region
+                            .guest_base
+                            .unchecked_add(page as GuestUsize * page_size)
+                    }),
+            );
+        }
+        pages
+    }
+
     /// Applies two functions, specified as callbacks, on the inner memory regions.
     ///
     /// # Arguments
     /// * `init` - Starting value of the accumulator for the `foldf` function.
     /// * `mapf` - "Map" function, applied to all the inner memory regions. It returns an array of
-    ///            the same size as the memory regions array, containing the function's results
-    ///            for each region.
+    ///   the same size as the memory regions array, containing the function's results for each
+    ///   region.
     /// * `foldf` - "Fold" function, applied to the array returned by `mapf`. It acts as an
-    ///             operator, applying itself to the `init` value and to each subsequent elemnent
-    ///             in the array returned by `mapf`.
+    ///   operator, applying itself to the `init` value and to each subsequent elemnent in the
+    ///   array returned by `mapf`.
     ///
     /// # Examples
     ///
@@ -569,48 +1046,169 @@ impl GuestMemory {
         F: Fn((usize, &MemoryRegion)) -> T,
         G: Fn(T, T) -> T,
     {
-        self.regions.iter().enumerate().map(mapf).fold(init, foldf)
+        self.regions
+            .iter()
+            .enumerate()
+            .map(|(index, region)| (index, &**region))
+            .map(mapf)
+            .fold(init, foldf)
     }
 
     /// Read the whole object from a single MemoryRegion
     fn do_in_region<F, T>(&self, guest_addr: GuestAddress, size: usize, cb: F) -> Result<T>
     where
-        F: FnOnce(&MemoryMapping, usize) -> Result<T>,
+        F: FnOnce(&MemoryRegion, usize) -> Result<T>,
     {
-        for region in self.regions.iter() {
-            if guest_addr >= region.guest_base && guest_addr < region_end(region) {
-                let offset = guest_addr.offset_from(region.guest_base);
-                if size <= region.mapping.size() - offset {
-                    return cb(&region.mapping, offset);
-                }
-                break;
+        let range = GuestAddressRange::new(guest_addr, size as GuestUsize);
+        if let Some(region) = self.find_region(guest_addr) {
+            if region.range().contains_range(&range) {
+                // The offset fits in usize because it's smaller than the size of the mapping.
+                let offset = guest_addr.offset_from(region.guest_base) as usize;
+                return cb(region, offset);
             }
         }
-        Err(Error::InvalidGuestAddressRange(guest_addr, size))
+        Err(Error::InvalidGuestAddressRange(
+            guest_addr,
+            size as GuestUsize,
+        ))
     }
 
-    /// Read the whole or partial content from a single MemoryRegion
-    fn do_in_region_partial<F>(&self, guest_addr: GuestAddress, cb: F) -> Result<usize>
+    /// Perform the specified action on each chunk of a guest address range, iterating over
+    /// contiguous memory regions.
+    ///
+    /// The callback gets the region and offset of the chunk, the number of bytes already done
+    /// and the length of the chunk, and returns the number of bytes it processed. Returns the
+    /// total number of bytes processed, which is less than `count` if the range runs into a hole.
+    fn do_in_regions<F>(&self, guest_addr: GuestAddress, count: usize, mut cb: F) -> Result<usize>
     where
-        F: FnOnce(&MemoryMapping, usize) -> Result<usize>,
+        F: FnMut(&MemoryRegion, usize, usize, usize) -> Result<usize>,
     {
-        for region in self.regions.iter() {
-            if guest_addr >= region.guest_base && guest_addr < region_end(region) {
-                return cb(&region.mapping, guest_addr.offset_from(region.guest_base));
+        if !self.address_in_range(guest_addr) {
+            return Err(Error::InvalidGuestAddress(guest_addr));
+        }
+        let mut done = 0;
+        while done < count {
+            let region = match guest_addr
+                .checked_add(done as GuestUsize)
+                .and_then(|addr| self.find_region(addr))
+            {
+                Some(region) => region,
+                None => break,
+            };
+            // The offset fits in usize because it's smaller than the size of the mapping.
+            let offset = guest_addr
+                .unchecked_add(done as GuestUsize)
+                .offset_from(region.guest_base) as usize;
+            let len = std::cmp::min(count - done, region.mapping.size() - offset);
+            let completed = cb(region, offset, done, len)?;
+            done += completed;
+            if completed < len {
+                break;
             }
         }
-        Err(Error::InvalidGuestAddress(guest_addr))
+        Ok(done)
+    }
+
+    // Returns volatile slices covering a guest address range, checking that the memory regions
+    // are writable if `writable` is set.
+    fn slices(
+        &self,
+        guest_addr: GuestAddress,
+        count: usize,
+        writable: bool,
+    ) -> Result<Vec<VolatileSlice<'_>>> {
+        self.check_range(guest_addr, count)?;
+        let mut slices = Vec::new();
+        self.do_in_regions(guest_addr, count, |region, offset, done, len| {
+            if writable && !region.protection().is_writable() {
+                return Err(Error::ReadOnlyMemory(
+                    guest_addr.unchecked_add(done as GuestUsize),
+                ));
+            }
+            // This is safe because `do_in_regions` checks the chunk is within the mapping, which
+            // is kept alive by `self` for the lifetime of the slice.
+            slices.push(unsafe { VolatileSlice::new(region.mapping.as_ptr().add(offset), len) });
+            Ok(len)
+        })?;
+        Ok(slices)
+    }
+
+    // Marks dirty the pages of a guest address range.
+    fn mark_range_dirty(&self, guest_addr: GuestAddress, count: usize) {
+        let _ = self.do_in_regions(guest_addr, count, |region, offset, _, len| {
+            region.mark_dirty(offset, len);
+            Ok(len)
+        });
+    }
+
+    /// Check that a guest address range is covered by contiguous memory regions.
+    fn check_range(&self, guest_addr: GuestAddress, count: usize) -> Result<()> {
+        let covered = self.do_in_regions(guest_addr, count, |_, _, _, len| Ok(len))?;
+        if covered < count {
+            return Err(Error::InvalidGuestAddressRange(
+                guest_addr,
+                count as GuestUsize,
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Volatile slices or iovecs of guest memory, returned by `GuestMemory::get_slices()` and
+/// `GuestMemory::get_iovecs()`.
+///
+/// Writes through them can't be tracked, so their pages are marked dirty when they are obtained,
+/// and again when the guard is dropped. Pages collected by `fetch_and_clear_dirty_pages()` before
+/// the writes are done are so reported again by the next collection, as long as the guard is
+/// only dropped once the writes are done.
+pub struct DirtyGuard<'a, T> {
+    mem: &'a GuestMemory,
+    guest_addr: GuestAddress,
+    count: usize,
+    items: Vec<T>,
+}
+
+impl<'a, T> DirtyGuard<'a, T> {
+    fn new(mem: &'a GuestMemory, guest_addr: GuestAddress, count: usize, items: Vec<T>) -> Self {
+        mem.mark_range_dirty(guest_addr, count);
+        DirtyGuard {
+            mem,
+            guest_addr,
+            count,
+            items,
+        }
+    }
+}
+
+impl<'a, T> Deref for DirtyGuard<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.items
+    }
+}
+
+impl<'a, T> Drop for DirtyGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mem.mark_range_dirty(self.guest_addr, self.count);
     }
 }
 
+/// The offsets are guest addresses, so a 32-bit VMM can't reach guest memory above 4 GiB through
+/// this trait. Use `GuestMemory::get_slices()`, which takes a `GuestAddress`, instead.
+///
+/// Writes through the slices can't be tracked, so the pages covered by slices of memory regions
+/// tracking dirty pages are marked dirty when the slices are taken, as by `DirtyGuard`. Unlike
+/// with `GuestMemory::get_slices()`, they aren't marked again once the writes are done, so pages
+/// collected in between are missed: take a new slice for each batch of writes.
 impl VolatileMemory for GuestMemory {
-    fn get_slice(&self, offset: usize, count: usize) -> VolatileMemoryResult<VolatileSlice> {
-        for region in self.regions.iter() {
-            if offset >= region.guest_base.0 && offset < region_end(region).0 {
-                return region
-                    .mapping
-                    .get_slice(offset - region.guest_base.0, count);
-            }
+    fn get_slice(&self, offset: usize, count: usize) -> VolatileMemoryResult<VolatileSlice<'_>> {
+        let addr = GuestAddress(offset as GuestUsize);
+        if let Some(region) = self.find_region(addr) {
+            let region_offset = addr.offset_from(region.guest_base) as usize;
+            let slice = region.mapping.get_slice(region_offset, count)?;
+            region.mark_dirty(region_offset, count);
+            return Ok(slice);
         }
         Err(VolatileMemoryError::OutOfBounds { addr: offset })
     }
@@ -618,23 +1216,27 @@ impl VolatileMemory for GuestMemory {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
     use super::*;
     use std::fs::File;
+    use std::io::{Seek, SeekFrom};
     use std::mem;
+    use std::os::unix::fs::FileExt;
     use std::path::Path;
 
     #[test]
     fn test_regions() {
         // No regions provided should return error.
         assert_eq!(
-            format!("{:?}", GuestMemory::new(&vec![]).err().unwrap()),
+            format!("{:?}", GuestMemory::new(&[]).err().unwrap()),
             format!("{:?}", Error::NoMemoryRegions)
         );
 
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x800);
-        let guest_mem =
-            GuestMemory::new(&vec![(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
+        let guest_mem = GuestMemory::new(&[(start_addr1, 0x400), (start_addr2, 0x400)]).unwrap();
         assert_eq!(guest_mem.num_regions(), 2);
         assert!(guest_mem.address_in_range(GuestAddress(0x200)));
         assert!(!guest_mem.address_in_range(GuestAddress(0x600)));
@@ -647,14 +1249,173 @@ mod tests {
         assert!(guest_mem.checked_offset(start_addr2, 0xc00).is_none());
     }
 
+    #[test]
+    fn high_memory() {
+        // Regions above 4GiB must be usable even if usize is only 32-bit.
+        let start_addr = GuestAddress(0x1_0000_0000);
+        let gm = GuestMemory::new(&[(start_addr, 0x1000)]).unwrap();
+        assert!(gm.address_in_range(GuestAddress(0x1_0000_0800)));
+        assert_eq!(gm.end_addr(), GuestAddress(0x1_0000_1000));
+
+        gm.write_obj_at_addr(0x1234u32, GuestAddress(0x1_0000_0800))
+            .unwrap();
+        let val: u32 = gm.read_obj_from_addr(GuestAddress(0x1_0000_0800)).unwrap();
+        assert_eq!(val, 0x1234);
+    }
+
     #[test]
     fn overlap_memory() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let res = GuestMemory::new(&vec![(start_addr1, 0x2000), (start_addr2, 0x2000)]);
+        let res = GuestMemory::new(&[(start_addr1, 0x2000), (start_addr2, 0x2000)]);
+        assert_eq!(
+            format!("{:?}", res.err().unwrap()),
+            format!(
+                "{:?}",
+                Error::MemoryRegionOverlap(
+                    GuestAddressRange::new(start_addr1, 0x2000),
+                    GuestAddressRange::new(start_addr2, 0x2000)
+                )
+            )
+        );
+        let res = GuestMemory::new(&[(start_addr2, 0x1000), (start_addr1, 0x1000)]);
+        assert_eq!(res.unwrap().end_addr(), GuestAddress(0x2000));
+        let res = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0)]);
+        assert_eq!(
+            format!("{:?}", res.err().unwrap()),
+            format!("{:?}", Error::EmptyMemoryRegion(start_addr2))
+        );
+    }
+
+    #[test]
+    fn validate_regions() {
+        let region = |base: u64, size: usize| {
+            MemoryRegion::new(MemoryMapping::new(size).unwrap(), GuestAddress(base))
+        };
+
+        // Unsorted regions are accepted.
+        let gm =
+            GuestMemory::from_regions(vec![region(0x4000, 0x1000), region(0x0, 0x1000)]).unwrap();
+        assert_eq!(gm.end_addr(), GuestAddress(0x5000));
+        assert_eq!(
+            gm.checked_offset(GuestAddress(0x0), 0x4800),
+            Some(GuestAddress(0x4800))
+        );
+        assert_eq!(gm.checked_offset(GuestAddress(0x0), 0x1800), None);
+
+        // The offending pair is reported whatever the order of the regions.
+        let res = GuestMemory::from_regions(vec![
+            region(0x8000, 0x1000),
+            region(0x2000, 0x2000),
+            region(0x0, 0x1000),
+            region(0x3000, 0x1000),
+        ]);
+        match res {
+            Err(Error::MemoryRegionOverlap(first, second)) => {
+                assert_eq!(first, GuestAddressRange::new(GuestAddress(0x2000), 0x2000));
+                assert_eq!(second, GuestAddressRange::new(GuestAddress(0x3000), 0x1000));
+            }
+            _ => panic!("overlapping regions should be rejected"),
+        }
+        let err = GuestMemory::from_regions(vec![region(0x0, 0x2000), region(0x1000, 0x1000)])
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!("{}", err),
+            "Guest memory error: Memory regions overlap, base 0x0/size 0x2000 and base \
+             0x1000/size 0x1000"
+        );
+
+        // Regions at the same address always overlap.
+        assert!(GuestMemory::from_regions(vec![region(0x0, 0x1000), region(0x0, 0x1000)]).is_err());
+    }
+
+    #[test]
+    fn insert_region() {
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x1000), (GuestAddress(0x4000), 0x1000)])
+            .unwrap();
+        gm.write_obj_at_addr(0x1234u16, GuestAddress(0x4010))
+            .unwrap();
+
+        let mapping = MemoryMapping::new(0x1000).unwrap();
+        let region = Arc::new(MemoryRegion::new(mapping, GuestAddress(0x2000)));
+        let gm2 = gm.insert_region(region.clone()).unwrap();
+        assert_eq!(gm.num_regions(), 2);
+        assert_eq!(gm2.num_regions(), 3);
+        assert!(Arc::ptr_eq(
+            gm2.find_region(GuestAddress(0x2fff)).unwrap(),
+            &region
+        ));
+        assert!(Arc::ptr_eq(
+            gm.find_region(GuestAddress(0x4000)).unwrap(),
+            gm2.find_region(GuestAddress(0x4000)).unwrap()
+        ));
+        assert_eq!(
+            gm2.read_obj_from_addr::<u16>(GuestAddress(0x4010)).unwrap(),
+            0x1234
+        );
+        // Regions are kept sorted by address.
+        let mut bases = Vec::new();
+        gm2.with_regions_mut(|_, base, _, _| -> result::Result<(), ()> {
+            bases.push(base);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            bases,
+            vec![
+                GuestAddress(0x0),
+                GuestAddress(0x2000),
+                GuestAddress(0x4000)
+            ]
+        );
+
+        let mapping = MemoryMapping::new(0x1000).unwrap();
+        let region = Arc::new(MemoryRegion::new(mapping, GuestAddress(0x2800)));
+        match gm2.insert_region(region) {
+            Err(Error::MemoryRegionOverlap(_, _)) => {}
+            _ => panic!("overlapping region should be rejected"),
+        }
+    }
+
+    #[test]
+    fn many_regions() {
+        // Hundreds of hot-plugged DIMMs with a hole between each pair of them, given out of order.
+        let count = 512u64;
+        let regions: Vec<MemoryRegion> = (0..count)
+            .rev()
+            .map(|i| {
+                let mapping = MemoryMapping::new(0x1000).unwrap();
+                MemoryRegion::new(mapping, GuestAddress(i * 0x2000))
+            })
+            .collect();
+        let gm = GuestMemory::from_regions(regions).unwrap();
+        assert_eq!(gm.num_regions(), count as usize);
+        assert_eq!(gm.end_addr(), GuestAddress((count - 1) * 0x2000 + 0x1000));
+
+        for i in 0..count {
+            let base = GuestAddress(i * 0x2000);
+            assert_eq!(gm.find_region(base).unwrap().guest_base(), base);
+            assert!(gm.address_in_range(base.unchecked_add(0xfff)));
+            assert!(!gm.address_in_range(base.unchecked_add(0x1000)));
+            assert!(!gm.address_in_range(base.unchecked_add(0x1fff)));
+            gm.write_obj_at_addr(i, base.unchecked_add(0x8)).unwrap();
+        }
+        for i in 0..count {
+            let addr = GuestAddress(i * 0x2000 + 0x8);
+            assert_eq!(gm.read_obj_from_addr::<u64>(addr).unwrap(), i);
+            assert_eq!(gm.get_ref::<u64>(addr.offset() as usize).unwrap().load(), i);
+        }
+        assert!(gm.get_slice((count * 0x2000) as usize, 1).is_err());
+    }
+
+    #[test]
+    fn overflow_memory() {
+        let start_addr = GuestAddress(0xffff_ffff_ffff_f000);
+        let res = GuestMemory::new(&[(start_addr, 0x1000)]);
         assert_eq!(
             format!("{:?}", res.err().unwrap()),
-            format!("{:?}", Error::MemoryRegionOverlap)
+            format!("{:?}", Error::InvalidGuestAddressRange(start_addr, 0x1000))
         );
     }
 
@@ -665,7 +1426,7 @@ mod tests {
         let bad_addr = GuestAddress(0x2001);
         let bad_addr2 = GuestAddress(0x1ffc);
 
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
 
         let val1: u64 = 0xaa55aa55aa55aa55;
         let val2: u64 = 0x55aa55aa55aa55aa;
@@ -698,7 +1459,7 @@ mod tests {
     #[test]
     fn write_and_read_slice() {
         let mut start_addr = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr, 0x400)]).unwrap();
+        let gm = GuestMemory::new(&[(start_addr, 0x400)]).unwrap();
         let sample_buf = &[1, 2, 3, 4, 5];
 
         assert_eq!(gm.write_at_addr(sample_buf, start_addr).unwrap(), 5);
@@ -715,7 +1476,7 @@ mod tests {
 
     #[test]
     fn read_to_and_write_from_mem() {
-        let gm = GuestMemory::new(&vec![(GuestAddress(0x1000), 0x400)]).unwrap();
+        let gm = GuestMemory::new(&[(GuestAddress(0x1000), 0x400)]).unwrap();
         let addr = GuestAddress(0x1010);
         gm.write_obj_at_addr(!0u32, addr).unwrap();
         gm.read_to_memory(
@@ -733,6 +1494,311 @@ mod tests {
         assert_eq!(sink, vec![0; mem::size_of::<u32>()]);
     }
 
+    #[test]
+    fn cross_region_access() {
+        // Two adjacent regions followed by a hole.
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x3000), 0x1000),
+        ])
+        .unwrap();
+        let sample_buf: Vec<u8> = (0..32).collect();
+
+        gm.write_all_at_addr(&sample_buf, GuestAddress(0xff0))
+            .unwrap();
+        let buf = &mut [0u8; 32];
+        gm.read_exact_at_addr(buf, GuestAddress(0xff0)).unwrap();
+        assert_eq!(buf[..], sample_buf[..]);
+        assert_eq!(
+            gm.read_obj_from_addr::<u8>(GuestAddress(0x1000)).unwrap(),
+            16
+        );
+
+        // Stops at the hole.
+        assert_eq!(
+            gm.write_at_addr(&sample_buf, GuestAddress(0x1ff0)).unwrap(),
+            16
+        );
+        match gm.write_all_at_addr(&sample_buf, GuestAddress(0x1ff0)) {
+            Err(Error::ShortWrite {
+                expected: 32,
+                completed: 16,
+            }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match gm.read_exact_at_addr(buf, GuestAddress(0x1ff0)) {
+            Err(Error::ShortRead {
+                expected: 32,
+                completed: 16,
+            }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(gm.write_at_addr(&sample_buf, GuestAddress(0x2000)).is_err());
+
+        let mut src = &sample_buf[..];
+        gm.read_to_memory(GuestAddress(0xff8), &mut src, 16)
+            .unwrap();
+        assert_eq!(src.len(), 16);
+        assert_eq!(
+            gm.read_obj_from_addr::<u64>(GuestAddress(0x1000)).unwrap(),
+            u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15])
+        );
+        // Nothing is consumed when the range runs into a hole.
+        assert!(gm
+            .read_to_memory(GuestAddress(0x1ff8), &mut src, 16)
+            .is_err());
+        assert_eq!(src.len(), 16);
+
+        let mut sink = Vec::new();
+        gm.write_from_memory(GuestAddress(0xff8), &mut sink, 16)
+            .unwrap();
+        assert_eq!(sink, (0..16).collect::<Vec<u8>>());
+        assert!(gm
+            .write_from_memory(GuestAddress(0x1ff8), &mut sink, 16)
+            .is_err());
+        assert_eq!(sink.len(), 16);
+    }
+
+    #[test]
+    fn vectored_access() {
+        let mem = GuestMemory::new_with_dirty_tracking(
+            &[
+                (GuestAddress(0x0), 0x1000),
+                (GuestAddress(0x1000), 0x1000),
+                (GuestAddress(0x4000), 0x1000),
+            ],
+            0x1000,
+        )
+        .unwrap();
+
+        let iovecs = mem.get_iovecs(GuestAddress(0xf00), 0x200).unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(
+            iovecs[0].iov_base as usize,
+            mem.get_host_address(GuestAddress(0xf00)).unwrap() as usize
+        );
+        assert_eq!(iovecs[0].iov_len, 0x100);
+        assert_eq!(
+            iovecs[1].iov_base as usize,
+            mem.get_host_address(GuestAddress(0x1000)).unwrap() as usize
+        );
+        assert_eq!(iovecs[1].iov_len, 0x100);
+        assert!(mem.is_dirty(GuestAddress(0x0)));
+        assert!(mem.is_dirty(GuestAddress(0x1000)));
+        assert!(mem.get_iovecs(GuestAddress(0x1f00), 0x200).is_err());
+        assert!(mem.get_slices(GuestAddress(0x2000), 0x1).is_err());
+        assert!(mem.get_slices(GuestAddress(0x0), 0).unwrap().is_empty());
+
+        let mut file = tempfile().unwrap();
+        file.write_all(&[0xa5; 0x200]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        mem.fetch_and_clear_dirty_pages(&GuestAddressRange::new(GuestAddress(0), 0x5000));
+        assert_eq!(
+            mem.read_vectored_from_fd(GuestAddress(0xf00), &file, 0x200)
+                .unwrap(),
+            0x200
+        );
+        assert!(mem.is_dirty(GuestAddress(0x1000)));
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x10ff)).unwrap(),
+            0xa5
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x1100)).unwrap(),
+            0
+        );
+
+        let mut file = tempfile().unwrap();
+        assert_eq!(
+            mem.write_vectored_to_fd(GuestAddress(0xe00), &file, 0x400)
+                .unwrap(),
+            0x400
+        );
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf.len(), 0x400);
+        assert!(buf[..0x100].iter().all(|&b| b == 0));
+        assert!(buf[0x100..0x300].iter().all(|&b| b == 0xa5));
+        assert!(buf[0x300..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn fd_access_at() {
+        let mem = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x1000),
+            (GuestAddress(0x1000), 0x1000),
+            (GuestAddress(0x4000), 0x1000),
+        ])
+        .unwrap();
+        mem.write_all_at_addr(&[0x5a; 0x200], GuestAddress(0xf00))
+            .unwrap();
+
+        let file = tempfile().unwrap();
+        mem.write_to_fd_at(GuestAddress(0xf00), &file, 0x1000, 0x200)
+            .unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0x1200);
+        assert!(mem
+            .write_to_fd_at(GuestAddress(0x1f00), &file, 0, 0x200)
+            .is_err());
+
+        assert_eq!(
+            mem.read_from_fd_at(GuestAddress(0x4000), &file, 0x1100, 0x200)
+                .unwrap(),
+            0x100
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0x40ff)).unwrap(),
+            0x5a
+        );
+        assert_eq!(
+            mem.read_from_fd_at(GuestAddress(0xe00), &file, 0xf00, 0x400)
+                .unwrap(),
+            0x300
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0xeff)).unwrap(),
+            0
+        );
+        assert_eq!(
+            mem.read_obj_from_addr::<u8>(GuestAddress(0xf00)).unwrap(),
+            0x5a
+        );
+        assert!(mem
+            .read_from_fd_at(GuestAddress(0x1f00), &file, 0, 0x200)
+            .is_err());
+
+        // File offsets past the end of the range of off_t, which would wrap around to the start of
+        // the file in the second region.
+        let mut head = [0u8; 0x200];
+        file.read_exact_at(&mut head, 0).unwrap();
+        assert!(mem
+            .write_to_fd_at(GuestAddress(0xf00), &file, u64::MAX - 0x10, 0x200)
+            .is_err());
+        let mut unchanged = [0u8; 0x200];
+        file.read_exact_at(&mut unchanged, 0).unwrap();
+        assert!(unchanged[..] == head[..]);
+        assert_eq!(file.metadata().unwrap().len(), 0x1200);
+        assert!(mem
+            .read_from_fd_at(GuestAddress(0xf00), &file, u64::MAX - 0x10, 0x200)
+            .is_err());
+    }
+
+    #[test]
+    fn vectored_read_only() {
+        let mapping = MemoryMapping::new_with_protection(0x1000, Protection::ReadOnly).unwrap();
+        let mem =
+            GuestMemory::from_regions(vec![MemoryRegion::new(mapping, GuestAddress(0x0))]).unwrap();
+        let file = tempfile().unwrap();
+        match mem.read_vectored_from_fd(GuestAddress(0x10), &file, 0x10) {
+            Err(Error::ReadOnlyMemory(GuestAddress(0x10))) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(mem.get_slice(0x10, 1).is_err());
+        match mem.get_slices(GuestAddress(0x10), 0x10) {
+            Err(Error::ReadOnlyMemory(GuestAddress(0x10))) => {}
+            res => panic!("unexpected result {:?}", res.map(|_| ())),
+        }
+        assert!(mem.get_iovecs(GuestAddress(0x10), 0x10).is_err());
+        assert_eq!(
+            mem.write_vectored_to_fd(GuestAddress(0x10), &file, 0x10)
+                .unwrap(),
+            0x10
+        );
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let gm = GuestMemory::new_with_dirty_tracking(
+            &[
+                (GuestAddress(0x0), 0x4000),
+                (GuestAddress(0x4000), 0x4000),
+                (GuestAddress(0x10000), 0x4000),
+            ],
+            0x1000,
+        )
+        .unwrap();
+        let all = GuestAddressRange::new(GuestAddress(0), 0x20000);
+        assert!(gm.dirty_pages(&all).is_empty());
+
+        gm.write_obj_at_addr(1u64, GuestAddress(0x7ffc))
+            .unwrap_err();
+        gm.write_obj_at_addr(1u32, GuestAddress(0x1ffc)).unwrap();
+        gm.write_all_at_addr(&[1, 2, 3, 4], GuestAddress(0x3ffe))
+            .unwrap();
+        gm.read_to_memory(
+            GuestAddress(0x10800),
+            &mut File::open(Path::new("/dev/zero")).unwrap(),
+            0x1000,
+        )
+        .unwrap();
+        gm.get_slice(0x12000, 1).unwrap();
+        gm.get_slices(GuestAddress(0x13000), 1).unwrap();
+        // Reads don't dirty pages.
+        gm.read_obj_from_addr::<u64>(GuestAddress(0x6000)).unwrap();
+        let mut sink = Vec::new();
+        gm.write_from_memory(GuestAddress(0x5000), &mut sink, 0x10)
+            .unwrap();
+
+        assert!(gm.is_dirty(GuestAddress(0x1000)));
+        assert!(!gm.is_dirty(GuestAddress(0x2000)));
+        assert!(!gm.is_dirty(GuestAddress(0x7000)));
+        assert!(!gm.is_dirty(GuestAddress(0x20000)));
+        let dirty: Vec<GuestAddress> = [0x1000, 0x3000, 0x4000, 0x10000, 0x11000, 0x12000, 0x13000]
+            .iter()
+            .map(|addr| GuestAddress(*addr))
+            .collect();
+        assert_eq!(gm.dirty_pages(&all), dirty);
+        assert_eq!(
+            gm.dirty_pages(&GuestAddressRange::new(GuestAddress(0x3fff), 0x2)),
+            vec![GuestAddress(0x3000), GuestAddress(0x4000)]
+        );
+
+        // Per region queries.
+        let region = gm.find_region(GuestAddress(0x10000)).unwrap();
+        let bitmap = region.dirty_bitmap().unwrap();
+        assert_eq!(bitmap.dirty_pages(0, 0x4000), vec![0, 1, 2, 3]);
+
+        let range = GuestAddressRange::new(GuestAddress(0x3000), 0xe000);
+        assert_eq!(
+            gm.fetch_and_clear_dirty_pages(&range),
+            vec![
+                GuestAddress(0x3000),
+                GuestAddress(0x4000),
+                GuestAddress(0x10000)
+            ]
+        );
+        assert_eq!(
+            gm.dirty_pages(&all),
+            vec![
+                GuestAddress(0x1000),
+                GuestAddress(0x11000),
+                GuestAddress(0x12000),
+                GuestAddress(0x13000)
+            ]
+        );
+        assert_eq!(gm.fetch_and_clear_dirty_pages(&all).len(), 4);
+        assert!(gm.dirty_pages(&all).is_empty());
+
+        // Pages are marked dirty again once the slices are dropped, in case they were written
+        // after the dirty pages were collected.
+        let slices = gm.get_slices(GuestAddress(0x13000), 8).unwrap();
+        assert_eq!(
+            gm.fetch_and_clear_dirty_pages(&all),
+            vec![GuestAddress(0x13000)]
+        );
+        slices[0].copy_from(&[1u8; 8]);
+        drop(slices);
+        assert_eq!(gm.dirty_pages(&all), vec![GuestAddress(0x13000)]);
+
+        // Dirty pages aren't tracked by default.
+        let gm = GuestMemory::new(&[(GuestAddress(0x0), 0x4000)]).unwrap();
+        gm.write_obj_at_addr(1u32, GuestAddress(0x0)).unwrap();
+        assert!(!gm.is_dirty(GuestAddress(0)));
+        assert!(gm.dirty_pages(&all).is_empty());
+    }
+
     #[test]
     fn create_vec_with_regions() {
         let region_size = 0x400;
@@ -761,14 +1827,18 @@ mod tests {
 
     // Get the base address of the mapping for a GuestAddress.
     fn get_mapping(mem: &GuestMemory, addr: GuestAddress) -> Result<*const u8> {
-        mem.do_in_region(addr, 1, |mapping, _| Ok(mapping.as_ptr() as *const u8))
+        mem.do_in_region(
+            addr,
+            1,
+            |region, _| Ok(region.mapping.as_ptr() as *const u8),
+        )
     }
 
     #[test]
     fn guest_to_host() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x100);
-        let mem = GuestMemory::new(&vec![(start_addr1, 0x100), (start_addr2, 0x400)]).unwrap();
+        let mem = GuestMemory::new(&[(start_addr1, 0x100), (start_addr2, 0x400)]).unwrap();
 
         // Verify the host addresses match what we expect from the mappings.
         let addr1_base = get_mapping(&mem, start_addr1).unwrap();
@@ -787,7 +1857,7 @@ mod tests {
     fn test_map_fold() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x400);
-        let mem = GuestMemory::new(&vec![(start_addr1, 1024), (start_addr2, 2048)]).unwrap();
+        let mem = GuestMemory::new(&[(start_addr1, 1024), (start_addr2, 2048)]).unwrap();
 
         assert_eq!(
             mem.map_and_fold(
@@ -803,7 +1873,7 @@ mod tests {
     fn test_ref_load_u64() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
 
         let val1: u64 = 0xaa55aa55aa55aa55;
         let val2: u64 = 0x55aa55aa55aa55aa;
@@ -820,7 +1890,7 @@ mod tests {
     fn test_ref_store_u64() {
         let start_addr1 = GuestAddress(0x0);
         let start_addr2 = GuestAddress(0x1000);
-        let gm = GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
+        let gm = GuestMemory::new(&[(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
 
         let val1: u64 = 0xaa55aa55aa55aa55;
         let val2: u64 = 0x55aa55aa55aa55aa;
@@ -838,11 +1908,8 @@ mod tests {
         let size_region1 = 0x1000;
         let start_region2 = GuestAddress(0x10000);
         let size_region2 = 0x2000;
-        let gm = GuestMemory::new(&vec![
-            (start_region1, size_region1),
-            (start_region2, size_region2),
-        ])
-        .unwrap();
+        let gm = GuestMemory::new(&[(start_region1, size_region1), (start_region2, size_region2)])
+            .unwrap();
 
         let mem_size = gm.memory_size();
         assert_eq!(mem_size, size_region1 + size_region2);
@@ -21,6 +21,11 @@ use std::slice::{from_raw_parts, from_raw_parts_mut};
 /// any type that includes a reference.
 ///
 /// Implementing this trait guarantees that it is safe to instantiate the struct with random data.
+///
+/// # Safety
+///
+/// Implementors must guarantee that any bit pattern of `size_of::<Self>()` bytes is a valid value
+/// of `Self`.
 pub unsafe trait DataInit: Copy + Send + Sync {
     /// Converts a slice of raw data into a reference of `Self`.
     ///
@@ -132,17 +137,36 @@ data_init_type!(i32);
 data_init_type!(i64);
 data_init_type!(isize);
 
+#[macro_use]
+mod address;
 mod address_space;
+mod dirty_bitmap;
 mod guest_address;
 mod guest_memory;
+mod migration;
 mod mmap;
+mod snapshot;
+mod stream;
 mod volatile_memory;
 
+pub use address::{Address, FileOffset, IovaAddress, MmioAddress};
 pub use address_space::{
-    AddressRegion, AddressRegionType, AddressSpace, Error as AddressSpaceError,
+    AddressRegion, AddressRegionId, AddressRegionType, AddressSpace, AddressSpaceEvent,
+    AddressSpaceListener, AddressSpaceSnapshot, AllocPolicy, Error as AddressSpaceError,
+    ListenerId, PageSizePolicy,
 };
-pub use guest_address::GuestAddress;
+pub use dirty_bitmap::{DirtyBitmap, DirtyLog};
+pub use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
 pub use guest_memory::Error as GuestMemoryError;
-pub use guest_memory::GuestMemory;
-pub use mmap::{Error as MemoryMappingError, MemoryMapping};
+pub use guest_memory::{DirtyGuard, GuestMemory, MemoryRegion};
+pub use migration::{
+    Error as MigrationError, MigrationReceiver, MigrationSender, MIGRATION_MAGIC, MIGRATION_VERSION,
+};
+pub use mmap::{
+    Error as MemoryMappingError, MemoryMapping, MemoryMappingBuilder, MemoryReservation, Protection,
+};
+pub use snapshot::{
+    merge_snapshots, snapshot_checksum, Error as SnapshotError, DIFF_SNAPSHOT_VERSION,
+    SNAPSHOT_MAGIC, SNAPSHOT_VERSION, SPARSE_SNAPSHOT_VERSION,
+};
 pub use volatile_memory::*;
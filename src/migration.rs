@@ -0,0 +1,544 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Migrate guest memory to another process while the virtual machine keeps running.
+//!
+//! The sender first sends all the guest memory, then repeatedly sends the pages written by the
+//! running virtual machine since the previous round, until few enough pages are written in a
+//! round. The virtual machine is then paused and the last pages written are sent.
+//!
+//! The migration stream starts with a header describing the layout of the guest memory, made of
+//! a magic number, a version, a region count and the guest base and size of each region. It is
+//! followed by frames, each made of a 24 bytes header, frame kind, region index, offset in the
+//! region and length, and for page frames of the contents of the pages. All integers are stored in
+//! little endian.
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::result;
+
+use address::Address;
+use dirty_bitmap::DirtyLog;
+use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
+use guest_memory::{Error as GuestMemoryError, GuestMemory};
+use mmap::host_size;
+use stream::{
+    self, check_layout, encode_header, page_runs, read_error, read_u32, read_u64,
+    require_dirty_tracking, StreamError,
+};
+
+/// Magic number identifying a guest memory migration stream.
+pub const MIGRATION_MAGIC: [u8; 8] = *b"GMEMMIGR";
+/// Version of the migration protocol.
+pub const MIGRATION_VERSION: u32 = 1;
+
+// Kinds of frames of the migration stream.
+const FRAME_PAGES: u32 = 1;
+const FRAME_END_OF_ROUND: u32 = 2;
+const FRAME_DONE: u32 = 3;
+
+/// Errors associated with migrating guest memory.
+#[derive(Debug)]
+pub enum Error {
+    /// Failure in reading or writing the migration stream.
+    Io(io::Error),
+    /// Failure in accessing guest memory.
+    GuestMemory(GuestMemoryError),
+    /// The migration stream doesn't start with the migration magic number.
+    InvalidMagic,
+    /// The migration stream uses an unsupported version of the protocol.
+    UnsupportedVersion(u32),
+    /// The memory region at the given address doesn't track dirty pages.
+    NoDirtyTracking(GuestAddress),
+    /// The sender and the receiver have a different number of memory regions.
+    RegionCountMismatch {
+        /// Number of memory regions of the receiver.
+        expected: usize,
+        /// Number of memory regions of the sender.
+        found: usize,
+    },
+    /// A memory region of the sender doesn't match the memory region of the receiver.
+    RegionMismatch {
+        /// Memory region of the receiver.
+        expected: GuestAddressRange,
+        /// Memory region of the sender.
+        found: GuestAddressRange,
+    },
+    /// A frame of an unknown kind was received.
+    InvalidFrame(u32),
+    /// A frame references a memory region which doesn't exist.
+    InvalidRegion(u32),
+    /// A frame references pages past the end of their memory region.
+    InvalidPageRange {
+        /// Index of the memory region.
+        region: u32,
+        /// Offset of the pages in the memory region.
+        offset: u64,
+        /// Length of the pages.
+        len: u64,
+    },
+    /// The migration stream ended before the migration was done.
+    Truncated,
+}
+type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guest memory migration error: ")?;
+        match self {
+            Error::Io(e) => write!(f, "failed to access the migration stream: {}", e),
+            Error::GuestMemory(e) => write!(f, "{}", e),
+            Error::InvalidMagic => write!(f, "invalid magic number"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Error::NoDirtyTracking(base) => write!(
+                f,
+                "memory region at {:#x} doesn't track dirty pages",
+                base.offset()
+            ),
+            Error::RegionCountMismatch { expected, found } => write!(
+                f,
+                "sender has {} memory regions instead of {}",
+                found, expected
+            ),
+            Error::RegionMismatch { expected, found } => write!(
+                f,
+                "sender has memory region base {:#x}/size {:#x} instead of base {:#x}/size {:#x}",
+                found.start().offset(),
+                found.len(),
+                expected.start().offset(),
+                expected.len(),
+            ),
+            Error::InvalidFrame(kind) => write!(f, "invalid frame kind {}", kind),
+            Error::InvalidRegion(region) => write!(f, "invalid memory region {}", region),
+            Error::InvalidPageRange {
+                region,
+                offset,
+                len,
+            } => write!(
+                f,
+                "invalid pages offset {:#x}/length {:#x} in memory region {}",
+                offset, len, region
+            ),
+            Error::Truncated => write!(f, "migration stream is truncated"),
+        }
+    }
+}
+
+impl StreamError for Error {
+    fn io(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+
+    fn truncated() -> Self {
+        Error::Truncated
+    }
+
+    fn guest_memory(e: GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+
+    fn invalid_magic() -> Self {
+        Error::InvalidMagic
+    }
+
+    fn region_count_mismatch(expected: usize, found: usize) -> Self {
+        Error::RegionCountMismatch { expected, found }
+    }
+
+    fn region_mismatch(expected: GuestAddressRange, found: GuestAddressRange) -> Self {
+        Error::RegionMismatch { expected, found }
+    }
+}
+
+// Converts a failure of reading the migration stream, reporting truncation distinctly.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        read_error(e)
+    }
+}
+
+// Converts a failure of sending guest memory to the migration stream.
+fn save_error(e: GuestMemoryError) -> Error {
+    stream::save_error(e)
+}
+
+// Converts a failure of receiving guest memory, reporting truncation of the stream distinctly.
+fn restore_error(e: GuestMemoryError) -> Error {
+    stream::restore_error(e)
+}
+
+/// Sends guest memory to a `MigrationReceiver` through `dst`.
+///
+/// All the memory regions of the guest memory must track dirty pages.
+///
+/// # Examples
+///
+/// ```
+/// # use memory_model::{GuestAddress, GuestMemory, MigrationReceiver, MigrationSender};
+/// # use std::os::unix::net::UnixStream;
+/// # use std::thread;
+/// let ranges = [(GuestAddress(0x0), 0x10000)];
+/// let (tx, rx) = UnixStream::pair().unwrap();
+/// let receiver = thread::spawn(move || {
+///     let mem = GuestMemory::new(&ranges).unwrap();
+///     MigrationReceiver::new(&mem, rx).unwrap().receive().unwrap();
+///     mem.read_obj_from_addr::<u64>(GuestAddress(0x1000)).unwrap()
+/// });
+///
+/// let mem = GuestMemory::new_with_dirty_tracking(&ranges, 0x1000).unwrap();
+/// let mut sender = MigrationSender::new(&mem, tx).unwrap();
+/// sender.precopy(10, 0x4000).unwrap();
+/// // Pause the virtual machine before sending the last pages.
+/// mem.write_obj_at_addr(0x55aa_u64, GuestAddress(0x1000)).unwrap();
+/// sender.finish().unwrap();
+/// assert_eq!(receiver.join().unwrap(), 0x55aa);
+/// ```
+pub struct MigrationSender<'a, W: Write> {
+    mem: &'a GuestMemory,
+    // Ranges of the memory regions, with the size of their dirty pages.
+    regions: Vec<(GuestAddressRange, GuestUsize)>,
+    // Pages written since the previous round, regardless of the other consumers of dirty pages.
+    log: DirtyLog,
+    dst: W,
+    rounds: usize,
+}
+
+impl<'a, W: Write> MigrationSender<'a, W> {
+    /// Starts the migration of `mem` by sending its layout to `dst`.
+    pub fn new(mem: &'a GuestMemory, mut dst: W) -> Result<Self> {
+        let regions = require_dirty_tracking(mem).map_err(Error::NoDirtyTracking)?;
+        let ranges: Vec<GuestAddressRange> = regions.iter().map(|(range, _)| *range).collect();
+        let header = encode_header(&MIGRATION_MAGIC, MIGRATION_VERSION, &ranges, None);
+        dst.write_all(&header).map_err(Error::Io)?;
+        Ok(MigrationSender {
+            mem,
+            regions,
+            log: mem.dirty_log(),
+            dst,
+            rounds: 0,
+        })
+    }
+
+    /// Returns the number of pre-copy rounds sent.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Sends a pre-copy round, while the virtual machine keeps running, and returns the number of
+    /// bytes of guest memory sent.
+    ///
+    /// The first round sends all the guest memory, and each following round the pages written
+    /// since the previous round.
+    pub fn send_round(&mut self) -> Result<GuestUsize> {
+        let sent = self.send_pages()?;
+        self.send_frame(FRAME_END_OF_ROUND, 0, 0, 0)?;
+        self.dst.flush().map_err(Error::Io)?;
+        self.rounds += 1;
+        Ok(sent)
+    }
+
+    /// Sends pre-copy rounds until a round sends at most `max_remaining` bytes of guest memory,
+    /// or `max_rounds` rounds were sent, and returns whether the last round did converge.
+    pub fn precopy(&mut self, max_rounds: usize, max_remaining: GuestUsize) -> Result<bool> {
+        while self.rounds < max_rounds {
+            if self.send_round()? <= max_remaining {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Sends the pages written since the last pre-copy round, all the guest memory if none was
+    /// sent, and ends the migration.
+    ///
+    /// The virtual machine must be paused, so no more guest memory gets written.
+    pub fn finish(mut self) -> Result<W> {
+        self.send_pages()?;
+        self.send_frame(FRAME_DONE, 0, 0, 0)?;
+        self.dst.flush().map_err(Error::Io)?;
+        Ok(self.dst)
+    }
+
+    fn send_frame(&mut self, kind: u32, region: u32, offset: u64, len: u64) -> Result<()> {
+        let mut frame = [0u8; 24];
+        frame[0..4].copy_from_slice(&kind.to_le_bytes());
+        frame[4..8].copy_from_slice(&region.to_le_bytes());
+        frame[8..16].copy_from_slice(&offset.to_le_bytes());
+        frame[16..24].copy_from_slice(&len.to_le_bytes());
+        self.dst.write_all(&frame).map_err(Error::Io)
+    }
+
+    // Sends the pages to migrate in the current round, and returns the number of bytes sent.
+    fn send_pages(&mut self) -> Result<GuestUsize> {
+        let mut sent = 0;
+        if self.rounds == 0 {
+            // Pages written from now on are sent again in the next round.
+            self.log = self.mem.dirty_log();
+        }
+        for index in 0..self.regions.len() {
+            let (range, page_size) = self.regions[index];
+            let runs = if self.rounds == 0 {
+                vec![(range.start(), range.len())]
+            } else {
+                let pages = self.mem.fetch_and_clear_dirty_log(&self.log, &range);
+                page_runs(&pages, page_size, &range)
+            };
+            for (addr, len) in runs {
+                let offset = addr.offset_from(range.start());
+                self.send_frame(FRAME_PAGES, index as u32, offset, len)?;
+                self.mem
+                    .write_from_memory(addr, &mut self.dst, len as usize)
+                    .map_err(save_error)?;
+                sent += len;
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// Receives guest memory from a `MigrationSender` through `src`.
+///
+/// Pages are written directly to the guest memory as they are received.
+pub struct MigrationReceiver<'a, R: Read> {
+    mem: &'a GuestMemory,
+    ranges: Vec<GuestAddressRange>,
+    src: R,
+    rounds: usize,
+}
+
+impl<'a, R: Read> MigrationReceiver<'a, R> {
+    /// Starts the migration to `mem` by receiving the layout of the guest memory of the sender
+    /// from `src`, which must match the layout of `mem`.
+    pub fn new(mem: &'a GuestMemory, mut src: R) -> Result<Self> {
+        let version = stream::read_version::<_, Error>(&mut src, &MIGRATION_MAGIC)?;
+        if version != MIGRATION_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let count = read_u32(&mut src)?;
+        let mut found = Vec::new();
+        for _ in 0..count {
+            let base = GuestAddress(read_u64(&mut src)?);
+            found.push(GuestAddressRange::new(base, read_u64(&mut src)?));
+        }
+        let ranges = check_layout::<Error>(mem, &found)?;
+        Ok(MigrationReceiver {
+            mem,
+            ranges,
+            src,
+            rounds: 0,
+        })
+    }
+
+    /// Returns the number of pre-copy rounds received.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Receives a pre-copy round, and returns whether more rounds follow or the migration is done.
+    pub fn receive_round(&mut self) -> Result<bool> {
+        loop {
+            let kind = read_u32(&mut self.src)?;
+            let region = read_u32(&mut self.src)?;
+            let offset = read_u64(&mut self.src)?;
+            let len = read_u64(&mut self.src)?;
+            match kind {
+                FRAME_PAGES => self.receive_pages(region, offset, len)?,
+                FRAME_END_OF_ROUND => {
+                    self.rounds += 1;
+                    return Ok(true);
+                }
+                FRAME_DONE => return Ok(false),
+                kind => return Err(Error::InvalidFrame(kind)),
+            }
+        }
+    }
+
+    /// Receives all the rounds until the migration is done.
+    pub fn receive(mut self) -> Result<R> {
+        while self.receive_round()? {}
+        Ok(self.src)
+    }
+
+    fn receive_pages(&mut self, region: u32, offset: u64, len: u64) -> Result<()> {
+        let range = *self
+            .ranges
+            .get(region as usize)
+            .ok_or(Error::InvalidRegion(region))?;
+        let invalid = Error::InvalidPageRange {
+            region,
+            offset,
+            len,
+        };
+        match offset.checked_add(len) {
+            Some(end) if end <= range.len() => {}
+            _ => return Err(invalid),
+        }
+        let count = host_size(len).map_err(|_| invalid)?;
+        self.mem
+            .read_to_memory(range.start().unchecked_add(offset), &mut self.src, count)
+            .map_err(restore_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const PAGE_SIZE: GuestUsize = 0x1000;
+
+    fn ranges() -> Vec<(GuestAddress, GuestUsize)> {
+        vec![
+            (GuestAddress(0x0), 0x10_0000),
+            (GuestAddress(0x1000_0000), 0x8000),
+        ]
+    }
+
+    #[test]
+    fn precopy_migration() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let receiver = thread::spawn(move || {
+            let mem = GuestMemory::new(&ranges()).unwrap();
+            let mut receiver = MigrationReceiver::new(&mem, rx).unwrap();
+            while receiver.receive_round().unwrap() {}
+            let rounds = receiver.rounds();
+            let mut contents = vec![0u8; 0x10_8000];
+            mem.read_exact_at_addr(&mut contents[..0x10_0000], GuestAddress(0x0))
+                .unwrap();
+            mem.read_exact_at_addr(&mut contents[0x10_0000..], GuestAddress(0x1000_0000))
+                .unwrap();
+            (rounds, contents)
+        });
+
+        let mem = GuestMemory::new_with_dirty_tracking(&ranges(), PAGE_SIZE as usize).unwrap();
+        mem.write_all_at_addr(&[0x11; 0x3000], GuestAddress(0x0))
+            .unwrap();
+        let mut sender = MigrationSender::new(&mem, tx).unwrap();
+        assert_eq!(sender.send_round().unwrap(), 0x10_8000);
+
+        // Written while the first round was sent.
+        mem.write_obj_at_addr(0x22_u8, GuestAddress(0x5fff))
+            .unwrap();
+        mem.write_obj_at_addr(0x33_u16, GuestAddress(0x6fff))
+            .unwrap();
+        mem.write_obj_at_addr(0x44_u8, GuestAddress(0x1000_7fff))
+            .unwrap();
+        assert_eq!(sender.send_round().unwrap(), 4 * PAGE_SIZE);
+        assert_eq!(sender.send_round().unwrap(), 0);
+        assert!(sender.precopy(4, 0).unwrap());
+        assert_eq!(sender.rounds(), 4);
+
+        // Stop and copy.
+        mem.write_obj_at_addr(0x55_u8, GuestAddress(0x0)).unwrap();
+        sender.finish().unwrap();
+
+        let mut expected = vec![0u8; 0x10_8000];
+        mem.read_exact_at_addr(&mut expected[..0x10_0000], GuestAddress(0x0))
+            .unwrap();
+        mem.read_exact_at_addr(&mut expected[0x10_0000..], GuestAddress(0x1000_0000))
+            .unwrap();
+        let (rounds, contents) = receiver.join().unwrap();
+        assert_eq!(rounds, 4);
+        assert!(contents == expected);
+        assert_eq!(contents[0], 0x55);
+        assert_eq!(contents[0x10_7fff], 0x44);
+    }
+
+    #[test]
+    fn stop_and_copy_only() {
+        let mem = GuestMemory::new_with_dirty_tracking(&ranges(), PAGE_SIZE as usize).unwrap();
+        mem.write_obj_at_addr(0x1234_u16, GuestAddress(0x1000_0010))
+            .unwrap();
+        let stream = MigrationSender::new(&mem, Vec::new())
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let restored = GuestMemory::new(&ranges()).unwrap();
+        let mut receiver = MigrationReceiver::new(&restored, stream.as_slice()).unwrap();
+        assert!(!receiver.receive_round().unwrap());
+        assert_eq!(receiver.rounds(), 0);
+        let val: u16 = restored
+            .read_obj_from_addr(GuestAddress(0x1000_0010))
+            .unwrap();
+        assert_eq!(val, 0x1234);
+    }
+
+    #[test]
+    fn other_consumers_of_dirty_pages() {
+        let mem = GuestMemory::new_with_dirty_tracking(&ranges(), PAGE_SIZE as usize).unwrap();
+        let mut sender = MigrationSender::new(&mem, Vec::new()).unwrap();
+        sender.send_round().unwrap();
+
+        mem.write_obj_at_addr(0x11_u8, GuestAddress(0x2000))
+            .unwrap();
+        mem.clear_dirty_pages();
+        assert_eq!(sender.send_round().unwrap(), PAGE_SIZE);
+    }
+
+    #[test]
+    fn invalid_streams() {
+        let mem = GuestMemory::new(&ranges()).unwrap();
+        match MigrationSender::new(&mem, Vec::new()) {
+            Err(Error::NoDirtyTracking(GuestAddress(0))) => {}
+            _ => panic!("dirty tracking is required"),
+        }
+
+        let sender_mem =
+            GuestMemory::new_with_dirty_tracking(&ranges(), PAGE_SIZE as usize).unwrap();
+        let stream = MigrationSender::new(&sender_mem, Vec::new())
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let other = GuestMemory::new(&[
+            (GuestAddress(0x0), 0x10_0000),
+            (GuestAddress(0x2000_0000), 0x8000),
+        ])
+        .unwrap();
+        match MigrationReceiver::new(&other, stream.as_slice()) {
+            Err(Error::RegionMismatch { found, .. }) => {
+                assert_eq!(
+                    found,
+                    GuestAddressRange::new(GuestAddress(0x1000_0000), 0x8000)
+                )
+            }
+            _ => panic!("layouts don't match"),
+        }
+
+        let mut receiver = MigrationReceiver::new(&mem, &stream[..0x1000]).unwrap();
+        match receiver.receive_round() {
+            Err(Error::Truncated) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        // Pages past the end of the second region.
+        let header_len = 16 + 2 * 16;
+        let mut bad = stream[..header_len].to_vec();
+        bad.extend_from_slice(&FRAME_PAGES.to_le_bytes());
+        bad.extend_from_slice(&1u32.to_le_bytes());
+        bad.extend_from_slice(&0x7000u64.to_le_bytes());
+        bad.extend_from_slice(&0x2000u64.to_le_bytes());
+        let mut receiver = MigrationReceiver::new(&mem, bad.as_slice()).unwrap();
+        match receiver.receive_round() {
+            Err(Error::InvalidPageRange {
+                region: 1,
+                offset: 0x7000,
+                len: 0x2000,
+            }) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        let mut bad = stream[..header_len].to_vec();
+        bad.extend_from_slice(&[0xff; 24]);
+        let mut receiver = MigrationReceiver::new(&mem, bad.as_slice()).unwrap();
+        match receiver.receive_round() {
+            Err(Error::InvalidFrame(0xffff_ffff)) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+}
@@ -9,9 +9,11 @@
 //! mmap object leaves scope.
 
 use std;
+use std::fs::File;
 use std::io::{self, Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::ptr::null_mut;
+use std::sync::{Arc, Mutex};
 
 use libc;
 
@@ -25,6 +27,8 @@ pub enum Error {
     InvalidAddress,
     /// Requested offset is out of range of `libc::off_t`.
     InvalidOffset,
+    /// Requested size can't be represented in the address space of the current process.
+    InvalidSize(u64),
     /// Requested memory range spans past the end of the region.
     InvalidRange(usize, usize),
     /// Couldn't read from the given source.
@@ -35,14 +39,608 @@ pub enum Error {
     WriteToMemory(io::Error),
     /// Reading from memory failed
     ReadFromMemory(io::Error),
+    /// Writing to a read-only mapping.
+    WriteProtected,
+    /// Requested huge page size isn't a power of two, or the size of the mapping isn't a multiple
+    /// of it.
+    InvalidHugePageSize(usize),
+    /// Requested range of a memory reservation is already mapped.
+    RangeInUse(usize, usize),
 }
 type Result<T> = std::result::Result<T, Error>;
 
+/// Converts a size in the guest's address space into a size in the current process.
+///
+/// Guest sizes are always 64-bit, but a 32-bit VMM process can't map more than `usize::MAX`
+/// bytes at once.
+pub fn host_size(size: u64) -> Result<usize> {
+    if size > usize::MAX as u64 {
+        return Err(Error::InvalidSize(size));
+    }
+    Ok(size as usize)
+}
+
+/// Returns the size of the pages of the current process.
+pub fn host_page_size() -> usize {
+    // This is safe because sysconf() doesn't access memory.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Creates an anonymous memory file of `size` bytes with `memfd_create`.
+///
+/// The file is sealed so that it can't shrink or grow, and no more seals may be added, which makes
+/// it safe to map by processes it's shared with. It's backed by huge pages of `huge_page_size`
+/// bytes if given.
+pub fn create_sealed_memfd(size: u64, huge_page_size: Option<usize>) -> Result<File> {
+    if size > libc::off_t::MAX as u64 {
+        return Err(Error::InvalidSize(size));
+    }
+    let mut flags = libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING;
+    if let Some(page_size) = huge_page_size {
+        if !page_size.is_power_of_two() || size % page_size as u64 != 0 {
+            return Err(Error::InvalidHugePageSize(page_size));
+        }
+        flags |= libc::MFD_HUGETLB | (page_size.trailing_zeros() << libc::MFD_HUGE_SHIFT);
+    }
+    // This is safe because the name is a valid C string and memfd_create doesn't access any
+    // other memory.
+    let fd =
+        unsafe { libc::memfd_create(b"guest-memory\0".as_ptr() as *const libc::c_char, flags) };
+    if fd < 0 {
+        return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+    }
+    // This is safe because we own the new file descriptor.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size).map_err(Error::SystemCallFailed)?;
+    // This is safe because fcntl(F_ADD_SEALS) doesn't access memory.
+    let ret = unsafe {
+        libc::fcntl(
+            file.as_raw_fd(),
+            libc::F_ADD_SEALS,
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_SEAL,
+        )
+    };
+    if ret < 0 {
+        return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+    }
+    Ok(file)
+}
+
+// Reserves `size` bytes of inaccessible address space at `addr`, or anywhere if `addr` is null.
+fn reserve_range(addr: *mut u8, size: usize) -> Result<*mut u8> {
+    let mut flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+    if !addr.is_null() {
+        flags |= libc::MAP_FIXED;
+    }
+    // This is safe because nothing can access the reserved range, and the callers only pass a
+    // non-null `addr` for ranges they reserved themselves.
+    let addr = unsafe {
+        libc::mmap(
+            addr as *mut libc::c_void,
+            size,
+            libc::PROT_NONE,
+            flags,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+    }
+    Ok(addr as *mut u8)
+}
+
+// Calls the positioned I/O system call `f` until `count` bytes are done starting at `file_offset`,
+// or it transfers no more bytes, and returns the number of bytes done.
+//
+// `f` gets the number of bytes already done and the file offset to continue at.
+fn retry_at<F>(file_offset: u64, count: usize, mut f: F) -> io::Result<usize>
+where
+    F: FnMut(usize, libc::off_t) -> libc::ssize_t,
+{
+    let mut done = 0;
+    while done < count {
+        let offset = match file_offset.checked_add(done as u64) {
+            Some(offset) if offset <= libc::off_t::MAX as u64 => offset as libc::off_t,
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let ret = f(done, offset);
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if ret == 0 {
+            break;
+        }
+        done += ret as usize;
+    }
+    Ok(done)
+}
+
+/// Access permissions of a memory mapping in the current process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    /// The mapping may only be read.
+    ReadOnly,
+    /// The mapping may be read and written.
+    ReadWrite,
+}
+
+impl Protection {
+    /// Checks whether the mapping may be written.
+    pub fn is_writable(self) -> bool {
+        self == Protection::ReadWrite
+    }
+
+    fn as_prot(self) -> libc::c_int {
+        match self {
+            Protection::ReadOnly => libc::PROT_READ,
+            Protection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+        }
+    }
+}
+
+/// Builds a memory mapping with the given mmap options.
+///
+/// By default the mapping is anonymous, shared and readable and writable, and swap space is
+/// reserved for it.
+///
+/// # Examples
+///
+/// ```
+/// # use memory_model::{MemoryMappingBuilder, Protection};
+/// let mem_map = MemoryMappingBuilder::new(0x10000)
+///     .private(true)
+///     .populate(true)
+///     .protection(Protection::ReadOnly)
+///     .build()
+///     .unwrap();
+/// assert_eq!(mem_map.read_obj::<u64>(0x8000).unwrap(), 0);
+/// ```
+pub struct MemoryMappingBuilder<'a> {
+    size: usize,
+    fd: Option<(&'a dyn AsRawFd, u64)>,
+    prot: Protection,
+    private: bool,
+    populate: bool,
+    hugetlb: Option<Option<usize>>,
+    noreserve: bool,
+    locked: bool,
+    fixed_addr: Option<*mut u8>,
+    guard_pages: bool,
+    reservation: Option<(Arc<MemoryReservation>, usize)>,
+}
+
+impl<'a> MemoryMappingBuilder<'a> {
+    /// Starts building a mapping of `size` bytes.
+    pub fn new(size: usize) -> MemoryMappingBuilder<'a> {
+        MemoryMappingBuilder {
+            size,
+            fd: None,
+            prot: Protection::ReadWrite,
+            private: false,
+            populate: false,
+            hugetlb: None,
+            noreserve: false,
+            locked: false,
+            fixed_addr: None,
+            guard_pages: false,
+            reservation: None,
+        }
+    }
+
+    /// Maps `fd` starting at `offset` bytes instead of anonymous memory.
+    pub fn from_fd(mut self, fd: &'a dyn AsRawFd, offset: u64) -> MemoryMappingBuilder<'a> {
+        self.fd = Some((fd, offset));
+        self
+    }
+
+    /// Sets the access permissions of the mapping.
+    pub fn protection(mut self, prot: Protection) -> MemoryMappingBuilder<'a> {
+        self.prot = prot;
+        self
+    }
+
+    /// Makes the mapping copy-on-write (`MAP_PRIVATE`) rather than shared (`MAP_SHARED`).
+    ///
+    /// Writes to a private mapping are neither seen by other processes nor carried to the mapped
+    /// file.
+    pub fn private(mut self, private: bool) -> MemoryMappingBuilder<'a> {
+        self.private = private;
+        self
+    }
+
+    /// Faults in all the pages of the mapping when it is created (`MAP_POPULATE`).
+    pub fn populate(mut self, populate: bool) -> MemoryMappingBuilder<'a> {
+        self.populate = populate;
+        self
+    }
+
+    /// Backs the mapping with huge pages (`MAP_HUGETLB`) of `page_size` bytes, or of the default
+    /// huge page size of the system if `page_size` is `None`.
+    ///
+    /// The size of the mapping must be a multiple of the huge page size.
+    pub fn hugetlb(mut self, page_size: Option<usize>) -> MemoryMappingBuilder<'a> {
+        self.hugetlb = Some(page_size);
+        self
+    }
+
+    /// Doesn't reserve swap space for the mapping (`MAP_NORESERVE`).
+    pub fn noreserve(mut self, noreserve: bool) -> MemoryMappingBuilder<'a> {
+        self.noreserve = noreserve;
+        self
+    }
+
+    /// Locks the pages of the mapping in memory (`MAP_LOCKED`), subject to `RLIMIT_MEMLOCK`.
+    pub fn locked(mut self, locked: bool) -> MemoryMappingBuilder<'a> {
+        self.locked = locked;
+        self
+    }
+
+    /// Places the mapping at `addr` (`MAP_FIXED`), replacing whatever was mapped there before.
+    ///
+    /// The mapping owns the range from then on and unmaps it when dropped.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be page aligned, and nothing else may use the `size` bytes starting at `addr`,
+    /// typically because they were reserved by the caller.
+    pub unsafe fn fixed_address(mut self, addr: *mut u8) -> MemoryMappingBuilder<'a> {
+        self.fixed_addr = Some(addr);
+        self
+    }
+
+    /// Surrounds the mapping with inaccessible guard areas, so that accessing memory just before
+    /// or after the mapping crashes instead of silently hitting whatever the kernel placed
+    /// there.
+    ///
+    /// The guard areas are one page large, or one huge page for mappings backed by huge pages,
+    /// whose size must then be given to `hugetlb()`. Guard pages can't be combined with
+    /// `fixed_address()` or `in_reservation()`.
+    pub fn guard_pages(mut self, guard_pages: bool) -> MemoryMappingBuilder<'a> {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Places the mapping at `offset` bytes into `reservation`, which must not be used by any
+    /// other mapping.
+    ///
+    /// The range is made inaccessible again when the mapping is dropped, and the reservation is
+    /// kept alive as long as the mapping.
+    pub fn in_reservation(
+        mut self,
+        reservation: Arc<MemoryReservation>,
+        offset: usize,
+    ) -> MemoryMappingBuilder<'a> {
+        self.reservation = Some((reservation, offset));
+        self
+    }
+
+    // Reserves an inaccessible range for the mapping and its guard areas, and returns it with the
+    // address to place the mapping at.
+    fn reserve_guarded(&self) -> Result<((*mut u8, usize), *mut u8)> {
+        let align = match self.hugetlb {
+            Some(Some(page_size)) => page_size,
+            Some(None) => return Err(Error::InvalidHugePageSize(0)),
+            None => host_page_size(),
+        };
+        // The reservation is only aligned to the host page size, make room to align the mapping
+        // to the huge page size.
+        let size = self
+            .size
+            .checked_add(3 * align - host_page_size())
+            .ok_or(Error::InvalidSize(self.size as u64))?;
+        let addr = reserve_range(null_mut(), size)?;
+        let start = (addr as usize + align).next_multiple_of(align);
+        Ok(((addr, size), start as *mut u8))
+    }
+
+    fn flags(&self) -> Result<libc::c_int> {
+        let mut flags = if self.private {
+            libc::MAP_PRIVATE
+        } else {
+            libc::MAP_SHARED
+        };
+        if self.fd.is_none() {
+            flags |= libc::MAP_ANONYMOUS;
+        }
+        if self.populate {
+            flags |= libc::MAP_POPULATE;
+        }
+        if let Some(page_size) = self.hugetlb {
+            flags |= libc::MAP_HUGETLB;
+            if let Some(page_size) = page_size {
+                if !page_size.is_power_of_two() || self.size % page_size != 0 {
+                    return Err(Error::InvalidHugePageSize(page_size));
+                }
+                flags |= (page_size.trailing_zeros() as libc::c_int) << libc::MAP_HUGE_SHIFT;
+            }
+        }
+        if self.noreserve {
+            flags |= libc::MAP_NORESERVE;
+        }
+        if self.locked {
+            flags |= libc::MAP_LOCKED;
+        }
+        if self.fixed_addr.is_some() {
+            flags |= libc::MAP_FIXED;
+        }
+        Ok(flags)
+    }
+
+    /// Creates the mapping.
+    pub fn build(self) -> Result<MemoryMapping> {
+        let flags = self.flags()?;
+        let shmem =
+            !self.private && self.hugetlb.is_none() && self.fd.map_or(true, |(fd, _)| is_tmpfs(fd));
+        let (fd, offset) = match self.fd {
+            Some((fd, offset)) => {
+                if offset > libc::off_t::MAX as u64 {
+                    return Err(Error::InvalidOffset);
+                }
+                (fd.as_raw_fd(), offset as libc::off_t)
+            }
+            None => (-1, 0),
+        };
+        let placements = [
+            self.fixed_addr.is_some(),
+            self.guard_pages,
+            self.reservation.is_some(),
+        ];
+        if placements.iter().filter(|placed| **placed).count() > 1 {
+            return Err(Error::InvalidAddress);
+        }
+        let (addr, flags, reserved) = match (self.fixed_addr, &self.reservation) {
+            (Some(addr), _) => (addr, flags, None),
+            (None, Some((reservation, offset))) => {
+                let addr = reservation.claim(*offset, self.size)?;
+                (addr, flags | libc::MAP_FIXED, None)
+            }
+            (None, None) if self.guard_pages => {
+                let (reserved, addr) = self.reserve_guarded()?;
+                (addr, flags | libc::MAP_FIXED, Some(reserved))
+            }
+            (None, None) => (null_mut(), flags, None),
+        };
+        // This is safe because we are creating a mapping either in a place not already used by
+        // any other area in this process, in a range we reserved ourselves, or in a place the
+        // caller of fixed_address() vouched for.
+        let addr = unsafe {
+            libc::mmap(
+                addr as *mut libc::c_void,
+                self.size,
+                self.prot.as_prot(),
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            if let Some((addr, size)) = reserved {
+                // This is safe because nobody else uses the range we reserved.
+                unsafe { libc::munmap(addr as *mut libc::c_void, size) };
+            }
+            if let Some((ref reservation, offset)) = self.reservation {
+                reservation.release(offset, self.size);
+            }
+            return Err(Error::SystemCallFailed(err));
+        }
+        Ok(MemoryMapping {
+            addr: addr as *mut u8,
+            size: self.size,
+            prot: self.prot,
+            anonymous: self.fd.is_none(),
+            shmem,
+            reserved,
+            reservation: self.reservation.map(|(reservation, _)| reservation),
+        })
+    }
+}
+
+// Checks whether `fd` is a file of tmpfs, backed by shmem as memfds are.
+fn is_tmpfs(fd: &dyn AsRawFd) -> bool {
+    // This is safe because the kernel only writes to the structure, which is large enough.
+    unsafe {
+        let mut buf: libc::statfs = std::mem::zeroed();
+        libc::fstatfs(fd.as_raw_fd(), &mut buf) == 0 && buf.f_type == libc::TMPFS_MAGIC as _
+    }
+}
+
+/// Areas of the address space of the current process holding swapped out pages, as reported by
+/// /proc/self/smaps.
+///
+/// Reading smaps costs as much as the process has memory areas, so the areas are read once for
+/// all the mappings scanned with `MemoryMapping::zero_pages_with()`. If smaps can't be read or
+/// parsed, and by default, all the areas are assumed to hold swapped out pages.
+#[derive(Default)]
+pub(crate) struct SwappedAreas(Option<Vec<(usize, usize)>>);
+
+impl SwappedAreas {
+    /// Reads the areas holding swapped out pages from /proc/self/smaps.
+    pub(crate) fn read() -> Self {
+        let smaps = std::fs::read_to_string("/proc/self/smaps").ok();
+        SwappedAreas(smaps.and_then(|smaps| parse_swapped_areas(&smaps)))
+    }
+
+    // Checks whether the range from `start` to `end` may hold swapped out pages.
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        match self.0 {
+            Some(ref areas) => areas.iter().any(|(low, high)| *low < end && start < *high),
+            None => true,
+        }
+    }
+}
+
+// Returns the areas of `smaps` holding swapped out pages, or None if it can't be parsed.
+fn parse_swapped_areas(smaps: &str) -> Option<Vec<(usize, usize)>> {
+    let mut areas = Vec::new();
+    let mut area = None;
+    for line in smaps.lines() {
+        // Each area starts with a line such as "7f0000000000-7f0000200000 rw-s ...", followed by
+        // lines such as "Swap:    4 kB".
+        let range = line
+            .split(' ')
+            .next()
+            .and_then(|range| range.split_once('-'))
+            .and_then(|(low, high)| {
+                let low = usize::from_str_radix(low, 16).ok()?;
+                Some((low, usize::from_str_radix(high, 16).ok()?))
+            });
+        if range.is_some() {
+            area = range;
+            continue;
+        }
+        let (key, value) = line.split_once(':')?;
+        if key == "Swap" {
+            let kb = value
+                .trim()
+                .strip_suffix("kB")?
+                .trim()
+                .parse::<u64>()
+                .ok()?;
+            if kb > 0 {
+                areas.push(area?);
+            }
+        }
+    }
+    Some(areas)
+}
+
+/// Inaccessible range of the address space of the current process, in which memory mappings may
+/// be placed with `MemoryMappingBuilder::in_reservation()`.
+///
+/// # Examples
+///
+/// ```
+/// # use memory_model::{MemoryMappingBuilder, MemoryReservation};
+/// # use std::sync::Arc;
+/// let reservation = Arc::new(MemoryReservation::new(0x10000, 0x1000).unwrap());
+/// let mem_map = MemoryMappingBuilder::new(0x1000)
+///     .in_reservation(reservation.clone(), 0x4000)
+///     .build()
+///     .unwrap();
+/// assert_eq!(mem_map.as_ptr(), unsafe { reservation.as_ptr().add(0x4000) });
+/// ```
+#[derive(Debug)]
+pub struct MemoryReservation {
+    addr: *mut u8,
+    size: usize,
+    // Ranges of the mappings placed in the reservation, as (offset, size).
+    used: Mutex<Vec<(usize, usize)>>,
+}
+
+// Send and Sync aren't automatically inherited for the raw address pointer, which is only used
+// to place mappings in the reservation.
+unsafe impl Send for MemoryReservation {}
+unsafe impl Sync for MemoryReservation {}
+
+impl MemoryReservation {
+    /// Reserves `size` bytes of address space starting at a multiple of `align`, which must be a
+    /// power of two.
+    pub fn new(size: usize, align: usize) -> Result<MemoryReservation> {
+        if !align.is_power_of_two() {
+            return Err(Error::InvalidAddress);
+        }
+        let align = align.max(host_page_size());
+        let total = size
+            .checked_add(align - host_page_size())
+            .ok_or(Error::InvalidSize(size as u64))?;
+        let addr = reserve_range(null_mut(), total)? as usize;
+        let start = addr.next_multiple_of(align);
+        // Give back the parts of the range which aren't aligned.
+        // This is safe because nobody else uses the range we reserved.
+        unsafe {
+            if start > addr {
+                libc::munmap(addr as *mut libc::c_void, start - addr);
+            }
+            if addr + total > start + size {
+                libc::munmap(
+                    (start + size) as *mut libc::c_void,
+                    addr + total - start - size,
+                );
+            }
+        }
+        Ok(MemoryReservation {
+            addr: start as *mut u8,
+            size,
+            used: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns a pointer to the beginning of the reservation.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// Returns the size of the reservation in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // Claims `size` bytes at `offset` for a mapping, and returns the address to place it at.
+    fn claim(&self, offset: usize, size: usize) -> Result<*mut u8> {
+        if offset % host_page_size() != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        match offset.checked_add(size) {
+            Some(end) if end <= self.size => {}
+            _ => return Err(Error::InvalidRange(offset, size)),
+        }
+        // Assuming the lock is healthy otherwise we are already in trouble
+        let mut used = self.used.lock().unwrap();
+        if used
+            .iter()
+            .any(|&(start, len)| offset < start + len && start < offset + size)
+        {
+            return Err(Error::RangeInUse(offset, size));
+        }
+        used.push((offset, size));
+        // This is safe because the range was checked to be within the reservation.
+        Ok(unsafe { self.addr.add(offset) })
+    }
+
+    // Makes the range of a mapping placed at `offset` inaccessible again.
+    fn release(&self, offset: usize, size: usize) {
+        // Replace the mapping instead of unmapping it, so that the kernel doesn't place any other
+        // mapping in the range. If that fails, the range is unmapped when the reservation is
+        // dropped anyway.
+        // This is safe because the range belongs to the mapping being dropped.
+        let _ = reserve_range(unsafe { self.addr.add(offset) }, size);
+        // Assuming the lock is healthy otherwise we are already in trouble
+        self.used
+            .lock()
+            .unwrap()
+            .retain(|&(start, _)| start != offset);
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        // This is safe because all the mappings placed in the reservation hold a reference to
+        // it, so none is left.
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.size);
+        }
+    }
+}
+
 /// Wraps an anonymous shared memory mapping in the current process.
 #[derive(Debug)]
 pub struct MemoryMapping {
     addr: *mut u8,
     size: usize,
+    prot: Protection,
+    anonymous: bool,
+    // Whether the mapping shares shmem pages, which are only allocated once touched.
+    shmem: bool,
+    // Range reserved for the mapping and its guard areas, unmapped as a whole on drop.
+    reserved: Option<(*mut u8, usize)>,
+    // Reservation the mapping was placed in, which gets the range back on drop.
+    reservation: Option<Arc<MemoryReservation>>,
 }
 
 // Send and Sync aren't automatically inherited for the raw address pointer.
@@ -58,25 +656,19 @@ impl MemoryMapping {
     /// # Arguments
     /// * `size` - Size of memory region in bytes.
     pub fn new(size: usize) -> Result<MemoryMapping> {
-        // This is safe because we are creating an anonymous mapping in a place not already used by
-        // any other area in this process.
-        let addr = unsafe {
-            libc::mmap(
-                null_mut(),
-                size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-                -1,
-                0,
-            )
-        };
-        if addr == libc::MAP_FAILED {
-            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
-        }
-        Ok(MemoryMapping {
-            addr: addr as *mut u8,
-            size,
-        })
+        MemoryMapping::new_with_protection(size, Protection::ReadWrite)
+    }
+
+    /// Creates an anonymous shared mapping of `size` bytes with the given access permissions.
+    ///
+    /// # Arguments
+    /// * `size` - Size of memory region in bytes.
+    /// * `prot` - Access permissions of the mapping.
+    pub fn new_with_protection(size: usize, prot: Protection) -> Result<MemoryMapping> {
+        MemoryMappingBuilder::new(size)
+            .protection(prot)
+            .noreserve(true)
+            .build()
     }
 
     /// Maps the first `size` bytes of the given `fd`.
@@ -84,7 +676,7 @@ impl MemoryMapping {
     /// # Arguments
     /// * `fd` - File descriptor to mmap from.
     /// * `size` - Size of memory region in bytes.
-    pub fn from_fd(fd: &AsRawFd, size: usize) -> Result<MemoryMapping> {
+    pub fn from_fd(fd: &dyn AsRawFd, size: usize) -> Result<MemoryMapping> {
         MemoryMapping::from_fd_offset(fd, size, 0)
     }
 
@@ -94,29 +686,47 @@ impl MemoryMapping {
     /// * `fd` - File descriptor to mmap from.
     /// * `size` - Size of memory region in bytes.
     /// * `offset` - Offset in bytes from the beginning of `fd` to start the mmap.
-    pub fn from_fd_offset(fd: &AsRawFd, size: usize, offset: usize) -> Result<MemoryMapping> {
-        if offset > libc::off_t::max_value() as usize {
-            return Err(Error::InvalidOffset);
-        }
-        // This is safe because we are creating a mapping in a place not already used by any other
-        // area in this process.
-        let addr = unsafe {
-            libc::mmap(
-                null_mut(),
-                size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED,
-                fd.as_raw_fd(),
-                offset as libc::off_t,
-            )
-        };
-        if addr == libc::MAP_FAILED {
-            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
-        }
-        Ok(MemoryMapping {
-            addr: addr as *mut u8,
-            size,
-        })
+    pub fn from_fd_offset(fd: &dyn AsRawFd, size: usize, offset: u64) -> Result<MemoryMapping> {
+        MemoryMapping::from_fd_offset_with_protection(fd, size, offset, Protection::ReadWrite)
+    }
+
+    /// Maps the `size` bytes starting at `offset` bytes of the given `fd` with the given access
+    /// permissions.
+    ///
+    /// # Arguments
+    /// * `fd` - File descriptor to mmap from.
+    /// * `size` - Size of memory region in bytes.
+    /// * `offset` - Offset in bytes from the beginning of `fd` to start the mmap.
+    /// * `prot` - Access permissions of the mapping.
+    pub fn from_fd_offset_with_protection(
+        fd: &dyn AsRawFd,
+        size: usize,
+        offset: u64,
+        prot: Protection,
+    ) -> Result<MemoryMapping> {
+        MemoryMappingBuilder::new(size)
+            .from_fd(fd, offset)
+            .protection(prot)
+            .build()
+    }
+
+    /// Maps the `size` bytes starting at `offset` bytes of the given `fd` copy-on-write.
+    ///
+    /// Writes to the mapping are private to the current process and never reach the file.
+    ///
+    /// # Arguments
+    /// * `fd` - File descriptor to mmap from.
+    /// * `size` - Size of memory region in bytes.
+    /// * `offset` - Offset in bytes from the beginning of `fd` to start the mmap.
+    pub fn from_fd_offset_private(
+        fd: &dyn AsRawFd,
+        size: usize,
+        offset: u64,
+    ) -> Result<MemoryMapping> {
+        MemoryMappingBuilder::new(size)
+            .from_fd(fd, offset)
+            .private(true)
+            .build()
     }
 
     /// Returns a pointer to the beginning of the memory region.  Should only be
@@ -130,6 +740,111 @@ impl MemoryMapping {
         self.size
     }
 
+    /// Returns the access permissions of the memory region.
+    ///
+    /// `get_slice()` fails with `VolatileMemoryError::ReadOnly` if the region is read-only.
+    pub fn protection(&self) -> Protection {
+        self.prot
+    }
+
+    /// Checks whether the mapping is anonymous memory rather than a mapped file.
+    pub fn is_anonymous(&self) -> bool {
+        self.anonymous
+    }
+
+    /// Returns the reservation the mapping was placed in, if any.
+    pub fn reservation(&self) -> Option<&Arc<MemoryReservation>> {
+        self.reservation.as_ref()
+    }
+
+    /// Returns whether each page of the mapping is resident in memory, as reported by mincore.
+    ///
+    /// Pages of an anonymous mapping which were never touched are not resident, but neither are
+    /// the pages swapped out.
+    pub(crate) fn resident_pages(&self) -> Result<Vec<bool>> {
+        let num_pages = self.size.div_ceil(host_page_size());
+        let mut vec = vec![0u8; num_pages];
+        // This is safe because the vector has one byte for each page of the mapping, as required
+        // by mincore.
+        let ret =
+            unsafe { libc::mincore(self.addr as *mut libc::c_void, self.size, vec.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(Error::SystemCallFailed(io::Error::last_os_error()));
+        }
+        Ok(vec.into_iter().map(|v| v & 1 != 0).collect())
+    }
+
+    /// Returns whether each page of the mapping is full of zeros.
+    ///
+    /// The pages of shmem, backing shared anonymous mappings and mappings of memfds, aren't
+    /// allocated until they are touched. Those which aren't resident are reported as zero without
+    /// being read, so that scanning the mapping doesn't allocate them, unless part of the mapping
+    /// is swapped out. The other pages are scanned in place with volatile reads, nothing assumes
+    /// that their contents don't change.
+    pub fn zero_pages(&self) -> Result<Vec<bool>> {
+        let untouched = self.untouched_pages()?;
+        let swapped = if untouched.is_some() {
+            SwappedAreas::read()
+        } else {
+            SwappedAreas::default()
+        };
+        Ok(self.zero_pages_with(untouched, &swapped))
+    }
+
+    /// Returns whether each page of the mapping may have never been touched, if the mapping shares
+    /// shmem pages and some of them aren't resident.
+    pub(crate) fn untouched_pages(&self) -> Result<Option<Vec<bool>>> {
+        if !self.shmem {
+            return Ok(None);
+        }
+        let resident = self.resident_pages()?;
+        if resident.iter().all(|resident| *resident) {
+            return Ok(None);
+        }
+        Ok(Some(
+            resident.into_iter().map(|resident| !resident).collect(),
+        ))
+    }
+
+    /// Returns whether each page of the mapping is full of zeros, reporting the `untouched` pages
+    /// as zero without reading them unless the mapping overlaps the `swapped` areas.
+    ///
+    /// Pages swapped out aren't resident either, but they may hold data. The `swapped` areas must
+    /// be read after the `untouched` pages, which guarantees that none of them was swapped out, as
+    /// long as the mapping isn't accessed meanwhile.
+    pub(crate) fn zero_pages_with(
+        &self,
+        untouched: Option<Vec<bool>>,
+        swapped: &SwappedAreas,
+    ) -> Vec<bool> {
+        let start = self.addr as usize;
+        let mut zero_pages = match untouched {
+            Some(untouched) if !swapped.overlaps(start, start + self.size) => untouched,
+            _ => vec![false; self.size.div_ceil(host_page_size())],
+        };
+        for (index, zero) in zero_pages.iter_mut().enumerate() {
+            if !*zero {
+                *zero = self.is_zero_page(index);
+            }
+        }
+        zero_pages
+    }
+
+    // Checks whether the page at `index` is full of zeros, reading it with volatile accesses.
+    fn is_zero_page(&self, index: usize) -> bool {
+        let page_size = host_page_size();
+        let start = index * page_size;
+        let len = page_size.min(self.size - start);
+        // This is safe because the page is within the mapping, which is readable, and pages are
+        // aligned for u64.
+        unsafe {
+            let page = self.addr.add(start);
+            let words = page as *const u64;
+            (0..len / 8).all(|i| std::ptr::read_volatile(words.add(i)) == 0)
+                && (len / 8 * 8..len).all(|i| std::ptr::read_volatile(page.add(i)) == 0)
+        }
+    }
+
     /// Writes a slice to the memory region at the specified offset.
     /// Returns the number of bytes written.  The number of bytes written can
     /// be less than the length of the slice if there isn't enough room in the
@@ -146,6 +861,7 @@ impl MemoryMapping {
     ///     assert_eq!(res.unwrap(), 5);
     /// ```
     pub fn write_slice(&self, buf: &[u8], offset: usize) -> Result<usize> {
+        self.check_writable()?;
         if offset >= self.size {
             return Err(Error::InvalidAddress);
         }
@@ -154,7 +870,7 @@ impl MemoryMapping {
             // volatile.  Writing to it with what compiles down to a memcpy
             // won't hurt anything as long as we get the bounds checks right.
             let mut slice: &mut [u8] = &mut self.as_mut_slice()[offset..];
-            Ok(slice.write(buf).map_err(Error::WriteToMemory)?)
+            slice.write(buf).map_err(Error::WriteToMemory)
         }
     }
 
@@ -183,7 +899,7 @@ impl MemoryMapping {
             // volatile.  Writing to it with what compiles down to a memcpy
             // won't hurt anything as long as we get the bounds checks right.
             let slice: &[u8] = &self.as_slice()[offset..];
-            Ok(buf.write(slice).map_err(Error::ReadFromMemory)?)
+            buf.write(slice).map_err(Error::ReadFromMemory)
         }
     }
 
@@ -200,12 +916,19 @@ impl MemoryMapping {
     ///     assert!(res.is_ok());
     /// ```
     pub fn write_obj<T: DataInit>(&self, val: T, offset: usize) -> Result<()> {
+        self.check_writable()?;
         unsafe {
             // Guest memory can't strictly be modeled as a slice because it is
             // volatile.  Writing to it with what compiles down to a memcpy
             // won't hurt anything as long as we get the bounds checks right.
             self.range_end(offset, std::mem::size_of::<T>())?;
-            std::ptr::write_volatile(&mut self.as_mut_slice()[offset..] as *mut _ as *mut T, val);
+            let ptr = self.addr.add(offset) as *mut T;
+            // Volatile accesses must be aligned, so fall back to an unaligned store otherwise.
+            if ptr as usize % std::mem::align_of::<T>() == 0 {
+                std::ptr::write_volatile(ptr, val);
+            } else {
+                std::ptr::write_unaligned(ptr, val);
+            }
             Ok(())
         }
     }
@@ -231,9 +954,12 @@ impl MemoryMapping {
         unsafe {
             // This is safe because by definition Copy types can have their bits
             // set arbitrarily and still be valid.
-            Ok(std::ptr::read_volatile(
-                &self.as_slice()[offset..] as *const _ as *const T,
-            ))
+            let ptr = self.addr.add(offset) as *const T;
+            if ptr as usize % std::mem::align_of::<T>() == 0 {
+                Ok(std::ptr::read_volatile(ptr))
+            } else {
+                Ok(std::ptr::read_unaligned(ptr))
+            }
         }
     }
 
@@ -264,6 +990,7 @@ impl MemoryMapping {
     where
         F: Read,
     {
+        self.check_writable()?;
         let mem_end = self.range_end(mem_offset, count)?;
         unsafe {
             // It is safe to overwrite the volatile memory. Accessing the guest
@@ -312,9 +1039,79 @@ impl MemoryMapping {
         Ok(())
     }
 
+    /// Reads up to `count` bytes at `file_offset` of `fd` to memory at `mem_offset`, with `pread`,
+    /// and returns how many bytes were read.
+    ///
+    /// Interrupted and short reads are retried, so fewer bytes are only read if the end of the
+    /// file is reached. The file position of `fd` is left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::MemoryMapping;
+    /// # use std::fs::File;
+    /// let mem_map = MemoryMapping::new(1024).unwrap();
+    /// let file = File::open("/dev/zero").unwrap();
+    /// assert_eq!(mem_map.read_from_fd_at(&file, 0, 32, 64).unwrap(), 64);
+    /// ```
+    pub fn read_from_fd_at(
+        &self,
+        fd: &dyn AsRawFd,
+        file_offset: u64,
+        mem_offset: usize,
+        count: usize,
+    ) -> Result<usize> {
+        self.check_writable()?;
+        self.range_end(mem_offset, count)?;
+        retry_at(file_offset, count, |done, offset| {
+            // This is safe because the destination was checked to be within the mapping.
+            unsafe {
+                libc::pread(
+                    fd.as_raw_fd(),
+                    self.addr.add(mem_offset + done) as *mut libc::c_void,
+                    count - done,
+                    offset,
+                )
+            }
+        })
+        .map_err(Error::ReadFromSource)
+    }
+
+    /// Writes `count` bytes of memory at `mem_offset` at `file_offset` of `fd`, with `pwrite`.
+    ///
+    /// Interrupted and short writes are retried. The file position of `fd` is left untouched.
+    pub fn write_to_fd_at(
+        &self,
+        fd: &dyn AsRawFd,
+        file_offset: u64,
+        mem_offset: usize,
+        count: usize,
+    ) -> Result<()> {
+        self.range_end(mem_offset, count)?;
+        let done = retry_at(file_offset, count, |done, offset| {
+            // This is safe because the source was checked to be within the mapping.
+            unsafe {
+                libc::pwrite(
+                    fd.as_raw_fd(),
+                    self.addr.add(mem_offset + done) as *const libc::c_void,
+                    count - done,
+                    offset,
+                )
+            }
+        })
+        .map_err(Error::SystemCallFailed)?;
+        if done < count {
+            return Err(Error::SystemCallFailed(io::Error::from(
+                io::ErrorKind::WriteZero,
+            )));
+        }
+        Ok(())
+    }
+
     /// Uses madvise to tell the kernel to remove the specified range.  Subsequent reads
     /// to the pages in the range will return zero bytes.
     pub fn remove_range(&self, mem_offset: usize, count: usize) -> Result<()> {
+        self.check_writable()?;
         self.range_end(mem_offset, count)
             .map_err(|_| Error::InvalidRange(mem_offset, count))?;
         let ret = unsafe {
@@ -333,6 +1130,25 @@ impl MemoryMapping {
         }
     }
 
+    /// Uses madvise to ask the kernel to back the mapping with transparent huge pages.
+    ///
+    /// Fails with EINVAL if the kernel doesn't support transparent huge pages.
+    pub fn advise_hugepages(&self) -> Result<()> {
+        // This is safe because madvise(MADV_HUGEPAGE) doesn't change the content of the mapping.
+        let ret = unsafe {
+            libc::madvise(
+                self.addr as *mut libc::c_void,
+                self.size,
+                libc::MADV_HUGEPAGE,
+            )
+        };
+        if ret < 0 {
+            Err(Error::SystemCallFailed(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Uses madvise to tell the kernel not to dump the specified range.
     pub fn mark_dontdump(&self, mem_offset: usize, count: usize) -> Result<()> {
         self.range_end(mem_offset, count)
@@ -359,12 +1175,20 @@ impl MemoryMapping {
         std::slice::from_raw_parts(self.addr, self.size)
     }
 
+    #[allow(clippy::mut_from_ref)]
     unsafe fn as_mut_slice(&self) -> &mut [u8] {
         // This is safe because we mapped the area at addr ourselves, so this slice will not
         // overflow. However, it is possible to alias.
         std::slice::from_raw_parts_mut(self.addr, self.size)
     }
 
+    fn check_writable(&self) -> Result<()> {
+        if !self.prot.is_writable() {
+            return Err(Error::WriteProtected);
+        }
+        Ok(())
+    }
+
     // Check that offset+count is valid and return the sum.
     fn range_end(&self, offset: usize, count: usize) -> Result<usize> {
         let mem_end = offset.checked_add(count).ok_or(Error::InvalidAddress)?;
@@ -376,11 +1200,15 @@ impl MemoryMapping {
 }
 
 impl VolatileMemory for MemoryMapping {
-    fn get_slice(&self, offset: usize, count: usize) -> VolatileMemoryResult<VolatileSlice> {
+    fn get_slice(&self, offset: usize, count: usize) -> VolatileMemoryResult<VolatileSlice<'_>> {
         let mem_end = calc_offset(offset, count)?;
         if mem_end > self.size {
             return Err(VolatileMemoryError::OutOfBounds { addr: mem_end });
         }
+        // Volatile slices are writable, so they can't be handed out for read-only memory.
+        if !self.prot.is_writable() {
+            return Err(VolatileMemoryError::ReadOnly { addr: offset });
+        }
 
         // Safe because we checked that offset + count was within our range and we only ever hand
         // out volatile accessors.
@@ -390,10 +1218,15 @@ impl VolatileMemory for MemoryMapping {
 
 impl Drop for MemoryMapping {
     fn drop(&mut self) {
+        if let Some(ref reservation) = self.reservation {
+            reservation.release(self.addr as usize - reservation.addr as usize, self.size);
+            return;
+        }
+        let (addr, size) = self.reserved.unwrap_or((self.addr, self.size));
         // This is safe because we mmap the area at addr ourselves, and nobody
         // else is holding a reference to it.
         unsafe {
-            libc::munmap(self.addr as *mut libc::c_void, self.size);
+            libc::munmap(addr as *mut libc::c_void, size);
         }
     }
 }
@@ -406,9 +1239,19 @@ mod tests {
     use super::*;
     use std::fs::File;
     use std::mem;
-    use std::os::unix::io::FromRawFd;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::RawFd;
     use std::path::Path;
 
+    // A file descriptor which is never valid, used to exercise the mmap error paths.
+    struct InvalidFd;
+
+    impl AsRawFd for InvalidFd {
+        fn as_raw_fd(&self) -> RawFd {
+            -1
+        }
+    }
+
     #[test]
     fn basic_map() {
         let m = MemoryMapping::new(1024).unwrap();
@@ -427,8 +1270,7 @@ mod tests {
 
     #[test]
     fn map_invalid_fd() {
-        let fd = unsafe { std::fs::File::from_raw_fd(-1) };
-        let res = MemoryMapping::from_fd(&fd, 1024).unwrap_err();
+        let res = MemoryMapping::from_fd(&InvalidFd, 1024).unwrap_err();
         if let Error::SystemCallFailed(e) = res {
             assert_eq!(e.raw_os_error(), Some(libc::EBADF));
         } else {
@@ -436,6 +1278,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn convert_host_size() {
+        assert_eq!(host_size(0x1000).unwrap(), 0x1000);
+        if usize::MAX as u64 == u64::MAX {
+            assert_eq!(host_size(u64::MAX).unwrap(), usize::MAX);
+        } else {
+            match host_size(u64::MAX).unwrap_err() {
+                Error::InvalidSize(size) => assert_eq!(size, u64::MAX),
+                e => panic!("unexpected error: {:?}", e),
+            }
+        }
+    }
+
     #[test]
     fn slice_size() {
         let m = MemoryMapping::new(5).unwrap();
@@ -461,11 +1316,11 @@ mod tests {
     #[test]
     fn slice_overflow_error() {
         let m = MemoryMapping::new(5).unwrap();
-        let res = m.get_slice(std::usize::MAX, 3).unwrap_err();
+        let res = m.get_slice(usize::MAX, 3).unwrap_err();
         assert_eq!(
             res,
             VolatileMemoryError::Overflow {
-                base: std::usize::MAX,
+                base: usize::MAX,
                 offset: 3,
             }
         );
@@ -478,10 +1333,17 @@ mod tests {
         assert_eq!(res, VolatileMemoryError::OutOfBounds { addr: 6 });
     }
 
+    #[test]
+    fn slice_read_only_error() {
+        let m = MemoryMapping::new_with_protection(0x1000, Protection::ReadOnly).unwrap();
+        let res = m.get_slice(0x10, 8).unwrap_err();
+        assert_eq!(res, VolatileMemoryError::ReadOnly { addr: 0x10 });
+        assert!(m.get_ref::<u64>(0x10).is_err());
+    }
+
     #[test]
     fn from_fd_offset_invalid() {
-        let fd = unsafe { std::fs::File::from_raw_fd(-1) };
-        let res = MemoryMapping::from_fd_offset(&fd, 4096, (libc::off_t::max_value() as usize) + 1)
+        let res = MemoryMapping::from_fd_offset(&InvalidFd, 4096, (libc::off_t::MAX as u64) + 1)
             .unwrap_err();
         match res {
             Error::InvalidOffset => {}
@@ -513,11 +1375,11 @@ mod tests {
     fn obj_read_and_write() {
         let mem_map = MemoryMapping::new(5).unwrap();
         assert!(mem_map.write_obj(55u16, 4).is_err());
-        assert!(mem_map.write_obj(55u16, core::usize::MAX).is_err());
+        assert!(mem_map.write_obj(55u16, usize::MAX).is_err());
         assert!(mem_map.write_obj(55u16, 2).is_ok());
         assert_eq!(mem_map.read_obj::<u16>(2).unwrap(), 55u16);
         assert!(mem_map.read_obj::<u16>(4).is_err());
-        assert!(mem_map.read_obj::<u16>(core::usize::MAX).is_err());
+        assert!(mem_map.read_obj::<u16>(usize::MAX).is_err());
     }
 
     #[test]
@@ -529,7 +1391,7 @@ mod tests {
             .read_to_memory(2, &mut file, mem::size_of::<u32>())
             .is_err());
         assert!(mem_map
-            .read_to_memory(core::usize::MAX, &mut file, mem::size_of::<u32>())
+            .read_to_memory(usize::MAX, &mut file, mem::size_of::<u32>())
             .is_err());
 
         assert!(mem_map
@@ -540,7 +1402,7 @@ mod tests {
         assert!(mem_map
             .read_to_memory(1, &mut f, mem::size_of::<u32>())
             .is_err());
-        format!(
+        let _ = format!(
             "{:?}",
             mem_map.read_to_memory(1, &mut f, mem::size_of::<u32>())
         );
@@ -555,9 +1417,9 @@ mod tests {
             .write_from_memory(2, &mut sink, mem::size_of::<u32>())
             .is_err());
         assert!(mem_map
-            .write_from_memory(core::usize::MAX, &mut sink, mem::size_of::<u32>())
+            .write_from_memory(usize::MAX, &mut sink, mem::size_of::<u32>())
             .is_err());
-        format!(
+        let _ = format!(
             "{:?}",
             mem_map.write_from_memory(2, &mut sink, mem::size_of::<u32>())
         );
@@ -575,4 +1437,378 @@ mod tests {
         assert_eq!(mem_map.read_slice(buf, 0).unwrap(), sample_buf.len());
         assert_eq!(buf[0..sample_buf.len()], sample_buf[..]);
     }
+
+    #[test]
+    fn read_only_mapping() {
+        let mut f = tempfile().unwrap();
+        let sample_buf = &[1, 2, 3, 4, 5];
+        assert!(f.write_all(sample_buf).is_ok());
+
+        let mem_map = MemoryMapping::from_fd_offset_with_protection(
+            &f,
+            sample_buf.len(),
+            0,
+            Protection::ReadOnly,
+        )
+        .unwrap();
+        assert_eq!(mem_map.protection(), Protection::ReadOnly);
+        let buf = &mut [0u8; 5];
+        assert_eq!(mem_map.read_slice(buf, 0).unwrap(), sample_buf.len());
+        assert_eq!(buf, sample_buf);
+        assert_eq!(mem_map.read_obj::<u8>(4).unwrap(), 5);
+
+        match mem_map.write_slice(&[0xa5], 0) {
+            Err(Error::WriteProtected) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(mem_map.write_obj(0xa5u8, 0).is_err());
+        let mut zero = File::open(Path::new("/dev/zero")).unwrap();
+        assert!(mem_map.read_to_memory(0, &mut zero, 1).is_err());
+        assert!(mem_map.remove_range(0, 1).is_err());
+        assert_eq!(mem_map.read_obj::<u8>(0).unwrap(), 1);
+
+        let anon = MemoryMapping::new_with_protection(1024, Protection::ReadOnly).unwrap();
+        assert_eq!(anon.read_obj::<u64>(0).unwrap(), 0);
+        assert!(anon.write_obj(0u64, 0).is_err());
+        assert_eq!(
+            MemoryMapping::new(1024).unwrap().protection(),
+            Protection::ReadWrite
+        );
+    }
+
+    #[test]
+    fn fd_io_at() {
+        let mut f = tempfile().unwrap();
+        f.write_all(&[0xa5; 0x100]).unwrap();
+
+        let mem_map = MemoryMapping::new(0x1000).unwrap();
+        mem_map.write_slice(&[1, 2, 3, 4], 0x10).unwrap();
+        mem_map.write_to_fd_at(&f, 0x80, 0x10, 4).unwrap();
+        // The file position is left untouched.
+        f.write_all(&[0x5a]).unwrap();
+
+        let other = MemoryMapping::new(0x1000).unwrap();
+        assert_eq!(other.read_from_fd_at(&f, 0x7f, 0x800, 6).unwrap(), 6);
+        let buf = &mut [0u8; 6];
+        other.read_slice(buf, 0x800).unwrap();
+        assert_eq!(buf, &[0xa5, 1, 2, 3, 4, 0xa5]);
+
+        // Stops at the end of the file.
+        assert_eq!(other.read_from_fd_at(&f, 0xf0, 0, 0x100).unwrap(), 0x11);
+        assert_eq!(other.read_obj::<u8>(0x10).unwrap(), 0x5a);
+        assert!(other.read_from_fd_at(&f, 0, 0xf00, 0x200).is_err());
+        assert!(other.write_to_fd_at(&f, 0, usize::MAX, 1).is_err());
+        match other.read_from_fd_at(&f, u64::MAX, 0, 1) {
+            Err(Error::ReadFromSource(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match other.write_to_fd_at(&InvalidFd, 0, 0, 1) {
+            Err(Error::SystemCallFailed(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let read_only = MemoryMapping::new_with_protection(0x1000, Protection::ReadOnly).unwrap();
+        match read_only.read_from_fd_at(&f, 0, 0, 1) {
+            Err(Error::WriteProtected) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        read_only.write_to_fd_at(&f, 0, 0, 1).unwrap();
+    }
+
+    #[test]
+    fn private_mapping() {
+        let mut f = tempfile().unwrap();
+        let sample_buf = &[1, 2, 3, 4, 5];
+        assert!(f.write_all(sample_buf).is_ok());
+
+        let mem_map = MemoryMapping::from_fd_offset_private(&f, sample_buf.len(), 0).unwrap();
+        assert!(!mem_map.is_anonymous());
+        assert_eq!(mem_map.read_obj::<u8>(4).unwrap(), 5);
+        mem_map.write_obj(0xa5u8, 4).unwrap();
+        assert_eq!(mem_map.read_obj::<u8>(4).unwrap(), 0xa5);
+
+        // The file is left untouched.
+        let shared = MemoryMapping::from_fd(&f, sample_buf.len()).unwrap();
+        assert_eq!(shared.read_obj::<u8>(4).unwrap(), 5);
+    }
+
+    #[test]
+    fn zero_pages() {
+        let page_size = host_page_size();
+        let mem_map = MemoryMapping::new(3 * page_size + 0x10).unwrap();
+        assert_eq!(mem_map.zero_pages().unwrap(), vec![true; 4]);
+
+        mem_map.write_obj(1u8, page_size - 1).unwrap();
+        mem_map.write_obj(0u8, 2 * page_size).unwrap();
+        mem_map.write_obj(1u8, 3 * page_size + 0xf).unwrap();
+        assert_eq!(
+            mem_map.zero_pages().unwrap(),
+            vec![false, true, true, false]
+        );
+        // The pages never touched aren't allocated by the scan.
+        assert_eq!(
+            mem_map.resident_pages().unwrap(),
+            vec![true, false, true, true]
+        );
+
+        // Nor are those of memfds.
+        let memfd = create_sealed_memfd(4 * page_size as u64, None).unwrap();
+        let mem_map = MemoryMapping::from_fd(&memfd, 4 * page_size).unwrap();
+        mem_map.write_obj(1u8, 2 * page_size).unwrap();
+        assert_eq!(mem_map.zero_pages().unwrap(), vec![true, true, false, true]);
+        assert_eq!(
+            mem_map.resident_pages().unwrap(),
+            vec![false, false, true, false]
+        );
+
+        // Pages of files not backed by shmem are all read.
+        let f = tempfile().unwrap();
+        f.set_len(2 * page_size as u64).unwrap();
+        f.write_at(&[1], page_size as u64 + 1).unwrap();
+        let mem_map = MemoryMapping::from_fd(&f, 2 * page_size).unwrap();
+        assert_eq!(mem_map.zero_pages().unwrap(), vec![true, false]);
+    }
+
+    #[test]
+    fn swapped_areas() {
+        let smaps = "\
+7f0000000000-7f0000200000 rw-s 00000000 00:01 1234                       /memfd:guest (deleted)
+Size:               2048 kB
+Swap:                  0 kB
+VmFlags: rd wr sh mr mw me ms sd
+7f0000400000-7f0000600000 rw-s 00000000 00:01 1235                       /dev/zero (deleted)
+Size:               2048 kB
+Swap:                  8 kB
+VmFlags: rd wr sh mr mw me ms sd
+";
+        let swapped = SwappedAreas(parse_swapped_areas(smaps));
+        assert!(!swapped.overlaps(0x7f00_0000_0000, 0x7f00_0020_0000));
+        assert!(swapped.overlaps(0x7f00_001f_f000, 0x7f00_0040_1000));
+        assert!(!swapped.overlaps(0x7f00_0060_0000, 0x7f00_0080_0000));
+
+        // Unexpected contents are assumed to report swapped out pages everywhere.
+        assert!(parse_swapped_areas("Swap: 4 MB\n").is_none());
+        assert!(parse_swapped_areas("garbage\n").is_none());
+        assert!(SwappedAreas::default().overlaps(0, 0x1000));
+        assert!(!SwappedAreas::read().overlaps(0, 0x1000));
+    }
+
+    #[test]
+    fn resident_pages() {
+        let page_size = host_page_size();
+        let mem_map = MemoryMapping::new(4 * page_size).unwrap();
+        assert!(mem_map.is_anonymous());
+        assert_eq!(mem_map.resident_pages().unwrap(), vec![false; 4]);
+
+        mem_map.write_obj(1u8, 2 * page_size + 1).unwrap();
+        assert_eq!(
+            mem_map.resident_pages().unwrap(),
+            vec![false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn builder_options() {
+        let page_size = host_page_size();
+        let mem_map = MemoryMappingBuilder::new(4 * page_size)
+            .populate(true)
+            .locked(true)
+            .build()
+            .unwrap();
+        assert!(mem_map.is_anonymous());
+        assert_eq!(mem_map.resident_pages().unwrap(), vec![true; 4]);
+
+        let mut f = tempfile().unwrap();
+        f.write_all(&vec![0; page_size]).unwrap();
+        f.write_all(&[0x5a; 0x100]).unwrap();
+        let mem_map = MemoryMappingBuilder::new(0x100)
+            .from_fd(&f, page_size as u64)
+            .protection(Protection::ReadOnly)
+            .private(true)
+            .build()
+            .unwrap();
+        assert!(!mem_map.is_anonymous());
+        assert_eq!(mem_map.protection(), Protection::ReadOnly);
+        assert_eq!(mem_map.read_obj::<u8>(0xff).unwrap(), 0x5a);
+        match MemoryMappingBuilder::new(0x80)
+            .from_fd(&f, u64::MAX)
+            .build()
+            .unwrap_err()
+        {
+            Error::InvalidOffset => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        for &huge_page_size in &[3 << 20, 4 << 20] {
+            match MemoryMappingBuilder::new(2 << 20)
+                .hugetlb(Some(huge_page_size))
+                .build()
+                .unwrap_err()
+            {
+                Error::InvalidHugePageSize(size) => assert_eq!(size, huge_page_size),
+                e => panic!("unexpected error: {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_mapping() {
+        let page_size = host_page_size();
+        let reserved = MemoryMapping::new(4 * page_size).unwrap();
+        reserved.write_obj(1u8, page_size).unwrap();
+        reserved.write_obj(2u8, 3 * page_size).unwrap();
+
+        let addr = unsafe { reserved.as_ptr().add(page_size) };
+        let mem_map = unsafe {
+            MemoryMappingBuilder::new(2 * page_size)
+                .private(true)
+                .fixed_address(addr)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(mem_map.as_ptr(), addr);
+        assert_eq!(reserved.read_obj::<u8>(page_size).unwrap(), 0);
+        mem_map.write_obj(3u8, page_size).unwrap();
+        assert_eq!(reserved.read_obj::<u8>(2 * page_size).unwrap(), 3);
+        assert_eq!(reserved.read_obj::<u8>(3 * page_size).unwrap(), 2);
+        // Keep the range mapped until the reservation is dropped.
+        mem::forget(mem_map);
+    }
+
+    // Returns the permissions of the mapping containing `addr` in the current process, if any.
+    fn host_mapping_perms(addr: usize) -> Option<String> {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mut range = fields.next()?.split('-');
+            let start = usize::from_str_radix(range.next()?, 16).ok()?;
+            let end = usize::from_str_radix(range.next()?, 16).ok()?;
+            if (start..end).contains(&addr) {
+                fields.next().map(String::from)
+            } else {
+                None
+            }
+        })
+    }
+
+    #[test]
+    fn guard_pages() {
+        let page_size = host_page_size();
+        let mem_map = MemoryMappingBuilder::new(2 * page_size)
+            .guard_pages(true)
+            .build()
+            .unwrap();
+        let addr = mem_map.as_ptr() as usize;
+        assert_eq!(addr % page_size, 0);
+        assert_eq!(host_mapping_perms(addr - 1).unwrap(), "---p");
+        assert_eq!(host_mapping_perms(addr).unwrap(), "rw-s");
+        assert_eq!(
+            host_mapping_perms(addr + 2 * page_size - 1).unwrap(),
+            "rw-s"
+        );
+        assert_eq!(host_mapping_perms(addr + 2 * page_size).unwrap(), "---p");
+        mem_map.write_obj(1u8, 2 * page_size - 1).unwrap();
+
+        // The mapping is aligned to the size of the huge pages, which can't be guessed.
+        match MemoryMappingBuilder::new(0x20_0000)
+            .hugetlb(None)
+            .guard_pages(true)
+            .build()
+            .unwrap_err()
+        {
+            Error::InvalidHugePageSize(0) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let reserved = MemoryMapping::new(page_size).unwrap();
+        match unsafe {
+            MemoryMappingBuilder::new(page_size)
+                .fixed_address(reserved.as_ptr())
+                .guard_pages(true)
+                .build()
+                .unwrap_err()
+        } {
+            Error::InvalidAddress => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn reservation_mappings() {
+        let page_size = host_page_size();
+        let reservation = Arc::new(MemoryReservation::new(8 * page_size, 0x20_0000).unwrap());
+        let base = reservation.as_ptr() as usize;
+        assert_eq!(base % 0x20_0000, 0);
+        assert_eq!(reservation.size(), 8 * page_size);
+        assert_eq!(host_mapping_perms(base).unwrap(), "---p");
+
+        let build = |offset, size| {
+            MemoryMappingBuilder::new(size)
+                .in_reservation(reservation.clone(), offset)
+                .build()
+        };
+        let mem_map = build(page_size, 2 * page_size).unwrap();
+        assert_eq!(mem_map.as_ptr() as usize, base + page_size);
+        assert!(Arc::ptr_eq(mem_map.reservation().unwrap(), &reservation));
+        assert_eq!(host_mapping_perms(base + page_size).unwrap(), "rw-s");
+        assert_eq!(host_mapping_perms(base + 3 * page_size).unwrap(), "---p");
+        mem_map.write_obj(1u8, page_size).unwrap();
+
+        match build(2 * page_size, page_size).unwrap_err() {
+            Error::RangeInUse(offset, size) => {
+                assert_eq!((offset, size), (2 * page_size, page_size))
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        match build(7 * page_size, 2 * page_size).unwrap_err() {
+            Error::InvalidRange(..) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        match build(1, page_size).unwrap_err() {
+            Error::InvalidAddress => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        match MemoryMappingBuilder::new(page_size)
+            .in_reservation(reservation.clone(), 0)
+            .guard_pages(true)
+            .build()
+            .unwrap_err()
+        {
+            Error::InvalidAddress => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // The range of a dropped mapping is reserved again, and may be reused.
+        drop(mem_map);
+        assert_eq!(host_mapping_perms(base + page_size).unwrap(), "---p");
+        let mem_map = build(2 * page_size, page_size).unwrap();
+        assert_eq!(mem_map.read_obj::<u8>(0).unwrap(), 0);
+
+        // The mappings keep the reservation alive.
+        drop(reservation);
+        mem_map.write_obj(2u8, 0).unwrap();
+        assert_eq!(host_mapping_perms(base).unwrap(), "---p");
+    }
+
+    #[test]
+    fn sealed_memfd() {
+        let page_size = host_page_size();
+        let f = create_sealed_memfd(2 * page_size as u64, None).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 2 * page_size as u64);
+        assert!(f.set_len(page_size as u64).is_err());
+        assert!(f.set_len(4 * page_size as u64).is_err());
+
+        let mem_map = MemoryMapping::from_fd(&f, 2 * page_size).unwrap();
+        let other = MemoryMapping::from_fd(&f, 2 * page_size).unwrap();
+        mem_map.write_obj(0x5au8, page_size).unwrap();
+        assert_eq!(other.read_obj::<u8>(page_size).unwrap(), 0x5a);
+
+        match create_sealed_memfd(0x30_0000, Some(0x20_0000)) {
+            Err(Error::InvalidHugePageSize(0x20_0000)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match create_sealed_memfd(u64::MAX, None) {
+            Err(Error::InvalidSize(u64::MAX)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,1059 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Save the contents of guest memory to a snapshot and restore them, so a virtual machine may be
+//! paused, persisted and resumed.
+//!
+//! A snapshot starts with a header describing the layout of the guest memory, followed by the
+//! contents of the memory regions in ascending order of guest address. All integers are stored
+//! in little endian:
+//!
+//! | Field          | Size                    |
+//! |----------------|-------------------------|
+//! | magic          | 8 bytes, `GMEMSNAP`     |
+//! | version        | 4 bytes                 |
+//! | region count   | 4 bytes                 |
+//! | region table   | 16 bytes for each region, guest base and size |
+//! | region data    | size bytes for each region |
+//!
+//! Sparse snapshots, version 2 of the format, add the offset of the contents of each region to
+//! the region table, which takes 24 bytes for each region. The header and the contents of each
+//! region are padded to the host page size, and the pages full of zeros are left as holes in the
+//! snapshot file. Such a snapshot may be restored by reading it, holes reading as zeros, or by
+//! mapping it copy-on-write with `GuestMemory::from_snapshot_file()`.
+//!
+//! Diff snapshots, version 3 of the format, only contain the pages written since their parent
+//! snapshot was taken. The region table is followed by the checksum of the parent snapshot, then
+//! by runs of pages, each made of a 16 bytes header, guest address and length, and of the contents
+//! of the pages. A run header with a length of zero ends the runs, and is followed by the checksum
+//! of all the preceding bytes of the diff snapshot.
+//!
+//! The checksum of a snapshot, computed by `snapshot_checksum()`, is a 64-bit hash of all its
+//! bytes, holes reading as zeros. The bytes are taken eight at a time as little endian words, the
+//! last word padded with zeros, and the length of the snapshot in bytes is taken as a last word.
+//! Starting from 0xcbf29ce484222325, each word `w` updates the hash `h` to
+//! `((h ^ w) * 0x100000001b3).rotate_left(29)`, wrapping on overflow.
+
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::result;
+
+use address::Address;
+use dirty_bitmap::DirtyLog;
+use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
+use guest_memory::{Error as GuestMemoryError, GuestMemory, MemoryRegion};
+use mmap::{host_page_size, host_size, MemoryMapping, SwappedAreas};
+use stream::{
+    self, check_layout, encode_header, page_runs, read_error, read_u32, read_u64, region_ranges,
+    require_dirty_tracking, StreamError,
+};
+
+/// Magic number identifying a guest memory snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"GMEMSNAP";
+/// Version of the snapshot format written by `GuestMemory::snapshot()`.
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// Version of the snapshot format written by `GuestMemory::snapshot_sparse()`.
+pub const SPARSE_SNAPSHOT_VERSION: u32 = 2;
+/// Version of the snapshot format written by `GuestMemory::snapshot_diff()`.
+pub const DIFF_SNAPSHOT_VERSION: u32 = 3;
+
+/// Errors associated with saving and restoring guest memory snapshots.
+#[derive(Debug)]
+pub enum Error {
+    /// Failure in reading or writing the snapshot.
+    Io(io::Error),
+    /// Failure in accessing guest memory.
+    GuestMemory(GuestMemoryError),
+    /// The snapshot doesn't start with the snapshot magic number.
+    InvalidMagic,
+    /// The snapshot was written with an unsupported version of the format.
+    UnsupportedVersion(u32),
+    /// The snapshot and the guest memory have a different number of memory regions.
+    RegionCountMismatch {
+        /// Number of memory regions of the guest memory.
+        expected: usize,
+        /// Number of memory regions recorded in the snapshot.
+        found: usize,
+    },
+    /// A memory region of the snapshot doesn't match the memory region of the guest memory.
+    RegionMismatch {
+        /// Memory region of the guest memory.
+        expected: GuestAddressRange,
+        /// Memory region recorded in the snapshot.
+        found: GuestAddressRange,
+    },
+    /// The contents of a memory region are misplaced in the snapshot.
+    InvalidRegionOffset(u64),
+    /// The snapshot ended before all the guest memory was restored.
+    Truncated,
+    /// The memory region at the given address doesn't track dirty pages.
+    NoDirtyTracking(GuestAddress),
+    /// The diff snapshot doesn't apply to the given parent snapshot.
+    ParentMismatch {
+        /// Checksum of the parent snapshot.
+        expected: u64,
+        /// Checksum of the parent snapshot recorded in the diff snapshot.
+        found: u64,
+    },
+    /// The checksum of the diff snapshot doesn't match its contents.
+    ChecksumMismatch {
+        /// Checksum recorded in the diff snapshot.
+        expected: u64,
+        /// Checksum of the contents of the diff snapshot.
+        found: u64,
+    },
+}
+type Result<T> = result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guest memory snapshot error: ")?;
+        match self {
+            Error::Io(e) => write!(f, "failed to access the snapshot: {}", e),
+            Error::GuestMemory(e) => write!(f, "{}", e),
+            Error::InvalidMagic => write!(f, "invalid magic number"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            Error::RegionCountMismatch { expected, found } => write!(
+                f,
+                "snapshot has {} memory regions instead of {}",
+                found, expected
+            ),
+            Error::RegionMismatch { expected, found } => write!(
+                f,
+                "snapshot has memory region base {:#x}/size {:#x} instead of base {:#x}/size {:#x}",
+                found.start().offset(),
+                found.len(),
+                expected.start().offset(),
+                expected.len(),
+            ),
+            Error::InvalidRegionOffset(offset) => {
+                write!(f, "invalid memory region offset {:#x}", offset)
+            }
+            Error::Truncated => write!(f, "snapshot is truncated"),
+            Error::NoDirtyTracking(base) => write!(
+                f,
+                "memory region at {:#x} doesn't track dirty pages",
+                base.offset()
+            ),
+            Error::ParentMismatch { expected, found } => write!(
+                f,
+                "diff snapshot applies to parent {:#018x} instead of {:#018x}",
+                found, expected
+            ),
+            Error::ChecksumMismatch { expected, found } => write!(
+                f,
+                "diff snapshot checksum is {:#018x} instead of {:#018x}",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl StreamError for Error {
+    fn io(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+
+    fn truncated() -> Self {
+        Error::Truncated
+    }
+
+    fn guest_memory(e: GuestMemoryError) -> Self {
+        Error::GuestMemory(e)
+    }
+
+    fn invalid_magic() -> Self {
+        Error::InvalidMagic
+    }
+
+    fn region_count_mismatch(expected: usize, found: usize) -> Self {
+        Error::RegionCountMismatch { expected, found }
+    }
+
+    fn region_mismatch(expected: GuestAddressRange, found: GuestAddressRange) -> Self {
+        Error::RegionMismatch { expected, found }
+    }
+}
+
+// Converts a failure of reading the snapshot, reporting truncation distinctly.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        read_error(e)
+    }
+}
+
+// Converts a failure of saving guest memory to the snapshot.
+fn save_error(e: GuestMemoryError) -> Error {
+    stream::save_error(e)
+}
+
+// Converts a failure of restoring guest memory, reporting truncation of the snapshot distinctly.
+fn restore_error(e: GuestMemoryError) -> Error {
+    stream::restore_error(e)
+}
+
+// Skips `len` bytes of `src`.
+fn skip<R: Read>(src: &mut R, len: u64) -> Result<()> {
+    let skipped = io::copy(&mut src.take(len), &mut io::sink()).map_err(Error::Io)?;
+    if skipped != len {
+        return Err(Error::Truncated);
+    }
+    Ok(())
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+// Hash of the bytes of a snapshot, as described in the module documentation.
+#[derive(Clone)]
+struct Checksum {
+    hash: u64,
+    // Bytes which don't fill a word yet.
+    pending: [u8; 8],
+    pending_len: usize,
+    len: u64,
+}
+
+impl Checksum {
+    fn new() -> Self {
+        Checksum {
+            hash: 0xcbf2_9ce4_8422_2325,
+            pending: [0u8; 8],
+            pending_len: 0,
+            len: 0,
+        }
+    }
+
+    fn mix(&mut self, word: u64) {
+        self.hash = (self.hash ^ word)
+            .wrapping_mul(0x100_0000_01b3)
+            .rotate_left(29);
+    }
+
+    fn update(&mut self, mut buf: &[u8]) {
+        self.len += buf.len() as u64;
+        if self.pending_len > 0 {
+            let count = buf.len().min(8 - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + count].copy_from_slice(&buf[..count]);
+            self.pending_len += count;
+            buf = &buf[count..];
+            if self.pending_len < 8 {
+                return;
+            }
+            let word = u64::from_le_bytes(self.pending);
+            self.mix(word);
+            self.pending_len = 0;
+        }
+        let mut words = buf.chunks_exact(8);
+        for word in &mut words {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(word);
+            self.mix(u64::from_le_bytes(bytes));
+        }
+        let rest = words.remainder();
+        self.pending[..rest.len()].copy_from_slice(rest);
+        self.pending_len = rest.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut checksum = self.clone();
+        if checksum.pending_len > 0 {
+            for byte in checksum.pending[checksum.pending_len..].iter_mut() {
+                *byte = 0;
+            }
+            let word = u64::from_le_bytes(checksum.pending);
+            checksum.mix(word);
+        }
+        checksum.mix(self.len);
+        checksum.hash
+    }
+}
+
+// Computes the checksum of the bytes written to the inner writer.
+struct ChecksumWriter<'a, W: 'a> {
+    inner: &'a mut W,
+    checksum: Checksum,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        ChecksumWriter {
+            inner,
+            checksum: Checksum::new(),
+        }
+    }
+}
+
+impl<'a, W: Write> Write for ChecksumWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.checksum.update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Computes the checksum of the bytes read from the inner reader.
+struct ChecksumReader<'a, R: 'a> {
+    inner: &'a mut R,
+    checksum: Checksum,
+}
+
+impl<'a, R: Read> ChecksumReader<'a, R> {
+    fn new(inner: &'a mut R) -> Self {
+        ChecksumReader {
+            inner,
+            checksum: Checksum::new(),
+        }
+    }
+}
+
+impl<'a, R: Read> Read for ChecksumReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.checksum.update(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Computes the checksum of the snapshot read from `src`, which diff snapshots use to reference
+/// their parent snapshot.
+pub fn snapshot_checksum<R: Read>(src: &mut R) -> Result<u64> {
+    let mut sink = io::sink();
+    let mut dst = ChecksumWriter::new(&mut sink);
+    io::copy(src, &mut dst).map_err(Error::Io)?;
+    Ok(dst.checksum.finish())
+}
+
+/// Merges a full snapshot read from `base` and a chain of diff snapshots into a full snapshot
+/// written to `dst`, and returns its checksum.
+///
+/// Each diff snapshot of `diffs` must apply to the previous one, the first one applying to
+/// `base`. The merged contents are assembled in anonymous memory as large as the guest memory.
+pub fn merge_snapshots<R, I, D, W>(base: &mut R, diffs: I, dst: &mut W) -> Result<u64>
+where
+    R: Read,
+    I: IntoIterator<Item = D>,
+    D: Read,
+    W: Write,
+{
+    let mut src = ChecksumReader::new(base);
+    let layout = Layout::read_full(&mut src)?;
+    let ranges: Vec<(GuestAddress, GuestUsize)> = layout
+        .regions
+        .iter()
+        .map(|(range, _)| (range.start(), range.len()))
+        .collect();
+    let mem = GuestMemory::new(&ranges).map_err(Error::GuestMemory)?;
+    mem.restore_contents(layout, &mut src)?;
+    // Padding may follow the contents of the last region.
+    io::copy(&mut src, &mut io::sink()).map_err(Error::Io)?;
+
+    let mut parent = src.checksum.finish();
+    for mut diff in diffs {
+        parent = mem.restore_diff(&mut diff, parent)?;
+    }
+    let mut dst = ChecksumWriter::new(dst);
+    mem.snapshot(&mut dst)?;
+    Ok(dst.checksum.finish())
+}
+
+// Layout of the guest memory and of the snapshot, as recorded in the header of a snapshot.
+struct Layout {
+    // Length of the header.
+    header_len: u64,
+    // Ranges of the memory regions, and offsets of their contents in the snapshot.
+    regions: Vec<(GuestAddressRange, u64)>,
+}
+
+impl Layout {
+    // Reads the header of a dense or sparse snapshot.
+    fn read_full<R: Read>(src: &mut R) -> Result<Self> {
+        match read_version(src)? {
+            version @ SNAPSHOT_VERSION | version @ SPARSE_SNAPSHOT_VERSION => {
+                Layout::read_from(src, version)
+            }
+            version => Err(Error::UnsupportedVersion(version)),
+        }
+    }
+
+    // Reads the region table of a snapshot of the given version.
+    fn read_from<R: Read>(src: &mut R, version: u32) -> Result<Self> {
+        let count = read_u32(src)? as u64;
+        let entry_len = if version == SPARSE_SNAPSHOT_VERSION {
+            24
+        } else {
+            16
+        };
+        let header_len = 16 + entry_len * count;
+
+        // Contents are stored in the order of the region table, without overlapping.
+        let mut end = header_len;
+        let mut regions = Vec::new();
+        for _ in 0..count {
+            let base = GuestAddress(read_u64(src)?);
+            let range = GuestAddressRange::new(base, read_u64(src)?);
+            let offset = if version == SPARSE_SNAPSHOT_VERSION {
+                read_u64(src)?
+            } else {
+                end
+            };
+            if offset < end {
+                return Err(Error::InvalidRegionOffset(offset));
+            }
+            end = offset
+                .checked_add(range.len())
+                .ok_or(Error::InvalidRegionOffset(offset))?;
+            regions.push((range, offset));
+        }
+        Ok(Layout {
+            header_len,
+            regions,
+        })
+    }
+
+    // Checks that the memory regions recorded in the snapshot match those of `mem`.
+    fn check(&self, mem: &GuestMemory) -> Result<()> {
+        let found: Vec<GuestAddressRange> = self.regions.iter().map(|(range, _)| *range).collect();
+        check_layout::<Error>(mem, &found)?;
+        Ok(())
+    }
+}
+
+// Reads the magic number and the version of a snapshot.
+fn read_version<R: Read>(src: &mut R) -> Result<u32> {
+    stream::read_version(src, &SNAPSHOT_MAGIC)
+}
+
+impl GuestMemory {
+    /// Saves the layout and the contents of the guest memory to `dst`.
+    ///
+    /// The guest memory should not be modified while the snapshot is written, otherwise the
+    /// snapshot may not be consistent. The pages tracked as dirty are left untouched. To take diff
+    /// snapshots on top of this one, create a `DirtyLog` with `dirty_log()` before taking it, so
+    /// that the next diff snapshot only contains the pages written from then on. Diff snapshots
+    /// reference this snapshot by its checksum, see `snapshot_checksum()`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use memory_model::{GuestAddress, GuestMemory};
+    /// let gm = GuestMemory::new(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+    /// gm.write_obj_at_addr(0x55aa_u16, GuestAddress(0x1100)).unwrap();
+    ///
+    /// let mut snapshot = Vec::new();
+    /// gm.snapshot(&mut snapshot).unwrap();
+    ///
+    /// let restored = GuestMemory::new(&[(GuestAddress(0x1000), 0x1000)]).unwrap();
+    /// restored.restore(&mut snapshot.as_slice()).unwrap();
+    /// let val: u16 = restored.read_obj_from_addr(GuestAddress(0x1100)).unwrap();
+    /// assert_eq!(val, 0x55aa);
+    /// ```
+    pub fn snapshot<W: Write>(&self, dst: &mut W) -> Result<()> {
+        let ranges = region_ranges(self);
+        let header = encode_header(&SNAPSHOT_MAGIC, SNAPSHOT_VERSION, &ranges, None);
+        dst.write_all(&header).map_err(Error::Io)?;
+
+        for range in ranges.iter() {
+            self.write_from_memory(range.start(), dst, range.len() as usize)
+                .map_err(save_error)?;
+        }
+        dst.flush().map_err(Error::Io)
+    }
+
+    /// Saves the layout and the contents of the guest memory to `dst` as a sparse snapshot.
+    ///
+    /// The pages full of zeros are not written, leaving holes in `dst` which must thus be empty,
+    /// for example a newly created file. The memory regions are scanned in place, skipping the
+    /// shmem pages never touched so that they aren't allocated, see `MemoryMapping::zero_pages()`,
+    /// and each run of contiguous pages holding data is written at once.
+    ///
+    /// The guest memory should not be modified while the snapshot is written, otherwise the
+    /// snapshot may not be consistent. The pages tracked as dirty are left untouched, as with
+    /// `snapshot()`.
+    pub fn snapshot_sparse<W: Write + Seek>(&self, dst: &mut W) -> Result<()> {
+        let page_size = host_page_size() as u64;
+        let ranges = region_ranges(self);
+        let mut end = align_up(16 + 24 * ranges.len() as u64, page_size);
+        let mut offsets = Vec::with_capacity(ranges.len());
+        for range in ranges.iter() {
+            offsets.push(end);
+            end += align_up(range.len(), page_size);
+        }
+        let header = encode_header(
+            &SNAPSHOT_MAGIC,
+            SPARSE_SNAPSHOT_VERSION,
+            &ranges,
+            Some(&offsets),
+        );
+        let start = dst.stream_position().map_err(Error::Io)?;
+        dst.write_all(&header).map_err(Error::Io)?;
+        let mut pos = header.len() as u64;
+
+        // The regions are taken from the guest memory itself.
+        let mappings: Vec<&MemoryMapping> = ranges
+            .iter()
+            .map(|range| self.find_region(range.start()).unwrap().mapping())
+            .collect();
+        let mut untouched = Vec::with_capacity(ranges.len());
+        for (range, mapping) in ranges.iter().zip(mappings.iter()) {
+            untouched.push(
+                mapping
+                    .untouched_pages()
+                    .map_err(|e| save_error(GuestMemoryError::MemoryAccess(range.start(), e)))?,
+            );
+        }
+        // Read once for all the regions, after finding their untouched pages.
+        let swapped = if untouched.iter().any(Option::is_some) {
+            SwappedAreas::read()
+        } else {
+            SwappedAreas::default()
+        };
+
+        for (((range, offset), mapping), untouched) in
+            ranges.iter().zip(offsets).zip(mappings).zip(untouched)
+        {
+            let zero_pages = mapping.zero_pages_with(untouched, &swapped);
+            let mut index = 0;
+            while index < zero_pages.len() {
+                if zero_pages[index] {
+                    index += 1;
+                    continue;
+                }
+                let run = zero_pages[index..]
+                    .iter()
+                    .take_while(|&&zero| !zero)
+                    .count();
+                let run_offset = index as u64 * page_size;
+                let len = (run as u64 * page_size).min(range.len() - run_offset);
+                if pos != offset + run_offset {
+                    pos = offset + run_offset;
+                    dst.seek(SeekFrom::Start(start + pos)).map_err(Error::Io)?;
+                }
+                mapping
+                    .write_from_memory(run_offset as usize, dst, len as usize)
+                    .map_err(|e| {
+                        save_error(GuestMemoryError::MemoryAccess(
+                            range.start().unchecked_add(run_offset),
+                            e,
+                        ))
+                    })?;
+                pos += len;
+                index += run;
+            }
+        }
+        // Holes at the end of the snapshot don't extend it.
+        if pos != end {
+            dst.seek(SeekFrom::Start(start + end - 1))
+                .map_err(Error::Io)?;
+            dst.write_all(&[0]).map_err(Error::Io)?;
+        }
+        dst.flush().map_err(Error::Io)
+    }
+
+    /// Saves the pages written since the last snapshot to `dst` as a diff snapshot applying to
+    /// the snapshot whose checksum is `parent`, and returns the checksum of the diff snapshot.
+    ///
+    /// All the memory regions must track dirty pages. The pages written since the last snapshot
+    /// are fetched from `log`, which must have been created before the parent snapshot was taken,
+    /// so other consumers of the dirty pages, such as a `MigrationSender`, don't affect the diff
+    /// snapshots.
+    pub fn snapshot_diff<W: Write>(&self, log: &DirtyLog, dst: &mut W, parent: u64) -> Result<u64> {
+        let regions = require_dirty_tracking(self).map_err(Error::NoDirtyTracking)?;
+        let ranges: Vec<GuestAddressRange> = regions.iter().map(|(range, _)| *range).collect();
+        let mut header = encode_header(&SNAPSHOT_MAGIC, DIFF_SNAPSHOT_VERSION, &ranges, None);
+        header.extend_from_slice(&parent.to_le_bytes());
+        let mut dst = ChecksumWriter::new(dst);
+        dst.write_all(&header).map_err(Error::Io)?;
+
+        for (range, page_size) in regions.iter() {
+            let pages = self.fetch_and_clear_dirty_log(log, range);
+            for (addr, len) in page_runs(&pages, *page_size, range) {
+                dst.write_all(&addr.offset().to_le_bytes())
+                    .map_err(Error::Io)?;
+                dst.write_all(&len.to_le_bytes()).map_err(Error::Io)?;
+                self.write_from_memory(addr, &mut dst, len as usize)
+                    .map_err(save_error)?;
+            }
+        }
+        dst.write_all(&[0u8; 16]).map_err(Error::Io)?;
+        let checksum = dst.checksum.finish();
+        dst.write_all(&checksum.to_le_bytes()).map_err(Error::Io)?;
+        dst.flush().map_err(Error::Io)?;
+        Ok(dst.checksum.finish())
+    }
+
+    /// Creates guest memory mapping the contents of a sparse snapshot copy-on-write.
+    ///
+    /// Pages are only read from `file` when the guest memory is first accessed, and writes to the
+    /// guest memory never reach `file`.
+    pub fn from_snapshot_file(file: &File) -> Result<GuestMemory> {
+        let mut src = file;
+        src.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+        let layout = Layout::read_full(&mut src)?;
+        let file_len = file.metadata().map_err(Error::Io)?.len();
+        let page_size = host_page_size() as u64;
+
+        let mut regions = Vec::with_capacity(layout.regions.len());
+        for (range, offset) in layout.regions {
+            if offset % page_size != 0 {
+                return Err(Error::InvalidRegionOffset(offset));
+            }
+            // Accessing a mapping past the end of the file would raise SIGBUS.
+            if offset + range.len() > file_len {
+                return Err(Error::Truncated);
+            }
+            let mapping = host_size(range.len())
+                .and_then(|size| MemoryMapping::from_fd_offset_private(file, size, offset))
+                .map_err(|e| Error::GuestMemory(GuestMemoryError::MemoryMappingFailed(e)))?;
+            regions.push(MemoryRegion::new(mapping, range.start()));
+        }
+        GuestMemory::from_regions(regions).map_err(Error::GuestMemory)
+    }
+
+    /// Restores the contents of the guest memory from a snapshot read from `src`.
+    ///
+    /// Both dense and sparse snapshots are supported. The memory regions recorded in the snapshot
+    /// must match the memory regions of the guest memory exactly. The layout is checked before
+    /// any guest memory is modified, but the guest memory is left partially restored if the
+    /// snapshot turns out to be truncated.
+    ///
+    /// The restored pages are tracked as dirty like any other write, create the `DirtyLog` of the
+    /// next diff snapshots once restored so that they only contain the pages written from then
+    /// on.
+    pub fn restore<R: Read>(&self, src: &mut R) -> Result<()> {
+        let layout = Layout::read_full(src)?;
+        layout.check(self)?;
+        self.restore_contents(layout, src)
+    }
+
+    /// Applies a diff snapshot read from `src` to the guest memory, which must hold the contents
+    /// of the snapshot whose checksum is `parent`, and returns the checksum of the diff snapshot.
+    ///
+    /// The layout and the parent are checked before any guest memory is modified, but the
+    /// checksum of the diff snapshot is only checked once all its pages have been restored.
+    ///
+    /// The restored pages are tracked as dirty, as with `restore()`.
+    pub fn restore_diff<R: Read>(&self, src: &mut R, parent: u64) -> Result<u64> {
+        let mut src = ChecksumReader::new(src);
+        let version = read_version(&mut src)?;
+        if version != DIFF_SNAPSHOT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Layout::read_from(&mut src, version)?.check(self)?;
+        let found = read_u64(&mut src)?;
+        if found != parent {
+            return Err(Error::ParentMismatch {
+                expected: parent,
+                found,
+            });
+        }
+
+        loop {
+            let addr = GuestAddress(read_u64(&mut src)?);
+            let len = read_u64(&mut src)?;
+            if len == 0 {
+                break;
+            }
+            let count = host_size(len)
+                .map_err(|e| Error::GuestMemory(GuestMemoryError::MemoryAccess(addr, e)))?;
+            self.read_to_memory(addr, &mut src, count)
+                .map_err(restore_error)?;
+        }
+        let found = src.checksum.finish();
+        let expected = read_u64(&mut src)?;
+        if found != expected {
+            return Err(Error::ChecksumMismatch { expected, found });
+        }
+        Ok(src.checksum.finish())
+    }
+
+    // Restores the contents of the memory regions of a snapshot whose header was already read.
+    fn restore_contents<R: Read>(&self, layout: Layout, src: &mut R) -> Result<()> {
+        let mut pos = layout.header_len;
+        for (range, offset) in layout.regions {
+            skip(src, offset - pos)?;
+            self.read_to_memory(range.start(), src, range.len() as usize)
+                .map_err(restore_error)?;
+            pos = offset + range.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    fn create_memory() -> GuestMemory {
+        GuestMemory::new(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x10000), 0x1000)]).unwrap()
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mem = create_memory();
+        mem.write_obj_at_addr(0x1234_5678_u32, GuestAddress(0x1ffc))
+            .unwrap();
+        mem.write_obj_at_addr(0xdead_beef_u32, GuestAddress(0x10ffc))
+            .unwrap();
+
+        let mut snapshot = Vec::new();
+        mem.snapshot(&mut snapshot).unwrap();
+        assert_eq!(snapshot.len(), 16 + 2 * 16 + 0x3000);
+        assert_eq!(&snapshot[..8], b"GMEMSNAP");
+
+        let restored = create_memory();
+        restored.restore(&mut snapshot.as_slice()).unwrap();
+        let val: u32 = restored.read_obj_from_addr(GuestAddress(0x1ffc)).unwrap();
+        assert_eq!(val, 0x1234_5678);
+        let val: u32 = restored.read_obj_from_addr(GuestAddress(0x10ffc)).unwrap();
+        assert_eq!(val, 0xdead_beef);
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mem = create_memory();
+        let mut snapshot = Vec::new();
+        mem.snapshot(&mut snapshot).unwrap();
+
+        let mut bad = snapshot.clone();
+        bad[0] = b'X';
+        match mem.restore(&mut bad.as_slice()) {
+            Err(Error::InvalidMagic) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        let mut bad = snapshot.clone();
+        bad[8..12].copy_from_slice(&3u32.to_le_bytes());
+        match mem.restore(&mut bad.as_slice()) {
+            Err(Error::UnsupportedVersion(3)) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        match mem.restore(&mut &snapshot[..4]) {
+            Err(Error::Truncated) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn layout_mismatch() {
+        let mem = create_memory();
+        let mut snapshot = Vec::new();
+        mem.snapshot(&mut snapshot).unwrap();
+
+        let other = GuestMemory::new(&[(GuestAddress(0x0), 0x2000)]).unwrap();
+        match other.restore(&mut snapshot.as_slice()) {
+            Err(Error::RegionCountMismatch {
+                expected: 1,
+                found: 2,
+            }) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        let other =
+            GuestMemory::new(&[(GuestAddress(0x0), 0x2000), (GuestAddress(0x20000), 0x1000)])
+                .unwrap();
+        other.write_obj_at_addr(0xffu8, GuestAddress(0x0)).unwrap();
+        match other.restore(&mut snapshot.as_slice()) {
+            Err(Error::RegionMismatch { expected, found }) => {
+                assert_eq!(
+                    expected,
+                    GuestAddressRange::new(GuestAddress(0x20000), 0x1000)
+                );
+                assert_eq!(found, GuestAddressRange::new(GuestAddress(0x10000), 0x1000));
+            }
+            e => panic!("unexpected result {:?}", e),
+        }
+        // Guest memory is left untouched when the layout doesn't match.
+        let val: u8 = other.read_obj_from_addr(GuestAddress(0x0)).unwrap();
+        assert_eq!(val, 0xff);
+    }
+
+    #[test]
+    fn truncated_contents() {
+        let mem = create_memory();
+        let mut snapshot = Vec::new();
+        mem.snapshot(&mut snapshot).unwrap();
+
+        let len = snapshot.len();
+        match mem.restore(&mut &snapshot[..len - 1]) {
+            Err(Error::Truncated) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn sparse_snapshot() {
+        let page_size = host_page_size() as u64;
+        let ranges = [
+            (GuestAddress(0x0), 4 * page_size),
+            (GuestAddress(0x1000_0000), 64 * page_size + 0x10),
+        ];
+        let mem = GuestMemory::new(&ranges).unwrap();
+        mem.write_obj_at_addr(0x1234_5678_u32, GuestAddress(page_size - 2))
+            .unwrap();
+        mem.write_obj_at_addr(0x55_u8, GuestAddress(0x1000_0000 + 64 * page_size + 0xf))
+            .unwrap();
+
+        let mut file = tempfile().unwrap();
+        mem.snapshot_sparse(&mut file).unwrap();
+        // The header, the two pages of the first region and the page of the second region
+        // written, plus the zero pages in between left as holes.
+        let metadata = file.metadata().unwrap();
+        assert_eq!(metadata.len(), (1 + 4 + 65) * page_size);
+        assert!(metadata.blocks() * 512 < metadata.len());
+        // The pages never touched weren't allocated by the snapshot.
+        let high = mem.find_region(GuestAddress(0x1000_0000)).unwrap();
+        let resident = high.mapping().resident_pages().unwrap();
+        assert_eq!(resident.iter().filter(|resident| **resident).count(), 1);
+        assert!(resident[64]);
+
+        // Restore by reading the snapshot.
+        let restored = GuestMemory::new(&ranges).unwrap();
+        restored
+            .write_obj_at_addr(0xff_u8, GuestAddress(3 * page_size))
+            .unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        restored.restore(&mut file).unwrap();
+        let val: u32 = restored
+            .read_obj_from_addr(GuestAddress(page_size - 2))
+            .unwrap();
+        assert_eq!(val, 0x1234_5678);
+        let val: u8 = restored
+            .read_obj_from_addr(GuestAddress(3 * page_size))
+            .unwrap();
+        assert_eq!(val, 0);
+
+        // Restore by mapping the snapshot.
+        let mapped = GuestMemory::from_snapshot_file(&file).unwrap();
+        assert_eq!(mapped.num_regions(), 2);
+        assert_eq!(mapped.memory_size(), 68 * page_size + 0x10);
+        let val: u32 = mapped
+            .read_obj_from_addr(GuestAddress(page_size - 2))
+            .unwrap();
+        assert_eq!(val, 0x1234_5678);
+        let addr = GuestAddress(0x1000_0000 + 64 * page_size + 0xf);
+        assert_eq!(mapped.read_obj_from_addr::<u8>(addr).unwrap(), 0x55);
+        mapped.write_obj_at_addr(0xaa_u8, addr).unwrap();
+        let mapped = GuestMemory::from_snapshot_file(&file).unwrap();
+        assert_eq!(mapped.read_obj_from_addr::<u8>(addr).unwrap(), 0x55);
+    }
+
+    #[test]
+    fn map_dense_snapshot() {
+        let mem = create_memory();
+        let mut file = tempfile().unwrap();
+        mem.snapshot(&mut file).unwrap();
+        match GuestMemory::from_snapshot_file(&file) {
+            Err(Error::InvalidRegionOffset(0x30)) => {}
+            e => panic!("unexpected result {:?}", e.map(|_| ())),
+        }
+
+        let mut file = tempfile().unwrap();
+        mem.snapshot_sparse(&mut file).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 1).unwrap();
+        match GuestMemory::from_snapshot_file(&file) {
+            Err(Error::Truncated) => {}
+            e => panic!("unexpected result {:?}", e.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn snapshot_checksums() {
+        let mem = create_memory();
+        mem.write_obj_at_addr(0x1234_5678_u32, GuestAddress(0x1ffc))
+            .unwrap();
+        let mut snapshot = Vec::new();
+        mem.snapshot(&mut snapshot).unwrap();
+        let checksum = snapshot_checksum(&mut snapshot.as_slice()).unwrap();
+
+        // The checksum doesn't depend on how the bytes are split.
+        let mut sink = io::sink();
+        let mut dst = ChecksumWriter::new(&mut sink);
+        for chunk in snapshot.chunks(13) {
+            dst.write_all(chunk).unwrap();
+        }
+        assert_eq!(dst.checksum.finish(), checksum);
+
+        // Holes of sparse snapshots read as zeros.
+        let mut file = tempfile().unwrap();
+        mem.snapshot_sparse(&mut file).unwrap();
+        let mut contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(
+            snapshot_checksum(&mut file).unwrap(),
+            snapshot_checksum(&mut contents.as_slice()).unwrap()
+        );
+
+        // Trailing zeros change the checksum.
+        snapshot.push(0);
+        assert_ne!(
+            snapshot_checksum(&mut snapshot.as_slice()).unwrap(),
+            checksum
+        );
+        assert_eq!(
+            snapshot_checksum(&mut io::empty()).unwrap(),
+            Checksum::new().finish()
+        );
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let ranges = [
+            (GuestAddress(0x0), 0x10000),
+            (GuestAddress(0x10_0000), 0x2800),
+        ];
+        let mem = GuestMemory::new_with_dirty_tracking(&ranges, 0x1000).unwrap();
+        mem.write_obj_at_addr(0x11_u8, GuestAddress(0x0)).unwrap();
+        mem.write_obj_at_addr(0x22_u8, GuestAddress(0x8000))
+            .unwrap();
+        let all = GuestAddressRange::new(GuestAddress(0), u64::MAX);
+        // Full snapshots leave the dirty pages untouched.
+        mem.snapshot(&mut Vec::new()).unwrap();
+        assert_eq!(mem.dirty_pages(&all).len(), 2);
+        let log = mem.dirty_log();
+        let mut full = Vec::new();
+        mem.snapshot(&mut full).unwrap();
+        let full_checksum = snapshot_checksum(&mut full.as_slice()).unwrap();
+        assert_eq!(mem.dirty_pages(&all).len(), 2);
+
+        // Two contiguous pages, and the last partial page of the second region.
+        mem.write_obj_at_addr(0x33_u16, GuestAddress(0x8fff))
+            .unwrap();
+        mem.write_obj_at_addr(0x44_u8, GuestAddress(0x10_27ff))
+            .unwrap();
+        // Other consumers of the dirty pages don't affect diff snapshots.
+        mem.clear_dirty_pages();
+        let other = mem.dirty_log();
+        mem.write_obj_at_addr(0x44_u8, GuestAddress(0x10_27ff))
+            .unwrap();
+        assert_eq!(mem.fetch_and_clear_dirty_log(&other, &all).len(), 1);
+        let mut diff1 = Vec::new();
+        let diff1_checksum = mem.snapshot_diff(&log, &mut diff1, full_checksum).unwrap();
+        assert_eq!(
+            diff1.len(),
+            16 + 2 * 16 + 8 + 16 + 0x2000 + 16 + 0x800 + 16 + 8
+        );
+        assert_eq!(
+            snapshot_checksum(&mut diff1.as_slice()).unwrap(),
+            diff1_checksum
+        );
+
+        mem.write_obj_at_addr(0x55_u8, GuestAddress(0x0)).unwrap();
+        let mut diff2 = Vec::new();
+        let diff2_checksum = mem.snapshot_diff(&log, &mut diff2, diff1_checksum).unwrap();
+        assert_eq!(diff2.len(), 16 + 2 * 16 + 8 + 16 + 0x1000 + 16 + 8);
+
+        let check = |restored: &GuestMemory| {
+            assert_eq!(
+                restored
+                    .read_obj_from_addr::<u8>(GuestAddress(0x0))
+                    .unwrap(),
+                0x55
+            );
+            assert_eq!(
+                restored
+                    .read_obj_from_addr::<u8>(GuestAddress(0x8000))
+                    .unwrap(),
+                0x22
+            );
+            assert_eq!(
+                restored
+                    .read_obj_from_addr::<u16>(GuestAddress(0x8fff))
+                    .unwrap(),
+                0x33
+            );
+            assert_eq!(
+                restored
+                    .read_obj_from_addr::<u8>(GuestAddress(0x10_27ff))
+                    .unwrap(),
+                0x44
+            );
+        };
+
+        let restored = GuestMemory::new_with_dirty_tracking(&ranges, 0x1000).unwrap();
+        restored.restore(&mut full.as_slice()).unwrap();
+        match restored.restore_diff(&mut diff2.as_slice(), full_checksum) {
+            Err(Error::ParentMismatch { expected, found }) => {
+                assert_eq!(expected, full_checksum);
+                assert_eq!(found, diff1_checksum);
+            }
+            e => panic!("unexpected result {:?}", e),
+        }
+        assert_eq!(
+            restored
+                .restore_diff(&mut diff1.as_slice(), full_checksum)
+                .unwrap(),
+            diff1_checksum
+        );
+        assert_eq!(
+            restored
+                .restore_diff(&mut diff2.as_slice(), diff1_checksum)
+                .unwrap(),
+            diff2_checksum
+        );
+        check(&restored);
+        assert!(!restored.dirty_pages(&all).is_empty());
+
+        // A full snapshot can't be applied as a diff.
+        match restored.restore_diff(&mut full.as_slice(), full_checksum) {
+            Err(Error::UnsupportedVersion(SNAPSHOT_VERSION)) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        let mut corrupted = diff2.clone();
+        corrupted[16 + 2 * 16 + 8 + 16] ^= 1;
+        match restored.restore_diff(&mut corrupted.as_slice(), diff1_checksum) {
+            Err(Error::ChecksumMismatch { .. }) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+
+        let mut merged = Vec::new();
+        let merged_checksum = merge_snapshots(
+            &mut full.as_slice(),
+            vec![diff1.as_slice(), diff2.as_slice()],
+            &mut merged,
+        )
+        .unwrap();
+        assert_eq!(
+            snapshot_checksum(&mut merged.as_slice()).unwrap(),
+            merged_checksum
+        );
+        let restored = GuestMemory::new(&ranges).unwrap();
+        restored.restore(&mut merged.as_slice()).unwrap();
+        check(&restored);
+
+        match merge_snapshots(
+            &mut full.as_slice(),
+            vec![diff2.as_slice()],
+            &mut Vec::new(),
+        ) {
+            Err(Error::ParentMismatch { .. }) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+
+    #[test]
+    fn diff_snapshot_without_dirty_tracking() {
+        let mem = create_memory();
+        match mem.snapshot_diff(&mem.dirty_log(), &mut Vec::new(), 0) {
+            Err(Error::NoDirtyTracking(GuestAddress(0))) => {}
+            e => panic!("unexpected result {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright (C) 2019 Alibaba Cloud Computing. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers shared by the snapshot format and the migration stream.
+
+use std::io::{self, Read};
+use std::result;
+
+use address::Address;
+use guest_address::{GuestAddress, GuestAddressRange, GuestUsize};
+use guest_memory::{Error as GuestMemoryError, GuestMemory};
+use mmap;
+
+/// Errors reported by the helpers shared by the snapshot format and the migration stream.
+pub(crate) trait StreamError: Sized {
+    /// Failure in reading or writing the stream.
+    fn io(e: io::Error) -> Self;
+    /// The stream ended early.
+    fn truncated() -> Self;
+    /// Failure in accessing guest memory.
+    fn guest_memory(e: GuestMemoryError) -> Self;
+    /// The stream doesn't start with the expected magic number.
+    fn invalid_magic() -> Self;
+    /// The stream and the guest memory have a different number of memory regions.
+    fn region_count_mismatch(expected: usize, found: usize) -> Self;
+    /// A memory region of the stream doesn't match the memory region of the guest memory.
+    fn region_mismatch(expected: GuestAddressRange, found: GuestAddressRange) -> Self;
+}
+
+/// Converts a failure of reading the stream, reporting truncation distinctly.
+pub(crate) fn read_error<E: StreamError>(e: io::Error) -> E {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        E::truncated()
+    } else {
+        E::io(e)
+    }
+}
+
+/// Converts a failure of writing guest memory to the stream.
+pub(crate) fn save_error<E: StreamError>(e: GuestMemoryError) -> E {
+    match e {
+        GuestMemoryError::MemoryAccess(_, mmap::Error::ReadFromSource(e)) => E::io(e),
+        e => E::guest_memory(e),
+    }
+}
+
+/// Converts a failure of reading guest memory from the stream, reporting truncation distinctly.
+pub(crate) fn restore_error<E: StreamError>(e: GuestMemoryError) -> E {
+    match e {
+        GuestMemoryError::MemoryAccess(_, mmap::Error::ReadFromSource(e)) => read_error(e),
+        e => E::guest_memory(e),
+    }
+}
+
+/// Encodes the header of a stream: the magic number, the version, the region count and the
+/// guest base and size of each memory region, followed by its offset in the stream if `offsets`
+/// are given.
+pub(crate) fn encode_header(
+    magic: &[u8; 8],
+    version: u32,
+    ranges: &[GuestAddressRange],
+    offsets: Option<&[u64]>,
+) -> Vec<u8> {
+    let entry_len = if offsets.is_some() { 24 } else { 16 };
+    let mut header = Vec::with_capacity(16 + entry_len * ranges.len());
+    header.extend_from_slice(magic);
+    header.extend_from_slice(&version.to_le_bytes());
+    header.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+    for (index, range) in ranges.iter().enumerate() {
+        header.extend_from_slice(&range.start().offset().to_le_bytes());
+        header.extend_from_slice(&range.len().to_le_bytes());
+        if let Some(offsets) = offsets {
+            header.extend_from_slice(&offsets[index].to_le_bytes());
+        }
+    }
+    header
+}
+
+/// Reads the magic number of a stream, which must be `magic`, and returns its version.
+pub(crate) fn read_version<R: Read, E: StreamError>(
+    src: &mut R,
+    magic: &[u8; 8],
+) -> result::Result<u32, E> {
+    let mut found = [0u8; 8];
+    src.read_exact(&mut found).map_err(read_error)?;
+    if found != *magic {
+        return Err(E::invalid_magic());
+    }
+    read_u32(src).map_err(read_error)
+}
+
+/// Checks that the memory regions recorded in a stream match those of `mem`, and returns the
+/// ranges of the memory regions of `mem`.
+pub(crate) fn check_layout<E: StreamError>(
+    mem: &GuestMemory,
+    found: &[GuestAddressRange],
+) -> result::Result<Vec<GuestAddressRange>, E> {
+    let ranges = region_ranges(mem);
+    if found.len() != ranges.len() {
+        return Err(E::region_count_mismatch(ranges.len(), found.len()));
+    }
+    for (expected, found) in ranges.iter().zip(found.iter()) {
+        if found != expected {
+            return Err(E::region_mismatch(*expected, *found));
+        }
+    }
+    Ok(ranges)
+}
+
+/// Reads a little endian `u32` from `src`.
+pub(crate) fn read_u32<R: Read>(src: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Reads a little endian `u64` from `src`.
+pub(crate) fn read_u64<R: Read>(src: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    src.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Returns the ranges of the memory regions of `mem`, in ascending order of guest address.
+pub(crate) fn region_ranges(mem: &GuestMemory) -> Vec<GuestAddressRange> {
+    let mut ranges = Vec::with_capacity(mem.num_regions());
+    let _ = mem.with_regions_mut(|_, base, size, _| -> result::Result<(), ()> {
+        ranges.push(GuestAddressRange::new(base, size));
+        Ok(())
+    });
+    ranges
+}
+
+/// Returns the ranges of the memory regions of `mem` with the size of their dirty pages.
+///
+/// Fails with the base of the first memory region which doesn't track dirty pages.
+pub(crate) fn require_dirty_tracking(
+    mem: &GuestMemory,
+) -> result::Result<Vec<(GuestAddressRange, GuestUsize)>, GuestAddress> {
+    region_ranges(mem)
+        .into_iter()
+        .map(|range| {
+            // The regions are taken from the guest memory itself.
+            match mem.find_region(range.start()).unwrap().dirty_bitmap() {
+                Some(dirty) => Ok((range, dirty.page_size() as GuestUsize)),
+                None => Err(range.start()),
+            }
+        })
+        .collect()
+}
+
+/// Coalesces the dirty `pages` of the memory region `range` into runs of contiguous pages.
+///
+/// The pages must be sorted in ascending order, and the last page of the region is clipped to
+/// its end.
+pub(crate) fn page_runs(
+    pages: &[GuestAddress],
+    page_size: GuestUsize,
+    range: &GuestAddressRange,
+) -> Vec<(GuestAddress, GuestUsize)> {
+    let mut runs: Vec<(GuestAddress, GuestUsize)> = Vec::new();
+    for page in pages.iter() {
+        let len = page_size.min(range.len() - page.offset_from(range.start()));
+        match runs.last_mut() {
+            Some((start, run_len)) if start.unchecked_add(*run_len) == *page => *run_len += len,
+            _ => runs.push((*page, len)),
+        }
+    }
+    runs
+}
@@ -14,6 +14,7 @@
 //! For the purposes of maintaining safety, volatile memory has some rules of its own:
 //! 1. No references or slices to volatile memory (`&` or `&mut`).
 //! 2. Access should always been done with a volatile read or write.
+//!
 //! The First rule is because having references of any kind to memory considered volatile would
 //! violate pointer aliasing. The second is because unvolatile accesses are inherently undefined if
 //! done concurrently without synchronization. With volatile access we know that the compiler has
@@ -24,14 +25,16 @@
 use std::cmp::min;
 use std::fmt;
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::marker::PhantomData;
 use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
 use std::ptr::copy;
 use std::ptr::{null_mut, read_volatile, write_volatile};
 use std::result;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
-use std::{isize, usize};
+
+use libc;
 
 use DataInit;
 
@@ -42,6 +45,8 @@ pub enum VolatileMemoryError {
     OutOfBounds { addr: usize },
     /// Taking a slice at `base` with `offset` would overflow `usize`.
     Overflow { base: usize, offset: usize },
+    /// The memory at `addr` is mapped read-only and can't be handed out as a writable slice.
+    ReadOnly { addr: usize },
 }
 
 impl fmt::Display for VolatileMemoryError {
@@ -55,6 +60,9 @@ impl fmt::Display for VolatileMemoryError {
                 "address 0x{:x} offset by 0x{:x} would overflow",
                 base, offset
             ),
+            VolatileMemoryError::ReadOnly { addr } => {
+                write!(f, "address 0x{:x} is read-only", addr)
+            }
         }
     }
 }
@@ -87,14 +95,69 @@ pub fn calc_offset(base: usize, offset: usize) -> Result<usize> {
     }
 }
 
+// Converts the volatile slices into iovecs, up to the maximum number supported by the vectored
+// I/O system calls.
+fn slices_to_iovecs(slices: &[VolatileSlice]) -> Vec<libc::iovec> {
+    slices
+        .iter()
+        .take(libc::UIO_MAXIOV as usize)
+        .map(VolatileSlice::as_iovec)
+        .collect()
+}
+
+// Retries the vectored I/O system call `f` while it is interrupted.
+fn retry_vectored<F>(mut f: F) -> IoResult<usize>
+where
+    F: FnMut() -> libc::ssize_t,
+{
+    loop {
+        let ret = f();
+        if ret >= 0 {
+            return Ok(ret as usize);
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// Reads from `fd` to the volatile slices, filled in order, with a single `readv` system call and
+/// returns how many bytes were read.
+///
+/// Like `read`, fewer bytes than the total size of the slices may be read. Only the first
+/// `UIO_MAXIOV` slices are used.
+pub fn read_vectored_from_fd(fd: &dyn AsRawFd, slices: &[VolatileSlice]) -> IoResult<usize> {
+    let iovecs = slices_to_iovecs(slices);
+    // This is safe because the iovecs point to the memory of the volatile slices, which is
+    // available for their lifetime and only accessed with volatile accesses by other users.
+    retry_vectored(|| unsafe {
+        libc::readv(fd.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+    })
+}
+
+/// Writes the volatile slices, in order, to `fd` with a single `writev` system call and returns
+/// how many bytes were written.
+///
+/// Like `write`, fewer bytes than the total size of the slices may be written. Only the first
+/// `UIO_MAXIOV` slices are used.
+pub fn write_vectored_to_fd(fd: &dyn AsRawFd, slices: &[VolatileSlice]) -> IoResult<usize> {
+    let iovecs = slices_to_iovecs(slices);
+    // This is safe because writev only reads the memory of the volatile slices, which is
+    // available for their lifetime.
+    retry_vectored(|| unsafe {
+        libc::writev(fd.as_raw_fd(), iovecs.as_ptr(), iovecs.len() as libc::c_int)
+    })
+}
+
 /// Trait for types that support raw volatile access to their data.
 pub trait VolatileMemory {
     /// Gets a slice of memory at `offset` that is `count` bytes in length and supports volatile
     /// access.
-    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice>;
+    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice<'_>>;
 
     /// Gets a `VolatileRef` at `offset`.
-    fn get_ref<T: DataInit>(&self, offset: usize) -> Result<VolatileRef<T>> {
+    fn get_ref<T: DataInit>(&self, offset: usize) -> Result<VolatileRef<'_, T>> {
         let slice = self.get_slice(offset, size_of::<T>())?;
         Ok(VolatileRef {
             addr: slice.addr as *mut T,
@@ -103,8 +166,8 @@ pub trait VolatileMemory {
     }
 }
 
-impl<'a> VolatileMemory for &'a mut [u8] {
-    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice> {
+impl VolatileMemory for &mut [u8] {
+    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice<'_>> {
         let mem_end = calc_offset(offset, count)?;
         if mem_end > self.len() {
             return Err(Error::OutOfBounds { addr: mem_end });
@@ -134,6 +197,8 @@ impl<'a> Default for VolatileSlice<'a> {
 impl<'a> VolatileSlice<'a> {
     /// Creates a slice of raw memory that must support volatile access.
     ///
+    /// # Safety
+    ///
     /// To use this safely, the caller must guarantee that the memory at `addr` is `size` bytes long
     /// and is available for the duration of the lifetime of the new `VolatileSlice`. The caller
     /// must also guarantee that all other users of the given chunk of memory are using volatile
@@ -156,6 +221,14 @@ impl<'a> VolatileSlice<'a> {
         self.size
     }
 
+    /// Gets an iovec describing this slice's memory, to hand it to vectored I/O system calls.
+    pub fn as_iovec(&self) -> libc::iovec {
+        libc::iovec {
+            iov_base: self.addr as *mut libc::c_void,
+            iov_len: self.size,
+        }
+    }
+
     /// Creates a copy of this slice with the address increased by `count` bytes, and the size
     /// reduced by `count` bytes.
     pub fn offset(self, count: usize) -> Result<VolatileSlice<'a>> {
@@ -166,12 +239,6 @@ impl<'a> VolatileSlice<'a> {
                     base: self.addr as usize,
                     offset: count,
                 })?;
-        if new_addr > usize::MAX {
-            return Err(VolatileMemoryError::Overflow {
-                base: self.addr as usize,
-                offset: count,
-            })?;
-        }
         let new_size = self
             .size
             .checked_sub(count)
@@ -385,13 +452,14 @@ impl<'a> VolatileSlice<'a> {
     unsafe fn as_slice(&self) -> &[u8] {
         from_raw_parts(self.addr, self.size)
     }
+    #[allow(clippy::mut_from_ref)]
     unsafe fn as_mut_slice(&self) -> &mut [u8] {
         from_raw_parts_mut(self.addr, self.size)
     }
 }
 
 impl<'a> VolatileMemory for VolatileSlice<'a> {
-    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice> {
+    fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice<'_>> {
         let mem_end = calc_offset(offset, count)?;
         if mem_end > self.size {
             return Err(Error::OutOfBounds { addr: mem_end });
@@ -428,6 +496,8 @@ where
 impl<'a, T: DataInit> VolatileRef<'a, T> {
     /// Creates a reference to raw memory that must support volatile access of `T` sized chunks.
     ///
+    /// # Safety
+    ///
     /// To use this safely, the caller must guarantee that the memory at `addr` is big enough for a
     /// `T` and is available for the duration of the lifetime of the new `VolatileRef`. The caller
     /// must also guarantee that all other users of the given chunk of memory are using volatile
@@ -481,7 +551,11 @@ impl<'a, T: DataInit> VolatileRef<'a, T> {
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
     use super::*;
+    use std::io::{Seek, SeekFrom};
 
     use std::sync::Arc;
     use std::thread::{sleep, spawn};
@@ -494,14 +568,14 @@ mod tests {
 
     impl VecMem {
         fn new(size: usize) -> VecMem {
-            let mut mem = Vec::new();
-            mem.resize(size, 0);
-            VecMem { mem: Arc::new(mem) }
+            VecMem {
+                mem: Arc::new(vec![0; size]),
+            }
         }
     }
 
     impl VolatileMemory for VecMem {
-        fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice> {
+        fn get_slice(&self, offset: usize, count: usize) -> Result<VolatileSlice<'_>> {
             let mem_end = calc_offset(offset, count)?;
             if mem_end > self.mem.len() {
                 return Err(Error::OutOfBounds { addr: mem_end });
@@ -605,13 +679,12 @@ mod tests {
 
     #[test]
     fn slice_overflow_error() {
-        use std::usize::MAX;
         let a = VecMem::new(1);
-        let res = a.get_slice(MAX, 1).unwrap_err();
+        let res = a.get_slice(usize::MAX, 1).unwrap_err();
         assert_eq!(
             res,
             Error::Overflow {
-                base: MAX,
+                base: usize::MAX,
                 offset: 1,
             }
         );
@@ -627,13 +700,12 @@ mod tests {
 
     #[test]
     fn ref_overflow_error() {
-        use std::usize::MAX;
         let a = VecMem::new(1);
-        let res = a.get_ref::<u8>(MAX).unwrap_err();
+        let res = a.get_ref::<u8>(usize::MAX).unwrap_err();
         assert_eq!(
             res,
             Error::Overflow {
-                base: MAX,
+                base: usize::MAX,
                 offset: 1,
             }
         );
@@ -653,4 +725,26 @@ mod tests {
         let res = a.get_ref::<u32>(0).unwrap_err();
         assert_eq!(res, Error::OutOfBounds { addr: 4 });
     }
+
+    #[test]
+    fn vectored_fd_io() {
+        let a = VecMem::new(8);
+        let b = VecMem::new(8);
+        a.get_slice(0, 8)
+            .unwrap()
+            .copy_from(&[1u8, 2, 3, 4, 5, 6, 7, 8]);
+        let iovec = a.get_slice(2, 3).unwrap().as_iovec();
+        assert_eq!(iovec.iov_base as usize, a.mem.as_ptr() as usize + 2);
+        assert_eq!(iovec.iov_len, 3);
+
+        let mut file = tempfile().unwrap();
+        let slices = [a.get_slice(6, 2).unwrap(), a.get_slice(0, 3).unwrap()];
+        assert_eq!(write_vectored_to_fd(&file, &slices).unwrap(), 5);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let slices = [b.get_slice(0, 1).unwrap(), b.get_slice(4, 4).unwrap()];
+        assert_eq!(read_vectored_from_fd(&file, &slices).unwrap(), 5);
+        assert_eq!(*b.mem, vec![7, 0, 0, 0, 8, 1, 2, 3]);
+        assert_eq!(read_vectored_from_fd(&file, &slices).unwrap(), 0);
+    }
 }